@@ -8,7 +8,7 @@ use photoncube2video::transforms::image_to_array3;
 #[cfg(target_os = "linux")]
 use pprof::criterion::{Output, PProfProfiler};
 use spano::{
-    blend::{distance_transform, merge_images},
+    blend::{distance_transform, merge_images, polygon_distance_transform},
     lk::{iclk, img_pyramid},
     warps::{Mapping, TransformationType},
 };
@@ -53,6 +53,25 @@ pub fn benchmark_distance_transform(c: &mut Criterion) {
     });
 }
 
+pub fn benchmark_polygon_distance_transform(c: &mut Criterion) {
+    let map = Mapping::from_matrix(
+        array![
+            [0.47654548, -0.045553986, 4.847797],
+            [-0.14852144, 0.6426208, 2.1364543],
+            [-0.009891294, -0.0021317923, 0.88151735]
+        ],
+        TransformationType::Projective,
+    )
+    .rescale(1.0 / 16.0);
+    let corners = map.corners((640, 480));
+
+    c.bench_function("polygon_distance_transform", |b| {
+        b.iter(|| {
+            let _ = polygon_distance_transform(&corners, (640, 480));
+        })
+    });
+}
+
 pub fn benchmark_iclk(c: &mut Criterion) {
     let img_src = ImageReader::open("tests/source.png")
         .unwrap()
@@ -119,7 +138,7 @@ pub fn benchmark_merge_images(c: &mut Criterion) {
 
     c.bench_function("merge_images", |b| {
         b.iter(|| {
-            let _ = merge_images(&maps, &imgs, None, None).unwrap();
+            let _ = merge_images(&maps, &imgs, None, None, None, None).unwrap();
         })
     });
 }
@@ -149,6 +168,7 @@ criterion_group! {
     targets =
         benchmark_warp_array3,
         benchmark_distance_transform,
+        benchmark_polygon_distance_transform,
         benchmark_iclk,
         benchmark_merge_images,
         benchmark_img_pyramid
@@ -159,6 +179,7 @@ criterion_group! {
     benches,
     benchmark_warp_array3,
     benchmark_distance_transform,
+    benchmark_polygon_distance_transform,
     benchmark_iclk,
     benchmark_merge_images,
     benchmark_img_pyramid
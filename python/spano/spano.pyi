@@ -1,5 +1,5 @@
 from os import PathLike
-from typing import List, Tuple, Dict, Optional
+from typing import List, Tuple, Dict, Optional, Union
 from typing_extensions import Self
 from enum import Enum, auto
 
@@ -20,6 +20,27 @@ class TransformationType(Enum):
     def to_str(self: Self) -> str: ...
     def from_str(name: str) -> Self: ...
 
+class DemosaicMethod(Enum):
+    Bilinear = auto()
+    Edge = auto()
+    PhotonNoise = auto()
+
+class GradientOperator(Enum):
+    Prewitt = auto()
+    Sobel = auto()
+    Scharr = auto()
+    Central = auto()
+    Gaussian = auto()
+
+class PyramidFilter(Enum):
+    Box = auto()
+    Gaussian = auto()
+
+class PixelSelection(Enum):
+    Gradient = auto()
+    ShiTomasi = auto()
+    Random = auto()
+
 class Mapping:
     mat: np.ndarray
     kind: str
@@ -70,6 +91,55 @@ class Mapping:
         background: Optional[List[float]],
     ) -> np.ndarray: ...
 
+class PanoBuilder:
+    def __init__(
+        self,
+        burst_size: int = 256,
+        granularity: int = 8,
+        step: int = 1,
+        wrt: float = 0.5,
+        start: Optional[int] = None,
+        end: Optional[int] = None,
+        max_lvls: int = 8,
+        min_size: int = 16,
+        downscale: float = 1.0,
+        iterations: int = 250,
+        early_stop: float = 1e-3,
+        patience: int = 10,
+        transforms: List[str] = [],
+        bitpacked: bool = True,
+        colorspad_fix: bool = False,
+        cfa_path: Optional[PathLike] = None,
+        inpaint_paths: List[PathLike] = [],
+        color: bool = False,
+        demosaic: DemosaicMethod = DemosaicMethod.Bilinear,
+        window: int = 512,
+        checkpoint_dir: Optional[PathLike] = None,
+        resume: bool = False,
+        start_level: Optional[int] = None,
+        init_mappings: Optional[List[Mapping]] = None,
+        bundle_adjust: Optional[int] = None,
+        loop_closure: bool = False,
+        mosaic: bool = False,
+        blend_mask: Optional[PathLike] = None,
+        gain_compensation: bool = False,
+        layers: bool = False,
+        upscale: float = 1.0,
+        pixfrac: float = 1.0,
+        invert_response: bool = False,
+        dark_count: Optional[float] = None,
+        bitplane_exact: bool = False,
+        tonemap2srgb: bool = False,
+        pixel_selection: Optional[PixelSelection] = None,
+        pixel_budget: int = 4096,
+        pyramid_filter: PyramidFilter = PyramidFilter.Box,
+        gradient_operator: GradientOperator = GradientOperator.Prewitt,
+        gradient_sigma: float = 1.0,
+        smoothing: Optional[float] = None,
+        pyramid_scale: float = 2.0,
+    ) -> None: ...
+    def run(self, path: PathLike, verbose: bool = False) -> Dict[str, object]: ...
+
 def iclk(
     im1: np.ndarray,
     im2: np.ndarray,
@@ -82,6 +152,14 @@ def iclk(
     stop_early: Optional[float] = 1e-3,
     patience: Optional[int] = 10,
     message: bool = False,
+    pyramid_filter: PyramidFilter = PyramidFilter.Box,
+    gradient_operator: GradientOperator = GradientOperator.Prewitt,
+    gradient_sigma: float = 1.0,
+    smoothing: Optional[float] = None,
+    pixel_selection: Optional[PixelSelection] = None,
+    pixel_budget: int = 4096,
+    schedule: Optional[List[Tuple[str, Optional[int], Optional[float]]]] = None,
+    pyramid_scale: float = 2.0,
 ) -> Tuple[Mapping, LKParams]: ...
 def pairwise_iclk(
     frames: List[np.ndarray],
@@ -93,10 +171,117 @@ def pairwise_iclk(
     stop_early: Optional[float] = 1e-3,
     patience: Optional[int] = 10,
     message: bool = False,
+    pixel_selection: Optional[PixelSelection] = None,
+    pixel_budget: int = 4096,
+    schedule: Optional[List[Tuple[str, Optional[int], Optional[float]]]] = None,
+    pyramid_scale: float = 2.0,
 ) -> Tuple[List[Mapping], List[LKParams]]: ...
+def register_pairs(
+    frames: List[np.ndarray],
+    pairs: List[Tuple[int, int]],
+    init_mappings: Optional[List[Mapping]] = None,
+    multi: bool = True,
+    max_iters: Optional[int] = 250,
+    min_dimension: int = 16,
+    max_levels: int = 8,
+    stop_early: Optional[float] = 1e-3,
+    patience: Optional[int] = 10,
+    message: bool = False,
+    pixel_selection: Optional[PixelSelection] = None,
+    pixel_budget: int = 4096,
+    schedule: Optional[List[Tuple[str, Optional[int], Optional[float]]]] = None,
+    pyramid_scale: float = 2.0,
+) -> Dict[Tuple[int, int], Tuple[Mapping, LKParams]]: ...
+def mosaic_iclk(
+    frames: List[np.ndarray],
+    init_mappings: Optional[List[Mapping]] = None,
+    kind: str = "translational",
+    multi: bool = True,
+    max_iters: Optional[int] = 250,
+    min_dimension: int = 16,
+    max_levels: int = 8,
+    stop_early: Optional[float] = 1e-3,
+    patience: Optional[int] = 10,
+    message: bool = False,
+    pixel_selection: Optional[PixelSelection] = None,
+    pixel_budget: int = 4096,
+    schedule: Optional[List[Tuple[str, Optional[int], Optional[float]]]] = None,
+    pyramid_scale: float = 2.0,
+) -> List[Mapping]: ...
 def img_pyramid(
-    im: np.ndarray, min_dimension: int = 16, max_levels: int = 8
-) -> Tuple[np.ndarray, ...]: ...
+    im: np.ndarray,
+    min_dimension: int = 16,
+    max_levels: int = 8,
+    scale: float = 2.0,
+    filter: PyramidFilter = PyramidFilter.Box,
+    kind: str = "image",
+    gradient_operator: GradientOperator = GradientOperator.Prewitt,
+    gradient_sigma: float = 1.0,
+) -> Union[List[np.ndarray], List[Tuple[np.ndarray, np.ndarray]]]: ...
+def distance_transform(size: Tuple[int, int]) -> np.ndarray: ...
+def polygon_distance_transform(
+    corners: np.ndarray, size: Tuple[int, int]
+) -> np.ndarray: ...
+def polygon_sdf(points: np.ndarray, vertices: np.ndarray) -> np.ndarray: ...
+def merge_arrays(
+    mappings: List[Mapping],
+    frames: List[np.ndarray],
+    weights: Optional[List[np.ndarray]] = None,
+    valid: Optional[List[np.ndarray]] = None,
+    size: Optional[Tuple[int, int]] = None,
+    message: Optional[str] = None,
+) -> np.ndarray: ...
+def merge_images(
+    mappings: List[Mapping],
+    frames: List[np.ndarray],
+    weights: Optional[List[np.ndarray]] = None,
+    valid: Optional[List[np.ndarray]] = None,
+    size: Optional[Tuple[int, int]] = None,
+    message: Optional[str] = None,
+) -> np.ndarray: ...
+def merge_arrays_with_layers(
+    mappings: List[Mapping],
+    frames: List[np.ndarray],
+    weights: Optional[List[np.ndarray]] = None,
+    valid: Optional[List[np.ndarray]] = None,
+    size: Optional[Tuple[int, int]] = None,
+    message: Optional[str] = None,
+) -> Tuple[np.ndarray, Dict[str, np.ndarray]]: ...
+def drizzle_arrays(
+    mappings: List[Mapping],
+    frames: List[np.ndarray],
+    weights: Optional[List[np.ndarray]] = None,
+    valid: Optional[List[np.ndarray]] = None,
+    scale: float = 2.0,
+    pixfrac: float = 1.0,
+    size: Optional[Tuple[int, int]] = None,
+    message: Optional[str] = None,
+) -> np.ndarray: ...
+def merge_photon_counts(
+    mappings: List[Mapping],
+    frames: List[np.ndarray],
+    num_trials: float,
+    valid: Optional[List[np.ndarray]] = None,
+    size: Optional[Tuple[int, int]] = None,
+    invert_response: bool = True,
+    dark_count: Optional[float] = None,
+    message: Optional[str] = None,
+) -> Tuple[np.ndarray, np.ndarray]: ...
+def gain_compensation(
+    mappings: List[Mapping],
+    frames: List[np.ndarray],
+    per_channel: bool = False,
+    sigma_n: float = 0.1,
+    sigma_g: float = 0.1,
+    max_offset: Optional[int] = None,
+    message: Optional[str] = None,
+) -> np.ndarray: ...
+def demosaic(
+    frame: np.ndarray,
+    pattern: np.ndarray,
+    method: DemosaicMethod = DemosaicMethod.Bilinear,
+    num_trials: Optional[float] = None,
+) -> np.ndarray: ...
 def animate_warp(
     img: np.ndarray,
     params_history: LKParams,
@@ -1,37 +1,35 @@
+use std::{fs::create_dir_all, path::Path};
+
 use anyhow::{anyhow, Result};
-use cached::proc_macro::cached;
 use image::Pixel;
 use imageproc::definitions::{Clamp, Image};
-use itertools::Itertools;
+use itertools::{iproduct, Itertools};
 use ndarray::{
-    azip, concatenate, s, stack, Array, Array1, Array2, Array3, ArrayBase, Axis, Ix3, NewAxis,
-    RawData,
+    array, azip, concatenate, s, stack, Array, Array1, Array2, Array3, ArrayBase, Axis, CowArray,
+    Ix3, NewAxis, RawData, Zip,
+};
+use ndarray_linalg::solve::Solve;
+use ndarray_npy::write_npy;
+use numpy::{Element, PyArray1, PyArray2, PyArray3, ToPyArray};
+use photoncube2video::{
+    signals::DeferredSignal,
+    transforms::{array3_to_image, ref_image_to_array3},
+};
+use pyo3::{prelude::*, types::PyDict};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
 };
-use photoncube2video::transforms::{array3_to_image, ref_image_to_array3};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
-use crate::{utils::get_pbar, warps::Mapping};
+use crate::{
+    lk::{pyarray_cast, pyarray_to_im_bridge},
+    utils::get_pbar,
+    warps::Mapping,
+};
 
 /// Computes normalized and clipped distance transform (bwdist) for rectangle that fills image.
-#[cached(sync_writes = true)]
 pub fn distance_transform(size: (usize, usize)) -> Array2<f32> {
-    let (w, h) = size;
-    let corners = [
-        (0.0, 0.0),
-        (w as f32, 0.0),
-        (w as f32, h as f32),
-        (0.0, h as f32),
-    ];
-    let dist = |q| {
-        corners
-            .iter()
-            .circular_tuple_windows()
-            .map(|(p1, p2)| distance_to_line(*p1, *p2, q))
-            .fold(f32::INFINITY, |a, b| a.min(b))
-    };
-    let max_dist = dist((w as f32 / 2.0, h as f32 / 2.0));
-
-    Array::from_shape_fn((h, w), |(i, j)| dist((j as f32, i as f32)) / max_dist)
+    let (w, h) = (size.0 as f32, size.1 as f32);
+    polygon_distance_transform(&array![[0.0, 0.0], [w, 0.0], [w, h], [0.0, h]], size)
 }
 
 /// Find distance to line defined by two points
@@ -45,31 +43,155 @@ pub fn distance_to_line(p1: (f32, f32), p2: (f32, f32), query: (f32, f32)) -> f3
         / ((x2 - x1).powf(2.0) + (y2 - y1).powf(2.0)).sqrt()
 }
 
-/// Computes normalized and clipped distance transform (bwdist) for arbitray polygon.
-/// The MATLAB version of this uses a different algorithm, which is much faster but less general.
-/// Here, we simply cache the result instead.
+/// Squared distance transform of a sampled 1D function, computed as the lower envelope of the
+/// parabolas rooted at every finite sample. Infinite samples are treated as not being part of the set.
+fn squared_distance_1d(f: &[f64]) -> Vec<f64> {
+    // Locations of parabolas in lower envelope and boundaries between them
+    let mut v: Vec<usize> = Vec::with_capacity(f.len());
+    let mut z: Vec<f64> = Vec::with_capacity(f.len());
+
+    for (q, &fq) in f.iter().enumerate() {
+        if fq.is_infinite() {
+            continue;
+        }
+
+        // Remove parabolas that are hidden by the new one, and find the intersection with the last one
+        let mut s = f64::NEG_INFINITY;
+        while let (Some(&p), Some(&zp)) = (v.last(), z.last()) {
+            s = ((fq + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2.0 * (q - p) as f64);
+            if s <= zp {
+                v.pop();
+                z.pop();
+                s = f64::NEG_INFINITY;
+            } else {
+                break;
+            }
+        }
+        v.push(q);
+        z.push(s);
+    }
+
+    if v.is_empty() {
+        return vec![f64::INFINITY; f.len()];
+    }
+
+    // Evaluate lower envelope
+    let mut k = 0;
+    (0..f.len())
+        .map(|q| {
+            while k + 1 < v.len() && z[k + 1] < q as f64 {
+                k += 1;
+            }
+            let dq = q as f64 - v[k] as f64;
+            dq * dq + f[v[k]]
+        })
+        .collect()
+}
+
+/// Computes the exact Euclidean distance transform of a binary mask in linear time, that is,
+/// the distance of every pixel to the nearest `false` pixel. Pixels that are `false` have zero distance,
+/// and if there are no `false` pixels at all, all distances are infinite.
 ///
-/// To get the belnding mask for an image of a given `size` you can:
-///     polygon_distance_transform(&Mapping::identity().corners(size), size)
+/// Reference:
+///     Felzenszwalb, Pedro F., and Daniel P. Huttenlocher, "Distance Transforms of Sampled Functions,"
+///     Theory of Computing, Vol. 8, No. 19, 2012, pp. 415-428.
+pub fn euclidean_distance_transform(mask: &Array2<bool>) -> Array2<f32> {
+    // Work in f64 as squared distances quickly exceed f32's integer precision
+    let mut sq_dist = mask.mapv(|v| if v { f64::INFINITY } else { 0.0 });
+
+    // The transform is separable, so process all columns then all rows (in parallel!)
+    for axis in [Axis(0), Axis(1)] {
+        Zip::from(sq_dist.lanes_mut(axis)).par_for_each(|mut lane| {
+            let d = squared_distance_1d(&lane.to_vec());
+            lane.iter_mut().zip(d).for_each(|(l, v)| *l = v);
+        });
+    }
+    sq_dist.mapv(|v| v.sqrt() as f32)
+}
+
+/// Computes normalized distance transform of a mask, i.e: the distance of every valid (`true`) pixel
+/// to the nearest invalid one, divided by the maximum distance. Anything outside of the mask is
+/// considered invalid, which makes this suitable as a feathering weight for blending.
 ///
-/// Reference of matlab algorithm:
-///     Breu, Heinz, Joseph Gil, David Kirkpatrick, and Michael Werman, "Linear Time Euclidean
-///     Distance Transform Algorithms," IEEE Transactions on Pattern Analysis and Machine
-///     Intelligence, Vol. 17, No. 5, May 1995, pp. 529-533.
-pub fn polygon_distance_transform(corners: &Array2<f32>, size: (usize, usize)) -> Array2<f32> {
-    // Points is a Nx2 array of xy pairs
+/// Unlike `polygon_distance_transform`, this works for arbitrary full-frame validity masks.
+pub fn mask_distance_transform(mask: &Array2<bool>) -> Array2<f32> {
+    let (h, w) = mask.dim();
+    let mut padded = Array2::from_elem((h + 2, w + 2), false);
+    padded.slice_mut(s![1..h + 1, 1..w + 1]).assign(mask);
+
+    let mut weights = euclidean_distance_transform(&padded)
+        .slice(s![1..h + 1, 1..w + 1])
+        .to_owned();
+    let max = weights.fold(0.0, |a: f32, b| a.max(*b));
+    if max > 0.0 {
+        weights.mapv_inplace(|v| v / max);
+    }
+    weights
+}
+
+/// Rasterizes the polygon defined by its Nx2 `corners` onto an image of size (width, height).
+/// A pixel is inside if its center, i.e: its integer xy coordinate, is inside (even-odd rule).
+pub fn rasterize_polygon(corners: &Array2<f32>, size: (usize, usize)) -> Array2<bool> {
     let (width, height) = size;
-    let points = Array::from_shape_fn((width * height, 2), |(i, j)| {
-        if j == 0 {
-            (i % width) as f32
-        } else {
-            (i / width) as f32
+    let mut mask = Array2::from_elem((height, width), false);
+    let vertices: Vec<(f32, f32)> = corners.rows().into_iter().map(|p| (p[0], p[1])).collect();
+
+    Zip::indexed(mask.rows_mut()).par_for_each(|i, mut row| {
+        let y = i as f32;
+
+        // Find where edges cross this scanline, half-open test avoids double counting vertices
+        let crossings: Vec<f32> = vertices
+            .iter()
+            .circular_tuple_windows()
+            .filter_map(|(&(x1, y1), &(x2, y2))| {
+                ((y1 <= y) != (y2 <= y)).then(|| x1 + (y - y1) * (x2 - x1) / (y2 - y1))
+            })
+            .sorted_by(|a, b| a.total_cmp(b))
+            .collect();
+
+        for (x1, x2) in crossings.into_iter().tuples() {
+            let start = x1.ceil().clamp(0.0, width as f32) as usize;
+            let end = x2.ceil().clamp(0.0, width as f32) as usize;
+            row.slice_mut(s![start..end]).fill(true);
         }
     });
-    let mut weights = -polygon_sdf(&points, corners);
-    let max = weights.fold(-f32::INFINITY, |a, b| a.max(*b));
-    weights.mapv_inplace(|v| v / max);
-    weights.into_shape((height, width)).unwrap()
+    mask
+}
+
+/// Computes normalized and clipped distance transform (bwdist) for arbitray polygon.
+/// The polygon is first rasterized, then the linear time `euclidean_distance_transform` is used
+/// both inside and outside of it, making this accurate to within a pixel. Pixels outside of the
+/// polygon get negative weights, and the result is normalized by the maximum distance inside.
+///
+/// To get the blending mask for an image of a given `size` you can:
+///     polygon_distance_transform(&Mapping::identity().corners(size), size)
+pub fn polygon_distance_transform(corners: &Array2<f32>, size: (usize, usize)) -> Array2<f32> {
+    let (width, height) = size;
+    let inside = rasterize_polygon(corners, size);
+    if !inside.iter().any(|&v| v) {
+        return Array2::zeros((height, width));
+    }
+
+    // Pad the inside mask such that the image border does not count as part of the polygon
+    let mut padded = Array2::from_elem((height + 2, width + 2), false);
+    padded
+        .slice_mut(s![1..height + 1, 1..width + 1])
+        .assign(&inside);
+    let dist_inside = euclidean_distance_transform(&padded)
+        .slice(s![1..height + 1, 1..width + 1])
+        .to_owned();
+    let dist_outside = euclidean_distance_transform(&inside.mapv(|v| !v));
+
+    // Distances are between pixel centers, shift by half a pixel to put the edge in between
+    let mut weights = Zip::from(&inside)
+        .and(&dist_inside)
+        .and(&dist_outside)
+        .par_map_collect(|&is_in, &d_in, &d_out| if is_in { d_in - 0.5 } else { 0.5 - d_out });
+    let max = weights.fold(0.0, |a: f32, b| a.max(*b));
+    if max > 0.0 {
+        weights.mapv_inplace(|v| v / max);
+    }
+    weights
 }
 
 /// Akin to the distance transform used by opencv or bwdist in MATLB but much more general.
@@ -126,15 +248,155 @@ pub fn polygon_sdf(points: &Array2<f32>, vertices: &Array2<f32>) -> Array1<f32>
     s * d.mapv(|v| v.sqrt())
 }
 
+/// Per-pixel auxiliary layers accumulated alongside the merged canvas.
+/// Only frames with a non-zero blending weight at a pixel are considered as contributing to it.
+/// Pixels with no contributing frames have a count of zero, and all other layers are set to zero.
+#[derive(Debug, Clone)]
+pub struct MergeLayers {
+    /// Total blending weight, i.e: the normalization term of the weighted average.
+    pub weight: Array2<f32>,
+
+    /// Number of contributing frames.
+    pub count: Array2<f32>,
+
+    /// Mean index of contributing frames.
+    pub time_mean: Array2<f32>,
+
+    /// Smallest index of contributing frames.
+    pub time_min: Array2<f32>,
+
+    /// Largest index of contributing frames.
+    pub time_max: Array2<f32>,
+}
+
+impl MergeLayers {
+    /// Save every layer to `dir` as a .npy file named after it, creating the directory if needed.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        create_dir_all(dir)?;
+        write_npy(dir.join("weight.npy"), &self.weight)?;
+        write_npy(dir.join("count.npy"), &self.count)?;
+        write_npy(dir.join("time_mean.npy"), &self.time_mean)?;
+        write_npy(dir.join("time_min.npy"), &self.time_min)?;
+        write_npy(dir.join("time_max.npy"), &self.time_max)?;
+        Ok(())
+    }
+}
+
 /// Merge frames using simple linear blending
 /// If size (height, width) is specified, that will be used as the canvas size,
 /// otherwise, find smallest canvas size that fits all warps.
+///
+/// Each frame is blended using a feathering weight that decays towards its border. This can be
+/// further modulated using custom `weights` (i.e: to down-weight vignetted or inpainted regions)
+/// and `valid` masks (i.e: to discard dead columns). Both are HxW arrays, and either a single mask
+/// can be provided, which is shared across all frames, or one per frame.
 pub fn merge_arrays<S>(
     mappings: &[Mapping],
     frames: &[ArrayBase<S, Ix3>],
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> Result<Array3<f32>>
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    let (canvas, _) = _merge_arrays(
+        mappings,
+        frames.iter().map(|f| CowArray::from(f.view())),
+        common_size(frames)?,
+        weights,
+        valid,
+        size,
+        message,
+        false,
+    )?;
+    Ok(canvas)
+}
+
+/// Same as `merge_arrays` but also returns auxiliary coverage and time-of-capture layers.
+/// Frame indices correspond to the position of the frame in `frames`.
+pub fn merge_arrays_with_layers<S>(
+    mappings: &[Mapping],
+    frames: &[ArrayBase<S, Ix3>],
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> Result<(Array3<f32>, MergeLayers)>
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    let (canvas, layers) = _merge_arrays(
+        mappings,
+        frames.iter().map(|f| CowArray::from(f.view())),
+        common_size(frames)?,
+        weights,
+        valid,
+        size,
+        message,
+        true,
+    )?;
+    Ok((canvas, layers.expect("Layers should have been computed")))
+}
+
+/// Same as `merge_arrays` but frames are consumed one at a time from an iterator, such that they
+/// do not all need to be in memory at once. There should be one frame per mapping, and all frames
+/// must have the given `frame_size` (height, width, channels).
+pub fn merge_arrays_iter<I>(
+    mappings: &[Mapping],
+    frames: I,
+    frame_size: (usize, usize, usize),
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
     size: Option<(usize, usize)>,
     message: Option<&str>,
 ) -> Result<Array3<f32>>
+where
+    I: IntoIterator<Item = Array3<f32>>,
+{
+    let (canvas, _) = _merge_arrays(
+        mappings,
+        frames.into_iter().map(CowArray::from),
+        frame_size,
+        weights,
+        valid,
+        size,
+        message,
+        false,
+    )?;
+    Ok(canvas)
+}
+
+/// Same as `merge_arrays_iter` but also returns auxiliary layers, see `merge_arrays_with_layers`.
+pub fn merge_arrays_with_layers_iter<I>(
+    mappings: &[Mapping],
+    frames: I,
+    frame_size: (usize, usize, usize),
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> Result<(Array3<f32>, MergeLayers)>
+where
+    I: IntoIterator<Item = Array3<f32>>,
+{
+    let (canvas, layers) = _merge_arrays(
+        mappings,
+        frames.into_iter().map(CowArray::from),
+        frame_size,
+        weights,
+        valid,
+        size,
+        message,
+        true,
+    )?;
+    Ok((canvas, layers.expect("Layers should have been computed")))
+}
+
+/// Get the size of frames, if they all have the same size.
+fn common_size<S>(frames: &[ArrayBase<S, Ix3>]) -> Result<(usize, usize, usize)>
 where
     S: RawData<Elem = f32> + ndarray::Data,
 {
@@ -146,7 +408,67 @@ where
     else {
         return Err(anyhow!("All frames must have same size."));
     };
+    Ok(frame_size)
+}
+
+/// Feathering weights that decay towards the border of the valid region if validity masks are
+/// given (one per mask), otherwise a single weight that decays towards the frame's border.
+fn feathering_weights(valid: Option<&[Array2<bool>]>, size: (usize, usize)) -> Vec<Array2<f32>> {
+    let (h, w) = size;
+    if let Some(valid) = valid {
+        valid.par_iter().map(mask_distance_transform).collect()
+    } else {
+        vec![distance_transform((w, h))]
+    }
+}
+
+/// Get the mask that applies to the frame at `idx`, masks are either shared or per-frame.
+fn select_mask<T>(masks: &[Array2<T>], idx: usize) -> &Array2<T> {
+    if masks.len() == 1 {
+        &masks[0]
+    } else {
+        &masks[idx]
+    }
+}
+
+/// Ensure there's either a single shared mask or one per frame, and that they match the frame size.
+fn validate_masks<T>(
+    masks: Option<&[Array2<T>]>,
+    num_frames: usize,
+    size: (usize, usize),
+    name: &str,
+) -> Result<()> {
+    if let Some(masks) = masks {
+        if masks.len() != 1 && masks.len() != num_frames {
+            return Err(anyhow!(
+                "Expected either one or {num_frames} {name} masks, got {}.",
+                masks.len()
+            ));
+        }
+        if masks.iter().any(|m| m.dim() != size) {
+            return Err(anyhow!("All {name} masks must have same size as frames."));
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn _merge_arrays<'a, I>(
+    mappings: &[Mapping],
+    frames: I,
+    frame_size: (usize, usize, usize),
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+    with_layers: bool,
+) -> Result<(Array3<f32>, Option<MergeLayers>)>
+where
+    I: Iterator<Item = CowArray<'a, f32, Ix3>>,
+{
     let (h, w, c) = frame_size;
+    validate_masks(weights, mappings.len(), (h, w), "weight")?;
+    validate_masks(valid, mappings.len(), (h, w), "validity")?;
 
     let ((canvas_h, canvas_w), offset) = if let Some(val) = size {
         (val, Mapping::identity())
@@ -159,11 +481,21 @@ where
         ((canvas_h.ceil() as usize, canvas_w.ceil() as usize), offset)
     };
 
-    let mut canvas: Array3<f32> = Array3::zeros((canvas_h, canvas_w, (c + 1)));
-    let mut valid: Array2<bool> = Array2::from_elem((canvas_h, canvas_w), false);
-    let weights = distance_transform((w, h));
-    let weights = weights.slice(s![.., .., NewAxis]);
-    let merge = |dst: &mut [f32], src: &[f32]| {
+    // Canvas channels are the weighted pixel sum, followed by the total weight. If layers are needed,
+    // they are warped in a separate pass, such that they don't limit the number of frame channels.
+    // Layer channels are the total weight, contributing frame count, time sum, time min and time max.
+    let mut canvas: Array3<f32> = Array3::zeros((canvas_h, canvas_w, c + 1));
+    let mut canvas_valid: Array2<bool> = Array2::from_elem((canvas_h, canvas_w), false);
+    let mut layers_canvas = with_layers.then(|| {
+        let mut layers_canvas: Array3<f32> = Array3::zeros((canvas_h, canvas_w, 5));
+        layers_canvas.slice_mut(s![.., .., 3]).fill(f32::INFINITY);
+        layers_canvas
+            .slice_mut(s![.., .., 4])
+            .fill(f32::NEG_INFINITY);
+        (layers_canvas, canvas_valid.clone())
+    });
+    let feathers = feathering_weights(valid, (h, w));
+    let merge: fn(&mut [f32], &[f32]) = |dst, src| {
         // Redefine c because otherwise we capture outside scope and stuff breaks, not sure why.
         let c = src.len() - 1;
 
@@ -174,6 +506,18 @@ where
         // Also keep track of total blending weight
         dst[c] += src[c];
     };
+    let merge_layers: fn(&mut [f32], &[f32]) = |dst, src| {
+        // Source pixel is laid out as [weight, 1, time, time, time]
+        // Frames with zero weight here do not contribute
+        if src[0] <= 0.0 {
+            return;
+        }
+        dst[0] += src[0];
+        dst[1] += src[1];
+        dst[2] += src[2];
+        dst[3] = dst[3].min(src[3]);
+        dst[4] = dst[4].max(src[4]);
+    };
 
     // Points is a Nx2 array of xy pairs
     let points = Array::from_shape_fn((canvas_h * canvas_w, 2), |(i, j)| {
@@ -184,29 +528,91 @@ where
         }
     });
 
-    let pbar = get_pbar(frames.len(), message);
-    for (frame, map) in frames.iter().zip(mappings) {
-        let frame = concatenate(Axis(2), &[frame.view(), weights.view()])?;
-        map.transform(None, Some(offset.clone()))
-            .warp_array3_into::<f32, _, _, _, _, _>(
-                &frame.as_standard_layout(),
-                &mut canvas,
-                &mut valid,
+    let pbar = get_pbar(mappings.len(), message);
+    for (idx, (frame, map)) in frames.zip(mappings).enumerate() {
+        if frame.dim() != frame_size {
+            return Err(anyhow!(
+                "All frames must have size {frame_size:?}, got {:?}.",
+                frame.dim()
+            ));
+        }
+
+        // Combine feathering weight with any custom weights
+        let mut frame_weights = select_mask(&feathers, idx).clone();
+        if let Some(weights) = weights {
+            frame_weights *= select_mask(weights, idx);
+        }
+        let frame_weights = frame_weights.slice(s![.., .., NewAxis]);
+
+        let frame = concatenate(Axis(2), &[frame.view(), frame_weights.view()])?;
+        let map = map.transform(None, Some(offset.clone()));
+        map.warp_array3_into::<f32, _, _, _, _, _>(
+            &frame.as_standard_layout(),
+            &mut canvas,
+            &mut canvas_valid,
+            &points,
+            None,
+            Some(merge),
+        );
+        if let Some((layers_canvas, layers_valid)) = layers_canvas.as_mut() {
+            let ones = Array3::<f32>::ones((h, w, 1));
+            let time = Array3::<f32>::from_elem((h, w, 3), idx as f32);
+            let layers = concatenate(Axis(2), &[frame_weights.view(), ones.view(), time.view()])?;
+            map.warp_array3_into::<f32, _, _, _, _, _>(
+                &layers.as_standard_layout(),
+                layers_canvas,
+                layers_valid,
                 &points,
                 None,
-                Some(merge),
+                Some(merge_layers),
             );
+        }
         pbar.inc(1);
     }
 
-    let canvas = canvas.slice(s![.., .., ..c]).to_owned() / canvas.slice(s![.., .., -1..]);
-    Ok(canvas)
+    // Normalize by total weight, leaving pixels without any coverage as zero
+    let weight = canvas.slice(s![.., .., c]).to_owned();
+    let mut merged = canvas.slice(s![.., .., ..c]).to_owned();
+    Zip::from(merged.lanes_mut(Axis(2)))
+        .and(&weight)
+        .par_for_each(|mut px, &wt| {
+            if wt > 0.0 {
+                px.mapv_inplace(|v| v / wt)
+            }
+        });
+
+    let Some((layers_canvas, _)) = layers_canvas else {
+        return Ok((merged, None));
+    };
+
+    let count = layers_canvas.slice(s![.., .., 1]).to_owned();
+    let mut time_mean = layers_canvas.slice(s![.., .., 2]).to_owned();
+    let mut time_min = layers_canvas.slice(s![.., .., 3]).to_owned();
+    let mut time_max = layers_canvas.slice(s![.., .., 4]).to_owned();
+    azip!((mean in &mut time_mean, min in &mut time_min, max in &mut time_max, &n in &count) {
+        if n > 0.0 {
+            *mean /= n;
+        } else {
+            (*mean, *min, *max) = (0.0, 0.0, 0.0);
+        }
+    });
+
+    let layers = MergeLayers {
+        weight,
+        count,
+        time_mean,
+        time_min,
+        time_max,
+    };
+    Ok((merged, Some(layers)))
 }
 
 /// Wrapper for `merge_arrays` that converts to/from images.
 pub fn merge_images<P>(
     mappings: &[Mapping],
     frames: &[Image<P>],
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
     size: Option<(usize, usize)>,
     message: Option<&str>,
 ) -> Result<Image<P>>
@@ -219,6 +625,1277 @@ where
         .iter()
         .map(|f| ref_image_to_array3(f).mapv(f32::from))
         .collect();
-    let merged = merge_arrays(mappings, &frames[..], size.map(|(w, h)| (h, w)), message)?;
+    let merged = merge_arrays(
+        mappings,
+        &frames[..],
+        weights,
+        valid,
+        size.map(|(w, h)| (h, w)),
+        message,
+    )?;
     Ok(array3_to_image(merged.mapv(<P as Pixel>::Subpixel::clamp)))
 }
+
+/// Wrapper for `merge_arrays_with_layers` that converts to/from images.
+pub fn merge_images_with_layers<P>(
+    mappings: &[Mapping],
+    frames: &[Image<P>],
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> Result<(Image<P>, MergeLayers)>
+where
+    P: Pixel + Send + Sync,
+    f32: From<<P as Pixel>::Subpixel>,
+    <P as Pixel>::Subpixel: Clamp<f32>,
+{
+    let frames: Vec<_> = frames
+        .iter()
+        .map(|f| ref_image_to_array3(f).mapv(f32::from))
+        .collect();
+    let (merged, layers) = merge_arrays_with_layers(
+        mappings,
+        &frames[..],
+        weights,
+        valid,
+        size.map(|(w, h)| (h, w)),
+        message,
+    )?;
+    Ok((
+        array3_to_image(merged.mapv(<P as Pixel>::Subpixel::clamp)),
+        layers,
+    ))
+}
+
+/// Merge frames by drizzling them onto a canvas that is upscaled by `scale`, which recovers detail
+/// beyond the native resolution when many frames with sub-pixel shifts are available.
+/// Every input pixel is shrunk to a square drop whose side is `pixfrac` times its size, mapped onto
+/// the output canvas, and deposits its value into each output pixel it overlaps, weighted by the
+/// overlap area and the blending weight. See `merge_arrays` for the meaning of `weights` and `valid`.
+///
+/// Drops are kept axis-aligned (akin to the "turbo" kernel), and their size is estimated from the
+/// local scale of each mapping at the center of the frame. Small `pixfrac` values give sharper results
+/// but might leave holes in the canvas (which are set to zero) if there aren't enough frames.
+///
+/// If size (height, width) is specified, that will be used as the (upscaled) canvas size,
+/// otherwise, find smallest canvas size that fits all warps.
+///
+/// See: Fruchter, Andrew S., and Richard N. Hook, "Drizzle: A Method for the Linear Reconstruction
+///     of Undersampled Images," Publications of the Astronomical Society of the Pacific, Vol. 114,
+///     No. 792, 2002, pp. 144-152.
+#[allow(clippy::too_many_arguments)]
+pub fn drizzle_arrays<S>(
+    mappings: &[Mapping],
+    frames: &[ArrayBase<S, Ix3>],
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
+    scale: f32,
+    pixfrac: f32,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> Result<Array3<f32>>
+where
+    S: RawData<Elem = f32> + ndarray::Data + Sync,
+{
+    let (h, w, c) = common_size(frames)?;
+    validate_masks(weights, frames.len(), (h, w), "weight")?;
+    validate_masks(valid, frames.len(), (h, w), "validity")?;
+    if scale <= 0.0 || pixfrac <= 0.0 {
+        return Err(anyhow!("Both `scale` and `pixfrac` must be positive."));
+    }
+
+    let ((canvas_h, canvas_w), offset) = if let Some(val) = size {
+        (val, Mapping::identity())
+    } else {
+        let (extent, offset) = Mapping::maximum_extent(mappings, &[(w, h)]);
+        let (canvas_w, canvas_h) = extent
+            .iter()
+            .collect_tuple()
+            .expect("Canvas should have width and height");
+        (
+            (
+                (canvas_h * scale).ceil() as usize,
+                (canvas_w * scale).ceil() as usize,
+            ),
+            offset,
+        )
+    };
+    let feathers = feathering_weights(valid, (h, w));
+
+    // Points is a Nx2 array of xy pairs, here these are the frame's pixel centers
+    let points = Array::from_shape_fn((h * w, 2), |(i, j)| if j == 0 { i % w } else { i / w });
+
+    // Length of overlap between a drop centered at `center` and the output pixel at `idx`
+    let overlap = |center: f32, half: f32, idx: usize| {
+        ((center + half).min(idx as f32 + 0.5) - (center - half).max(idx as f32 - 0.5)).max(0.0)
+    };
+
+    // Accumulate weighted pixel sum and total weight into a single canvas, like `merge_arrays`.
+    // Frames are processed one at a time, and each of their drops is binned by the output rows it
+    // covers, such that rows of the canvas can be updated in parallel without any contention.
+    let mut canvas = Array3::<f32>::zeros((canvas_h, canvas_w, c + 1));
+    let pbar = get_pbar(frames.len(), message);
+    for (idx, (frame, map)) in frames.iter().zip(mappings).enumerate() {
+        // Map pixel centers forward onto the canvas
+        let forward = map.transform(None, Some(offset.clone())).inverse();
+        let centers = forward.warp_points(&points);
+
+        // Estimate the size of a drop from the local scale of the mapping
+        let (cx, cy) = (w as f32 / 2.0, h as f32 / 2.0);
+        let local = forward.warp_points(&array![[cx, cy], [cx + 1.0, cy], [cx, cy + 1.0]]);
+        let (dx, dy) = (&local.row(1) - &local.row(0), &local.row(2) - &local.row(0));
+        let footprint = (dx[0] * dy[1] - dx[1] * dy[0]).abs().sqrt();
+        let half = 0.5 * pixfrac * scale * footprint;
+        let area = (2.0 * half).powi(2);
+
+        let mut frame_weights = select_mask(&feathers, idx).clone();
+        if let Some(weights) = weights {
+            frame_weights *= select_mask(weights, idx);
+        }
+
+        // Drops as (input pixel, location in output pixels, weight), and the drops covering every row
+        let mut drops: Vec<((usize, usize), (f32, f32), f32)> = vec![];
+        let mut rows: Vec<Vec<usize>> = vec![vec![]; canvas_h];
+        for (k, center) in centers.rows().into_iter().enumerate() {
+            let (y, x) = (k / w, k % w);
+            let weight = frame_weights[(y, x)];
+            if weight <= 0.0 {
+                continue;
+            }
+
+            let u = (center[0] + 0.5) * scale - 0.5;
+            let v = (center[1] + 0.5) * scale - 0.5;
+            let (y0, y1) = (
+                (v - half + 0.5).floor().max(0.0),
+                (v + half + 0.5).floor().min(canvas_h as f32 - 1.0),
+            );
+            if y0 > y1 {
+                continue;
+            }
+            for oy in (y0 as usize)..=(y1 as usize) {
+                rows[oy].push(drops.len());
+            }
+            drops.push(((y, x), (u, v), weight));
+        }
+
+        canvas
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .zip(&rows)
+            .enumerate()
+            .for_each(|(oy, (mut row, drop_indices))| {
+                for &d in drop_indices {
+                    let ((y, x), (u, v), weight) = drops[d];
+                    let (x0, x1) = (
+                        (u - half + 0.5).floor().max(0.0),
+                        (u + half + 0.5).floor().min(canvas_w as f32 - 1.0),
+                    );
+                    if x0 > x1 {
+                        continue;
+                    }
+
+                    let overlap_y = overlap(v, half, oy);
+                    for ox in (x0 as usize)..=(x1 as usize) {
+                        let a = overlap_y * overlap(u, half, ox) / area * weight;
+                        if a <= 0.0 {
+                            continue;
+                        }
+                        for ch in 0..c {
+                            row[(ox, ch)] += frame[(y, x, ch)] * a;
+                        }
+                        row[(ox, c)] += a;
+                    }
+                }
+            });
+        pbar.inc(1);
+    }
+    pbar.finish_and_clear();
+
+    // Normalize by total weight, leaving pixels without any coverage as zero
+    let weight = canvas.slice(s![.., .., c]).to_owned();
+    let mut merged = canvas.slice(s![.., .., ..c]).to_owned();
+    Zip::from(merged.lanes_mut(Axis(2)))
+        .and(&weight)
+        .par_for_each(|mut px, &wt| {
+            if wt > 0.0 {
+                px.mapv_inplace(|v| v / wt)
+            }
+        });
+    Ok(merged)
+}
+
+/// Wrapper for `drizzle_arrays` that converts to/from images.
+/// Note: If size is specified, it is expected to be (width, height) as with `merge_images`.
+#[allow(clippy::too_many_arguments)]
+pub fn drizzle_images<P>(
+    mappings: &[Mapping],
+    frames: &[Image<P>],
+    weights: Option<&[Array2<f32>]>,
+    valid: Option<&[Array2<bool>]>,
+    scale: f32,
+    pixfrac: f32,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> Result<Image<P>>
+where
+    P: Pixel + Send + Sync,
+    f32: From<<P as Pixel>::Subpixel>,
+    <P as Pixel>::Subpixel: Clamp<f32>,
+{
+    let frames: Vec<_> = frames
+        .iter()
+        .map(|f| ref_image_to_array3(f).mapv(f32::from))
+        .collect();
+    let merged = drizzle_arrays(
+        mappings,
+        &frames[..],
+        weights,
+        valid,
+        scale,
+        pixfrac,
+        size.map(|(w, h)| (h, w)),
+        message,
+    )?;
+    Ok(array3_to_image(merged.mapv(<P as Pixel>::Subpixel::clamp)))
+}
+
+/// Per-pixel photon statistics accumulated when merging binary (SPAD) frames onto a canvas.
+/// Pixels that no frame covers have zero trials.
+#[derive(Debug, Clone)]
+pub struct PhotonCounts {
+    /// Total number of detections, of shape (height, width, channels).
+    pub detections: Array3<f32>,
+    /// Total number of bitplanes that observed each pixel, of shape (height, width).
+    pub trials: Array2<f32>,
+}
+
+impl PhotonCounts {
+    /// Maximum-likelihood estimate of the per-bitplane detection probability, which is simply the
+    /// fraction of trials that resulted in a detection. Uncovered pixels are set to zero.
+    pub fn probability(&self) -> Array3<f32> {
+        let mut probability = self.detections.clone();
+        Zip::from(probability.lanes_mut(Axis(2)))
+            .and(&self.trials)
+            .par_for_each(|mut px, &n| {
+                if n > 0.0 {
+                    px.mapv_inplace(|d| (d / n).clamp(0.0, 1.0))
+                } else {
+                    px.fill(0.0)
+                }
+            });
+        probability
+    }
+
+    /// Maximum-likelihood estimate of the flux (in photons per bitplane) under a Bernoulli model
+    /// of the SPAD's response, that is `-ln(1-p)` where `p` is the detection probability.
+    ///
+    /// Pixels that always detected a photon would have an infinite flux, so `p` is clipped to at most
+    /// `n/(n+1)`, where `n` is the number of trials for that pixel. If a `dark_count` rate is given,
+    /// as the probability of a spurious detection in a single bitplane, its contribution is removed
+    /// from the flux estimate, which is then clipped to be non-negative.
+    pub fn flux(&self, dark_count: Option<f32>) -> Array3<f32> {
+        let dark_flux = -(1.0 - dark_count.unwrap_or(0.0).clamp(0.0, 1.0 - f32::EPSILON)).ln();
+        let mut flux = self.detections.clone();
+        Zip::from(flux.lanes_mut(Axis(2)))
+            .and(&self.trials)
+            .par_for_each(|mut px, &n| {
+                if n > 0.0 {
+                    px.mapv_inplace(|d| {
+                        let p = (d / n).clamp(0.0, n / (n + 1.0));
+                        (-(1.0 - p).ln() - dark_flux).max(0.0)
+                    })
+                } else {
+                    px.fill(0.0)
+                }
+            });
+        flux
+    }
+}
+
+/// Mosaic that is built incrementally by adding frames one at a time, as an average of all frames
+/// that cover each pixel. The canvas is in reference coordinates and grows as needed to fit new
+/// frames, and can be rendered in the viewport of any mapping, e.g: to register a new frame to it.
+#[derive(Debug, Clone)]
+pub struct Mosaic {
+    /// Sum of all frames, with the number of frames that cover each pixel as the last channel.
+    canvas: Array3<f32>,
+    /// Position of the canvas' top-left pixel in reference coordinates.
+    origin: (isize, isize),
+}
+
+impl Mosaic {
+    /// Create an empty mosaic of frames with the given number of channels.
+    pub fn new(channels: usize) -> Self {
+        Self {
+            canvas: Array3::zeros((0, 0, channels + 1)),
+            origin: (0, 0),
+        }
+    }
+
+    /// Size (height, width) of the canvas.
+    pub fn size(&self) -> (usize, usize) {
+        let (h, w, _) = self.canvas.dim();
+        (h, w)
+    }
+
+    /// Grow the canvas such that it contains the given extent (in reference coordinates),
+    /// and return the range of canvas pixels that the extent spans as ((x0, y0), (x1, y1)).
+    fn grow(&mut self, min: &Array1<f32>, max: &Array1<f32>) -> ((usize, usize), (usize, usize)) {
+        let (h, w, c) = self.canvas.dim();
+        let (min_x, min_y) = (min[0].floor() as isize, min[1].floor() as isize);
+        let (max_x, max_y) = (max[0].ceil() as isize, max[1].ceil() as isize);
+        let (x0, y0, x1, y1) = if h * w == 0 {
+            (min_x, min_y, max_x, max_y)
+        } else {
+            (
+                min_x.min(self.origin.0),
+                min_y.min(self.origin.1),
+                max_x.max(self.origin.0 + w as isize),
+                max_y.max(self.origin.1 + h as isize),
+            )
+        };
+
+        if (x0, y0) != self.origin || (x1 - x0) as usize != w || (y1 - y0) as usize != h {
+            let mut canvas = Array3::zeros(((y1 - y0) as usize, (x1 - x0) as usize, c));
+            let (dx, dy) = ((self.origin.0 - x0) as usize, (self.origin.1 - y0) as usize);
+            if h * w != 0 {
+                canvas
+                    .slice_mut(s![dy..dy + h, dx..dx + w, ..])
+                    .assign(&self.canvas);
+            }
+            self.canvas = canvas;
+            self.origin = (x0, y0);
+        }
+        (
+            ((min_x - x0) as usize, (min_y - y0) as usize),
+            ((max_x - x0) as usize, (max_y - y0) as usize),
+        )
+    }
+
+    /// Add a (h, w, c) frame to the mosaic, where the mapping maps reference coordinates to the frame's
+    /// coordinates, as with `merge_arrays`.
+    pub fn add<S>(&mut self, frame: &ArrayBase<S, Ix3>, mapping: &Mapping)
+    where
+        S: RawData<Elem = f32> + ndarray::Data,
+    {
+        let (h, w, _) = frame.dim();
+        let (min, max) = mapping.extent((w, h));
+        let ((x0, y0), (x1, y1)) = self.grow(&min, &max);
+
+        // Only warp the frame over the region of the canvas it covers
+        let offset = Mapping::shift(
+            (self.origin.0 + x0 as isize) as f32,
+            (self.origin.1 + y0 as isize) as f32,
+        );
+        let (warped, valid) =
+            mapping
+                .transform(None, Some(offset))
+                .warp_array3(frame, (y1 - y0, x1 - x0), None);
+
+        let mut region = self.canvas.slice_mut(s![y0..y1, x0..x1, ..]);
+        Zip::from(region.lanes_mut(Axis(2)))
+            .and(warped.lanes(Axis(2)))
+            .and(&valid)
+            .par_for_each(|mut px, value, &is_valid| {
+                if is_valid {
+                    let c = value.len();
+                    px.slice_mut(s![..c]).scaled_add(1.0, &value);
+                    px[c] += 1.0;
+                }
+            });
+    }
+
+    /// Render the mosaic in the viewport of a frame of size (width, height) with the given mapping.
+    /// Returns the rendered (h, w, c) frame along with its (h, w, 1) coverage, which is one where
+    /// the mosaic is defined and falls off to zero elsewhere.
+    pub fn viewport(&self, mapping: &Mapping, size: (usize, usize)) -> (Array3<f32>, Array3<f32>) {
+        let (w, h) = size;
+        let c = self.canvas.dim().2 - 1;
+        if self.canvas.is_empty() {
+            return (Array3::zeros((h, w, c)), Array3::zeros((h, w, 1)));
+        }
+
+        let offset = Mapping::shift(-self.origin.0 as f32, -self.origin.1 as f32);
+        let (warped, _) =
+            mapping
+                .inverse()
+                .transform(Some(offset), None)
+                .warp_array3(&self.canvas, (h, w), None);
+
+        let counts = warped.slice(s![.., .., c..]);
+        let mut frame = warped.slice(s![.., .., ..c]).to_owned();
+        Zip::from(frame.lanes_mut(Axis(2)))
+            .and(counts.lanes(Axis(2)))
+            .par_for_each(|mut px, n| {
+                if n[0] > 0.0 {
+                    px.mapv_inplace(|v| v / n[0]);
+                }
+            });
+        (frame, counts.mapv(|n| n.clamp(0.0, 1.0)))
+    }
+}
+
+/// Accumulate detection and trial counts of binary frames onto a canvas, without any blending.
+/// Each frame holds the number of detections of every pixel over `num_trials` bitplanes, and its
+/// optional validity mask (as with `merge_arrays`) excludes pixels from both counts. This
+/// preserves the photon statistics needed to estimate flux, see `PhotonCounts::flux`.
+///
+/// If size (height, width) is specified, that will be used as the canvas size,
+/// otherwise, find smallest canvas size that fits all warps.
+pub fn merge_photon_counts<S>(
+    mappings: &[Mapping],
+    frames: &[ArrayBase<S, Ix3>],
+    num_trials: f32,
+    valid: Option<&[Array2<bool>]>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> Result<PhotonCounts>
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    let (h, w, c) = common_size(frames)?;
+    validate_masks(valid, frames.len(), (h, w), "validity")?;
+    if num_trials <= 0.0 {
+        return Err(anyhow!("Argument `num_trials` must be positive."));
+    }
+
+    let ((canvas_h, canvas_w), offset) = if let Some(val) = size {
+        (val, Mapping::identity())
+    } else {
+        let (extent, offset) = Mapping::maximum_extent(mappings, &[(w, h)]);
+        let (canvas_w, canvas_h) = extent
+            .iter()
+            .collect_tuple()
+            .expect("Canvas should have width and height");
+        ((canvas_h.ceil() as usize, canvas_w.ceil() as usize), offset)
+    };
+
+    // Canvas channels are the detection counts followed by the number of trials
+    let mut canvas: Array3<f32> = Array3::zeros((canvas_h, canvas_w, c + 1));
+    let mut canvas_valid: Array2<bool> = Array2::from_elem((canvas_h, canvas_w), false);
+    let merge: fn(&mut [f32], &[f32]) = |dst, src| {
+        // Pixels with no trials carry no information (they might have been masked out)
+        let c = src.len() - 1;
+        if src[c] <= 0.0 {
+            return;
+        }
+        for i in 0..c {
+            dst[i] += src[i];
+        }
+        dst[c] += src[c];
+    };
+
+    // Points is a Nx2 array of xy pairs
+    let points = Array::from_shape_fn((canvas_h * canvas_w, 2), |(i, j)| {
+        if j == 0 {
+            i % canvas_w
+        } else {
+            i / canvas_w
+        }
+    });
+
+    let pbar = get_pbar(frames.len(), message);
+    for (idx, (frame, map)) in frames.iter().zip(mappings).enumerate() {
+        let trials = if let Some(valid) = valid {
+            select_mask(valid, idx).mapv(|v| if v { num_trials } else { 0.0 })
+        } else {
+            Array2::from_elem((h, w), num_trials)
+        };
+        let frame = concatenate(Axis(2), &[frame.view(), trials.slice(s![.., .., NewAxis])])?;
+        map.transform(None, Some(offset.clone()))
+            .warp_array3_into::<f32, _, _, _, _, _>(
+                &frame.as_standard_layout(),
+                &mut canvas,
+                &mut canvas_valid,
+                &points,
+                None,
+                Some(merge),
+            );
+        pbar.inc(1);
+    }
+    pbar.finish_and_clear();
+
+    Ok(PhotonCounts {
+        detections: canvas.slice(s![.., .., ..c]).to_owned(),
+        trials: canvas.slice(s![.., .., c]).to_owned(),
+    })
+}
+
+/// Warp every bitplane individually with its own mapping and splat its binary values onto a canvas,
+/// accumulating detection and trial counts as with `merge_photon_counts`. Unlike merging granular
+/// frames, this does not assume the scene is static over several bitplanes and thus avoids any motion
+/// blur within those groups.
+///
+/// Bitplanes are read directly from the cube, of shape (t, h, w/8) if `bitpacked` (most significant bit
+/// first), otherwise (t, h, w). No float frames are materialized: each set pixel center is mapped onto
+/// the canvas and added to its nearest canvas pixel. Mappings are expected to be defined relative to
+/// frames that have been downscaled by `downscale`, and an optional validity mask at the bitplanes'
+/// full resolution can exclude pixels (i.e: hot/dead pixels) from both counts.
+///
+/// If size (height, width) is specified, that will be used as the canvas size,
+/// otherwise, find smallest canvas size that fits all warps.
+///
+/// Note: Bitplanes are processed in parallel, in batches, and splatted onto a single shared canvas.
+pub fn splat_bitplanes<S>(
+    mappings: &[Mapping],
+    bitplanes: &ArrayBase<S, Ix3>,
+    bitpacked: bool,
+    downscale: f32,
+    valid: Option<&Array2<bool>>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> Result<PhotonCounts>
+where
+    S: RawData<Elem = u8> + ndarray::Data + Sync,
+{
+    let (t, h, w) = bitplanes.dim();
+    let w = if bitpacked { w * 8 } else { w };
+    if mappings.len() != t {
+        return Err(anyhow!(
+            "Expected one mapping per bitplane, got {} mappings and {t} bitplanes.",
+            mappings.len()
+        ));
+    }
+    if downscale <= 0.0 {
+        return Err(anyhow!("Argument `downscale` must be positive."));
+    }
+    if let Some(valid) = valid {
+        if valid.dim() != (h, w) {
+            return Err(anyhow!(
+                "Validity mask has shape {:?}, expected {:?}.",
+                valid.dim(),
+                (h, w)
+            ));
+        }
+    }
+
+    let ((canvas_h, canvas_w), offset) = if let Some(val) = size {
+        (val, Mapping::identity())
+    } else {
+        let frame_size = (
+            (w as f32 / downscale).round() as usize,
+            (h as f32 / downscale).round() as usize,
+        );
+        let (extent, offset) = Mapping::maximum_extent(mappings, &[frame_size]);
+        let (canvas_w, canvas_h) = extent
+            .iter()
+            .collect_tuple()
+            .expect("Canvas should have width and height");
+        ((canvas_h.ceil() as usize, canvas_w.ceil() as usize), offset)
+    };
+
+    // Pixel centers of the full resolution bitplane, in the downscaled frame's coordinates
+    let coord = |i: usize| (i as f32 + 0.5) / downscale - 0.5;
+
+    // Accumulate detections and trials into a single canvas, laid out as [detections, trials].
+    // Bitplanes are processed in batches, the set pixels of each bitplane are mapped onto the canvas
+    // in parallel and binned by output row, such that rows of the canvas can then be updated in
+    // parallel without any contention, like `drizzle_arrays`.
+    let mut canvas = Array3::<f32>::zeros((canvas_h, canvas_w, 2));
+    let pbar = get_pbar(t, message);
+    let batch_size = rayon::current_num_threads();
+    for (batch, maps) in bitplanes
+        .axis_chunks_iter(Axis(0), batch_size)
+        .zip(mappings.chunks(batch_size))
+    {
+        // Canvas column and value of every splatted pixel, binned by canvas row, for every bitplane
+        let bins: Vec<Vec<Vec<(usize, u8)>>> = batch
+            .axis_iter(Axis(0))
+            .into_par_iter()
+            .zip(maps)
+            .map(|(bitplane, map)| {
+                // Forward mapping from frame to canvas
+                let mat = map.transform(None, Some(offset.clone())).inverse().mat;
+                let mut rows = vec![vec![]; canvas_h];
+
+                for y in 0..h {
+                    let fy = coord(y);
+                    for x in 0..w {
+                        if valid.is_some_and(|v| !v[(y, x)]) {
+                            continue;
+                        }
+                        let bit = if bitpacked {
+                            (bitplane[(y, x / 8)] >> (7 - x % 8)) & 1
+                        } else {
+                            (bitplane[(y, x)] != 0) as u8
+                        };
+
+                        let fx = coord(x);
+                        let d = (mat[(2, 0)] * fx + mat[(2, 1)] * fy + mat[(2, 2)]).max(1e-8);
+                        let u = ((mat[(0, 0)] * fx + mat[(0, 1)] * fy + mat[(0, 2)]) / d).round();
+                        let v = ((mat[(1, 0)] * fx + mat[(1, 1)] * fy + mat[(1, 2)]) / d).round();
+                        if u < 0.0 || v < 0.0 || u >= canvas_w as f32 || v >= canvas_h as f32 {
+                            continue;
+                        }
+                        rows[v as usize].push((u as usize, bit));
+                    }
+                }
+                pbar.inc(1);
+                rows
+            })
+            .collect();
+
+        canvas
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(oy, mut row)| {
+                for &(u, bit) in bins.iter().flat_map(|rows| &rows[oy]) {
+                    row[(u, 0)] += bit as f32;
+                    row[(u, 1)] += 1.0;
+                }
+            });
+    }
+    pbar.finish_and_clear();
+
+    Ok(PhotonCounts {
+        detections: canvas.slice(s![.., .., 0..1]).to_owned(),
+        trials: canvas.slice(s![.., .., 1]).to_owned(),
+    })
+}
+
+/// Convert an sRGB encoded value in [0, 1] to its linear intensity.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert a linear intensity in [0, 1] to its sRGB encoded value.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Estimate per-frame gains that minimize intensity differences between overlapping frames,
+/// such that multiplying each frame by its gain before blending removes banding caused by
+/// exposure fluctuations. Returns an array of shape (num_frames, c) if `per_channel` is set,
+/// otherwise a single gain per frame is estimated and the returned shape is (num_frames, 1).
+///
+/// Overlaps are found by warping a sparse grid of points from each frame into the other, and gains
+/// are solved for in closed form by minimizing:
+///     sum_ij N_ij * ((g_i * I_ij - g_j * I_ji)^2 / sigma_n^2 + (1 - g_i)^2 / sigma_g^2)
+/// Where N_ij is the number of overlapping points between frames i and j, and I_ij is the mean
+/// of frame i over that overlap. Intensities are normalized by their global mean, making `sigma_n`
+/// a relative error. Only frames that are at most `max_offset` indices apart are compared, if set.
+///
+/// See: Brown, Matthew, and David G. Lowe, "Automatic Panoramic Image Stitching using Invariant
+///     Features," International Journal of Computer Vision, Vol. 74, No. 1, 2007, pp. 59-73.
+#[allow(clippy::too_many_arguments)]
+pub fn gain_compensation<S>(
+    mappings: &[Mapping],
+    frames: &[ArrayBase<S, Ix3>],
+    per_channel: bool,
+    sigma_n: Option<f32>,
+    sigma_g: Option<f32>,
+    max_offset: Option<usize>,
+    message: Option<&str>,
+) -> Result<Array2<f32>>
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    let frame_size = common_size(frames)?;
+    if mappings.len() != frames.len() {
+        return Err(anyhow!("Expected one mapping per frame."));
+    }
+    let (h, w, c) = frame_size;
+    let num_frames = frames.len();
+    let frames: Vec<_> = frames.iter().map(|f| f.as_standard_layout()).collect();
+
+    // Sparse grid of xy points at which overlaps are evaluated, and the frame values there
+    let step = (h.max(w) / 64).max(1);
+    let points: Vec<usize> = iproduct!((0..h).step_by(step), (0..w).step_by(step))
+        .flat_map(|(y, x)| [x, y])
+        .collect();
+    let num_points = points.len() / 2;
+    let points = Array2::from_shape_vec((num_points, 2), points)?;
+    let samples: Vec<Array2<f32>> = frames
+        .iter()
+        .map(|f| {
+            Array2::from_shape_fn((num_points, c), |(k, ch)| {
+                f[(points[(k, 1)], points[(k, 0)], ch)]
+            })
+        })
+        .collect();
+
+    // Only consider pairs of frames with overlapping extents
+    let extents: Vec<_> = mappings.iter().map(|m| m.extent((w, h))).collect();
+    let pairs: Vec<(usize, usize)> = (0..num_frames)
+        .tuple_combinations()
+        .filter(|&(i, j)| {
+            let ((min_i, max_i), (min_j, max_j)) = (&extents[i], &extents[j]);
+            j - i <= max_offset.unwrap_or(usize::MAX)
+                && (0..2).all(|k| min_i[k] < max_j[k] && min_j[k] < max_i[k])
+        })
+        .collect();
+
+    // Find number of overlapping points and mean intensity of both frames over the overlap
+    let pbar = get_pbar(pairs.len(), message);
+    let overlaps: Vec<(usize, usize, f32, Array1<f32>, Array1<f32>)> = pairs
+        .par_iter()
+        .map(|&(i, j)| {
+            let mut warped = Array2::<f32>::zeros((num_points, c));
+            let mut valid = Array1::<bool>::from_elem(num_points, false);
+            mappings[j]
+                .transform(None, Some(mappings[i].inverse()))
+                .warp_array3_into::<f32, _, _, _, _, _>(
+                    &frames[j],
+                    &mut warped,
+                    &mut valid,
+                    &points,
+                    None,
+                    None,
+                );
+
+            let mut sum_i = Array1::<f32>::zeros(c);
+            let mut sum_j = Array1::<f32>::zeros(c);
+            let mut n = 0.0;
+            for ((p_i, p_j), is_valid) in
+                samples[i].rows().into_iter().zip(warped.rows()).zip(&valid)
+            {
+                if *is_valid {
+                    sum_i += &p_i;
+                    sum_j += &p_j;
+                    n += 1.0;
+                }
+            }
+            pbar.inc(1);
+            (i, j, n, sum_i / n.max(1.0), sum_j / n.max(1.0))
+        })
+        .collect();
+    pbar.finish_and_clear();
+
+    // Solve normal equations independently for every channel (or for the mean intensity)
+    let num_gains = if per_channel { c } else { 1 };
+    let alpha = 1.0 / sigma_n.unwrap_or(0.1).powi(2);
+    let beta = 1.0 / sigma_g.unwrap_or(0.1).powi(2);
+    let mut gains = Array2::<f32>::ones((num_frames, num_gains));
+
+    for k in 0..num_gains {
+        let intensity = |v: &Array1<f32>| if per_channel { v[k] } else { v.mean().unwrap() };
+        let total: f32 = overlaps.iter().map(|(_, _, n, _, _)| n).sum();
+        let norm = overlaps
+            .iter()
+            .map(|(_, _, n, mean_i, mean_j)| n * (intensity(mean_i) + intensity(mean_j)) / 2.0)
+            .sum::<f32>()
+            / total;
+        if total == 0.0 || norm <= 0.0 {
+            continue;
+        }
+
+        // A weak prior (worth a single point) keeps frames without any overlap at unit gain
+        let mut a = Array2::<f32>::eye(num_frames) * beta;
+        let mut b = Array1::<f32>::from_elem(num_frames, beta);
+
+        for (i, j, n, mean_i, mean_j) in overlaps.iter() {
+            let (i, j, n) = (*i, *j, *n);
+            let (i_ij, i_ji) = (intensity(mean_i) / norm, intensity(mean_j) / norm);
+
+            a[(i, i)] += n * (2.0 * alpha * i_ij * i_ij + beta);
+            a[(j, j)] += n * (2.0 * alpha * i_ji * i_ji + beta);
+            a[(i, j)] -= n * 2.0 * alpha * i_ij * i_ji;
+            a[(j, i)] -= n * 2.0 * alpha * i_ij * i_ji;
+            b[i] += n * beta;
+            b[j] += n * beta;
+        }
+        gains.column_mut(k).assign(&a.solve_into(b)?);
+    }
+    Ok(gains)
+}
+
+/// Multiply every frame by its gains, as estimated by `gain_compensation`.
+pub fn apply_gains<S>(frames: &[ArrayBase<S, Ix3>], gains: &Array2<f32>) -> Vec<Array3<f32>>
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    frames
+        .iter()
+        .zip(gains.rows())
+        .map(|(frame, gain)| frame * &gain)
+        .collect()
+}
+
+// --------------------------------------------------------------- Python Interface ---------------------------------------------------------------
+pub fn pyarray_to_array2_bridge<T: Element>(arr: &Bound<'_, PyAny>) -> PyResult<Array2<T>> {
+    let arr = pyarray_cast(arr)?.to_owned_array();
+    let ndim = arr.ndim();
+    let arr = arr
+        .into_dimensionality()
+        .map_err(|_| anyhow!("Expected array with 2 dimensions. Got {ndim:} dims."))?;
+    Ok(arr)
+}
+
+/// Convert an optional list of python arrays to HxW masks.
+fn pymasks_to_masks_bridge<T: Element>(
+    masks: Option<Vec<Bound<'_, PyAny>>>,
+) -> PyResult<Option<Vec<Array2<T>>>> {
+    masks
+        .map(|ms| {
+            ms.iter()
+                .map(pyarray_to_array2_bridge)
+                .collect::<PyResult<Vec<_>>>()
+        })
+        .transpose()
+}
+
+impl MergeLayers {
+    /// Convert layers to a python dictionary of numpy arrays, keyed by layer name.
+    pub fn to_pydict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new_bound(py);
+        dict.set_item("weight", self.weight.to_pyarray_bound(py))?;
+        dict.set_item("count", self.count.to_pyarray_bound(py))?;
+        dict.set_item("time_mean", self.time_mean.to_pyarray_bound(py))?;
+        dict.set_item("time_min", self.time_min.to_pyarray_bound(py))?;
+        dict.set_item("time_max", self.time_max.to_pyarray_bound(py))?;
+        Ok(dict)
+    }
+}
+
+/// Computes normalized and clipped distance transform (bwdist) for rectangle that fills image.
+/// Size is expected to be (width, height), returned array has shape (height, width).
+#[pyfunction]
+#[pyo3(name = "distance_transform", signature = (size))]
+pub fn distance_transform_py(py: Python<'_>, size: (usize, usize)) -> Bound<'_, PyArray2<f32>> {
+    distance_transform(size).to_pyarray_bound(py)
+}
+
+/// Computes normalized and clipped distance transform (bwdist) for arbitray polygon,
+/// defined by its Nx2 `corners`, over an image of size (width, height).
+///
+/// To get the blending mask for an image of a given `size` you can:
+///     polygon_distance_transform(Mapping.identity().corners(size), size)
+#[pyfunction]
+#[pyo3(name = "polygon_distance_transform", signature = (corners, size))]
+pub fn polygon_distance_transform_py<'py>(
+    py: Python<'py>,
+    corners: &Bound<'py, PyAny>,
+    size: (usize, usize),
+) -> PyResult<Bound<'py, PyArray2<f32>>> {
+    let corners = pyarray_to_array2_bridge::<f32>(corners)?;
+    Ok(polygon_distance_transform(&corners, size).to_pyarray_bound(py))
+}
+
+/// Signed distance of every Nx2 point to the polygon defined by Nx2 `vertices`,
+/// distances are exact (computed analytically) and negative inside of the polygon.
+#[pyfunction]
+#[pyo3(name = "polygon_sdf", signature = (points, vertices))]
+pub fn polygon_sdf_py<'py>(
+    py: Python<'py>,
+    points: &Bound<'py, PyAny>,
+    vertices: &Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyArray1<f32>>> {
+    let points = pyarray_to_array2_bridge::<f32>(points)?;
+    let vertices = pyarray_to_array2_bridge::<f32>(vertices)?;
+    Ok(polygon_sdf(&points, &vertices).to_pyarray_bound(py))
+}
+
+/// Merge frames using simple linear blending.
+///
+/// Optional `weights` and `valid` masks are lists of HxW arrays that modulate the feathering
+/// weight of each frame. Either a single mask, shared across all frames, or one per frame is expected.
+///
+/// If size (height, width) is specified, that will be used as the canvas size,
+/// otherwise, find smallest canvas size that fits all warps.
+#[pyfunction]
+#[pyo3(
+    name = "merge_arrays",
+    signature = (mappings, frames, weights=None, valid=None, size=None, message=None)
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn merge_arrays_py<'py>(
+    py: Python<'py>,
+    mappings: Vec<Mapping>,
+    frames: Vec<Bound<'py, PyAny>>,
+    weights: Option<Vec<Bound<'py, PyAny>>>,
+    valid: Option<Vec<Bound<'py, PyAny>>>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> PyResult<Bound<'py, PyArray3<f32>>> {
+    let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+    let frames: Vec<Array3<f32>> = frames
+        .iter()
+        .map(pyarray_to_im_bridge::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let weights = pymasks_to_masks_bridge::<f32>(weights)?;
+    let valid = pymasks_to_masks_bridge::<bool>(valid)?;
+    let merged = merge_arrays(
+        &mappings,
+        &frames[..],
+        weights.as_deref(),
+        valid.as_deref(),
+        size,
+        message,
+    )?;
+    Ok(merged.to_pyarray_bound(py))
+}
+
+/// Merge 8-bit images using simple linear blending, the result is clamped back to 8-bits.
+/// Frames of any other dtype are cast to uint8 first.
+///
+/// Note: Unlike `merge_arrays`, size is expected to be (width, height), as with images.
+/// See `merge_arrays` for more.
+#[pyfunction]
+#[pyo3(
+    name = "merge_images",
+    signature = (mappings, frames, weights=None, valid=None, size=None, message=None)
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn merge_images_py<'py>(
+    py: Python<'py>,
+    mappings: Vec<Mapping>,
+    frames: Vec<Bound<'py, PyAny>>,
+    weights: Option<Vec<Bound<'py, PyAny>>>,
+    valid: Option<Vec<Bound<'py, PyAny>>>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> PyResult<Bound<'py, PyArray3<u8>>> {
+    let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+    let frames: Vec<Array3<f32>> = frames
+        .iter()
+        .map(|f| pyarray_to_im_bridge::<u8>(f).map(|f| f.mapv(f32::from)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let weights = pymasks_to_masks_bridge::<f32>(weights)?;
+    let valid = pymasks_to_masks_bridge::<bool>(valid)?;
+    let merged = merge_arrays(
+        &mappings,
+        &frames[..],
+        weights.as_deref(),
+        valid.as_deref(),
+        size.map(|(w, h)| (h, w)),
+        message,
+    )?;
+    Ok(merged.mapv(<u8 as Clamp<f32>>::clamp).to_pyarray_bound(py))
+}
+
+/// Merge frames using simple linear blending and return the merged canvas along with
+/// auxiliary per-pixel layers, as a dictionary with the following keys:
+///     - "weight": Total blending weight.
+///     - "count": Number of frames contributing to each pixel.
+///     - "time_mean", "time_min", "time_max": Mean and range of contributing frame indices.
+///
+/// Optional `weights` and `valid` masks are lists of HxW arrays that modulate the feathering
+/// weight of each frame. Either a single mask, shared across all frames, or one per frame is expected.
+///
+/// If size (height, width) is specified, that will be used as the canvas size,
+/// otherwise, find smallest canvas size that fits all warps.
+#[pyfunction]
+#[pyo3(
+    name = "merge_arrays_with_layers",
+    signature = (mappings, frames, weights=None, valid=None, size=None, message=None)
+)]
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+pub fn merge_arrays_with_layers_py<'py>(
+    py: Python<'py>,
+    mappings: Vec<Mapping>,
+    frames: Vec<Bound<'py, PyAny>>,
+    weights: Option<Vec<Bound<'py, PyAny>>>,
+    valid: Option<Vec<Bound<'py, PyAny>>>,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> PyResult<(Bound<'py, PyArray3<f32>>, Bound<'py, PyDict>)> {
+    let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+    let frames: Vec<Array3<f32>> = frames
+        .iter()
+        .map(pyarray_to_im_bridge::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let weights = pymasks_to_masks_bridge::<f32>(weights)?;
+    let valid = pymasks_to_masks_bridge::<bool>(valid)?;
+    let (merged, layers) = merge_arrays_with_layers(
+        &mappings,
+        &frames[..],
+        weights.as_deref(),
+        valid.as_deref(),
+        size,
+        message,
+    )?;
+    Ok((merged.to_pyarray_bound(py), layers.to_pydict(py)?))
+}
+
+/// Merge frames by drizzling them onto a canvas that is upscaled by `scale`, with drops that
+/// are `pixfrac` times the size of an input pixel. See `drizzle_arrays` in the rust docs for more.
+///
+/// If size (height, width) is specified, that will be used as the (upscaled) canvas size,
+/// otherwise, find smallest canvas size that fits all warps.
+#[pyfunction]
+#[pyo3(
+    name = "drizzle_arrays",
+    signature = (mappings, frames, weights=None, valid=None, scale=2.0, pixfrac=1.0, size=None, message=None)
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn drizzle_arrays_py<'py>(
+    py: Python<'py>,
+    mappings: Vec<Mapping>,
+    frames: Vec<Bound<'py, PyAny>>,
+    weights: Option<Vec<Bound<'py, PyAny>>>,
+    valid: Option<Vec<Bound<'py, PyAny>>>,
+    scale: f32,
+    pixfrac: f32,
+    size: Option<(usize, usize)>,
+    message: Option<&str>,
+) -> PyResult<Bound<'py, PyArray3<f32>>> {
+    let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+    let frames: Vec<Array3<f32>> = frames
+        .iter()
+        .map(pyarray_to_im_bridge::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let weights = pymasks_to_masks_bridge::<f32>(weights)?;
+    let valid = pymasks_to_masks_bridge::<bool>(valid)?;
+    let merged = drizzle_arrays(
+        &mappings,
+        &frames[..],
+        weights.as_deref(),
+        valid.as_deref(),
+        scale,
+        pixfrac,
+        size,
+        message,
+    )?;
+    Ok(merged.to_pyarray_bound(py))
+}
+
+/// Accumulate detection counts of binary frames, each observed over `num_trials` bitplanes, and estimate
+/// the per-pixel flux as `-ln(1-p)` if `invert_response` is set, otherwise return the detection probability.
+/// An optional `dark_count` rate (per-bitplane probability) is subtracted from the flux estimate.
+/// Returns a tuple of the estimate and the number of trials per canvas pixel.
+///
+/// If size (height, width) is specified, that will be used as the canvas size,
+/// otherwise, find smallest canvas size that fits all warps.
+#[pyfunction]
+#[pyo3(
+    name = "merge_photon_counts",
+    signature = (mappings, frames, num_trials, valid=None, size=None, invert_response=true, dark_count=None, message=None)
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn merge_photon_counts_py<'py>(
+    py: Python<'py>,
+    mappings: Vec<Mapping>,
+    frames: Vec<Bound<'py, PyAny>>,
+    num_trials: f32,
+    valid: Option<Vec<Bound<'py, PyAny>>>,
+    size: Option<(usize, usize)>,
+    invert_response: bool,
+    dark_count: Option<f32>,
+    message: Option<&str>,
+) -> PyResult<(Bound<'py, PyArray3<f32>>, Bound<'py, PyArray2<f32>>)> {
+    let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+    let frames: Vec<Array3<f32>> = frames
+        .iter()
+        .map(pyarray_to_im_bridge::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let valid = pymasks_to_masks_bridge::<bool>(valid)?;
+    let counts = merge_photon_counts(
+        &mappings,
+        &frames[..],
+        num_trials,
+        valid.as_deref(),
+        size,
+        message,
+    )?;
+    let estimate = if invert_response {
+        counts.flux(dark_count)
+    } else {
+        counts.probability()
+    };
+    Ok((
+        estimate.to_pyarray_bound(py),
+        counts.trials.to_pyarray_bound(py),
+    ))
+}
+
+/// Estimate per-frame gains that minimize intensity differences between overlapping frames.
+/// Returns an array of shape (num_frames, c) if `per_channel`, otherwise (num_frames, 1), which
+/// frames should be multiplied by before blending. See `gain_compensation` in the rust docs for more.
+#[pyfunction]
+#[pyo3(
+    name = "gain_compensation",
+    signature = (mappings, frames, per_channel=false, sigma_n=0.1, sigma_g=0.1, max_offset=None, message=None)
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn gain_compensation_py<'py>(
+    py: Python<'py>,
+    mappings: Vec<Mapping>,
+    frames: Vec<Bound<'py, PyAny>>,
+    per_channel: bool,
+    sigma_n: f32,
+    sigma_g: f32,
+    max_offset: Option<usize>,
+    message: Option<&str>,
+) -> PyResult<Bound<'py, PyArray2<f32>>> {
+    let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+    let frames: Vec<Array3<f32>> = frames
+        .iter()
+        .map(pyarray_to_im_bridge::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let gains = gain_compensation(
+        &mappings,
+        &frames[..],
+        per_channel,
+        Some(sigma_n),
+        Some(sigma_g),
+        max_offset,
+        message,
+    )?;
+    Ok(gains.to_pyarray_bound(py))
+}
+
+// ----------------------------------------------------------------------------
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test_blend {
+    use approx::assert_relative_eq;
+    use ndarray::{array, Array2, Array3, Axis};
+
+    use crate::{
+        blend::{
+            distance_transform, euclidean_distance_transform, linear_to_srgb,
+            merge_arrays_with_layers, polygon_distance_transform, polygon_sdf, rasterize_polygon,
+            splat_bitplanes, srgb_to_linear, Mosaic,
+        },
+        warps::Mapping,
+    };
+
+    #[test]
+    fn test_euclidean_distance_transform() {
+        let mask = Array2::from_shape_fn((17, 23), |(i, j)| (i * 7 + j * 3) % 11 != 0);
+        let dist = euclidean_distance_transform(&mask);
+
+        // Compare against brute force search of nearest background pixel
+        for ((i, j), d) in dist.indexed_iter() {
+            let expected = mask
+                .indexed_iter()
+                .filter(|(_, v)| !**v)
+                .map(|((k, l), _)| {
+                    ((i as f32 - k as f32).powi(2) + (j as f32 - l as f32).powi(2)).sqrt()
+                })
+                .fold(f32::INFINITY, |a, b| a.min(b));
+            assert_relative_eq!(*d, expected);
+        }
+
+        let dist = euclidean_distance_transform(&array![[true, true], [true, true]]);
+        assert!(dist.iter().all(|v| v.is_infinite()));
+    }
+
+    #[test]
+    fn test_polygon_sdf() {
+        let vertices = array![[0.0, 0.0], [4.0, 0.0], [4.0, 3.0], [0.0, 3.0]];
+        let points = array![
+            [1.0, 1.0],
+            [2.0, 1.5],
+            [3.5, 2.0],
+            [-3.0, -4.0],
+            [6.0, 1.0],
+            [1000.0, 1.5],
+            [4.0, 2.0]
+        ];
+        assert_relative_eq!(
+            polygon_sdf(&points, &vertices),
+            array![-1.0, -1.5, -0.5, 5.0, 2.0, 996.0, 0.0],
+            epsilon = 1e-4
+        );
+    }
+
+    #[test]
+    fn test_rasterize_polygon() {
+        // Triangle with vertices on pixel centers, covering the lower left half of a 4x4 image
+        let corners = array![[0.0, 0.0], [0.0, 4.0], [4.0, 4.0]];
+        assert_eq!(
+            rasterize_polygon(&corners, (4, 4)),
+            array![
+                [false, false, false, false],
+                [true, false, false, false],
+                [true, true, false, false],
+                [true, true, true, false]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_polygon_distance_transform() {
+        let size = (64, 48);
+        let corners = Mapping::identity().corners(size);
+        let expected = Array2::from_shape_fn((48, 64), |(i, j)| {
+            let (x, y) = (j as f32, i as f32);
+            x.min(y).min(64.0 - x).min(48.0 - y) / 24.0
+        });
+        assert_relative_eq!(
+            polygon_distance_transform(&corners, size),
+            expected,
+            epsilon = 0.05
+        );
+        assert_relative_eq!(distance_transform(size), expected, epsilon = 0.05);
+    }
+
+    #[test]
+    fn test_splat_bitplanes() {
+        // Bitpacked cube of shape (4, 2, 8), every pixel's detections follow its column's bits
+        let bitplanes = Array3::from_shape_fn((4, 2, 1), |(t, _, _)| [0xff, 0xf0, 0x0f, 0x00][t]);
+        let mappings = vec![Mapping::identity(); 4];
+        let counts =
+            splat_bitplanes(&mappings, &bitplanes, true, 1.0, None, Some((2, 8)), None).unwrap();
+
+        assert_relative_eq!(counts.trials, Array2::from_elem((2, 8), 4.0));
+        assert_relative_eq!(
+            counts.probability().index_axis_move(Axis(2), 0),
+            Array2::from_elem((2, 8), 0.5)
+        );
+    }
+
+    #[test]
+    fn test_srgb_roundtrip() {
+        for v in [0.0, 0.001, 0.04, 0.2, 0.5, 1.0] {
+            assert_relative_eq!(srgb_to_linear(linear_to_srgb(v)), v, epsilon = 1e-5);
+        }
+        assert_relative_eq!(linear_to_srgb(0.5), 0.7354, epsilon = 1e-4);
+    }
+
+    #[test]
+    fn test_merge_layers_many_channels() {
+        // Layers are warped separately, such that RGBW frames can be merged with layers
+        let frames: Vec<_> = (0..2)
+            .map(|i| Array3::from_shape_fn((8, 10, 4), |(y, x, c)| (x + y + c + i) as f32))
+            .collect();
+        let maps = [Mapping::identity(), Mapping::shift(2.0, 0.0)];
+        let (merged, layers) =
+            merge_arrays_with_layers(&maps, &frames, None, None, None, None).unwrap();
+        assert_eq!(merged.dim().2, 4);
+        assert_eq!(layers.count.dim(), (merged.dim().0, merged.dim().1));
+        assert!(layers.count.iter().any(|n| *n == 2.0));
+        assert!(layers.time_max.iter().all(|t| *t == 0.0 || *t == 1.0));
+    }
+
+    #[test]
+    fn test_mosaic() {
+        let scene = |dx: usize, dy: usize| {
+            Array3::from_shape_fn((12, 16, 1), |(y, x, _)| (x + dx + 2 * (y + dy)) as f32)
+        };
+        let mut mosaic = Mosaic::new(1);
+        mosaic.add(&scene(0, 0), &Mapping::identity());
+        assert_eq!(mosaic.size(), (12, 16));
+
+        // Frames shifted by whole pixels overlap exactly, and the canvas grows to fit them
+        mosaic.add(&scene(4, 2), &Mapping::shift(-4.0, -2.0));
+        assert_eq!(mosaic.size(), (14, 20));
+
+        let (view, coverage) = mosaic.viewport(&Mapping::shift(-4.0, -2.0), (16, 12));
+        assert!(coverage.iter().all(|c| *c == 1.0));
+        assert_relative_eq!(view, scene(4, 2), epsilon = 1e-4);
+
+        let (_, coverage) = mosaic.viewport(&Mapping::shift(-30.0, 0.0), (16, 12));
+        assert!(coverage.iter().all(|c| *c == 0.0));
+    }
+}
@@ -0,0 +1,673 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use ndarray::{s, Array1, Array2, Array3, ArrayBase, Ix3, RawData, Zip};
+
+use crate::warps::{Mapping, TransformationType};
+
+/// Number of points along each axis of the grid used to measure the residual of an edge.
+const GRID_SIZE: usize = 3;
+
+/// Edges between frames further apart than this are kept outside of the band of the normal equations.
+const MAX_BAND_SPAN: usize = 8;
+
+/// Maximum number of conjugate gradient iterations used to solve the normal equations.
+const MAX_CG_ITERATIONS: usize = 500;
+
+/// Size of the thumbnails used as global descriptors for loop closure detection.
+const THUMBNAIL_SIZE: usize = 16;
+
+/// An edge of the pose graph, i.e: a registration between frames `i` and `j` such that
+/// the absolute mapping of frame `j` should be the absolute mapping of frame `i` composed with
+/// `mapping`, in the same way as `Mapping::accumulate` composes pairwise mappings.
+#[derive(Debug, Clone)]
+pub struct PoseEdge {
+    pub i: usize,
+    pub j: usize,
+    pub mapping: Mapping,
+    /// Confidence of the registration in [0, 1], used to weight the edge. See `registration_confidence`.
+    pub confidence: f32,
+}
+
+/// Pose graph over frames of size (width, height), where nodes are absolute mappings and edges are
+/// pairwise registrations between any two frames. The first pose is held fixed during optimization.
+#[derive(Debug, Clone)]
+pub struct PoseGraph {
+    pub poses: Vec<Mapping>,
+    pub edges: Vec<PoseEdge>,
+    pub size: (usize, usize),
+}
+
+impl PoseGraph {
+    /// Create a pose graph whose poses are the accumulated pairwise mappings, without any edges.
+    pub fn from_pairwise(pairwise: &[Mapping], size: (usize, usize)) -> Self {
+        Self {
+            poses: Mapping::accumulate(pairwise.to_vec()),
+            edges: vec![],
+            size,
+        }
+    }
+
+    /// Pairwise mappings between consecutive poses, i.e: the inverse of `Mapping::accumulate`.
+    pub fn pairwise(&self) -> Vec<Mapping> {
+        self.poses
+            .windows(2)
+            .map(|w| {
+                let mut mapping = w[0].inverse().transform(None, Some(w[1].clone()));
+                mapping.kind = w[1].kind;
+                mapping
+            })
+            .collect()
+    }
+
+    /// Jointly optimize all poses (except the first one) such that they agree with the edges of the
+    /// graph, using Gauss-Newton iterations. Every edge is weighted by its confidence, and by a Huber
+    /// weight on its RMS residual (in pixels) which limits the influence of wrong registrations.
+    /// The residual of an edge is the distance between a grid of points of frame `j` as mapped by both
+    /// sides of the edge's constraint. Poses are restricted to mappings of the given `kind`.
+    ///
+    /// The normal equations are banded when edges only connect nearby frames, and are solved with
+    /// a banded Cholesky factorization. Edges between distant frames, such as loop closures, are kept
+    /// as sparse blocks outside of the band, in which case the equations are solved with conjugate
+    /// gradients preconditioned by the banded part. Returns the final RMS residual over all edges.
+    pub fn optimize(
+        &mut self,
+        kind: TransformationType,
+        iterations: u32,
+        huber_delta: f32,
+    ) -> Result<f32> {
+        let basis = kind_basis(kind)?;
+        let k = basis.ncols();
+        let num_poses = self.poses.len();
+        if k == 0 || num_poses < 2 || self.edges.is_empty() {
+            return Ok(0.0);
+        }
+
+        // Parameters of every pose, restricted to the given kind
+        let mut params: Vec<Array1<f64>> = self
+            .poses
+            .iter()
+            .map(|m| {
+                Array1::from_iter(
+                    Mapping::from_matrix(m.mat.clone(), kind)
+                        .get_params()
+                        .into_iter()
+                        .map(f64::from),
+                )
+            })
+            .collect();
+
+        // Bandwidth of the normal equations (excluding the fixed first pose)
+        let span = self
+            .edges
+            .iter()
+            .map(|e| e.i.abs_diff(e.j))
+            .filter(|span| *span <= MAX_BAND_SPAN)
+            .max()
+            .unwrap_or(1);
+        let bandwidth = (span + 1) * k - 1;
+        let dim = (num_poses - 1) * k;
+        let grid = self.grid();
+        let mut rms = 0.0;
+
+        for _ in 0..iterations {
+            let fulls: Vec<Array1<f64>> = params.iter().map(|p| basis.dot(p)).collect();
+            let mut band = Array2::<f64>::zeros((dim, bandwidth + 1));
+            let mut off_band: HashMap<(usize, usize), Array2<f64>> = HashMap::new();
+            let mut rhs = Array1::<f64>::zeros(dim);
+            let (mut total_sq, mut total_num) = (0.0, 0);
+
+            for edge in self.edges.iter() {
+                if edge.confidence <= 0.0 || edge.i == edge.j {
+                    continue;
+                }
+                let pose_j = homography(&fulls[edge.j]);
+                let Some(pose_j_inv) = invert3(&pose_j) else {
+                    continue;
+                };
+                let mapping = edge.mapping.mat.mapv(f64::from);
+
+                // Residuals and jacobians (w.r.t the full parameters) of both poses at every point
+                let terms: Vec<_> = grid
+                    .iter()
+                    .map(|&(gx, gy)| {
+                        let (x, y) = apply3(&pose_j_inv, gx, gy);
+                        let (zx, zy) = apply3(&mapping, x, y);
+                        let (uj, vj, jac_j) = warp_jacobian(&fulls[edge.j], x, y);
+                        let (ui, vi, jac_i) = warp_jacobian(&fulls[edge.i], zx, zy);
+                        ([uj - ui, vj - vi], jac_i.dot(&basis), jac_j.dot(&basis))
+                    })
+                    .collect();
+
+                let sq = terms
+                    .iter()
+                    .map(|(r, _, _)| r[0] * r[0] + r[1] * r[1])
+                    .sum::<f64>();
+                total_sq += sq;
+                total_num += terms.len();
+
+                let edge_rms = (sq / terms.len() as f64).sqrt();
+                let huber = if edge_rms <= huber_delta as f64 {
+                    1.0
+                } else {
+                    huber_delta as f64 / edge_rms
+                };
+                let weight = edge.confidence as f64 * huber;
+
+                for (r, jac_i, jac_j) in terms.iter() {
+                    let r = Array1::from_vec(r.to_vec());
+                    // Residual is pose_j - pose_i, so the jacobian of pose_i is negated
+                    let blocks = [(edge.i, -jac_i), (edge.j, jac_j.clone())];
+                    for (a, jac_a) in blocks.iter() {
+                        if *a == 0 {
+                            continue;
+                        }
+                        let ra = (a - 1) * k;
+                        rhs.slice_mut(s![ra..ra + k])
+                            .scaled_add(-weight, &jac_a.t().dot(&r));
+
+                        for (b, jac_b) in blocks.iter() {
+                            if *b == 0 || b < a {
+                                continue;
+                            }
+                            let rb = (b - 1) * k;
+                            let block = jac_a.t().dot(jac_b) * weight;
+                            if b - a > span {
+                                *off_band
+                                    .entry((a - 1, b - 1))
+                                    .or_insert_with(|| Array2::zeros((k, k))) += &block;
+                                continue;
+                            }
+                            for p in 0..k {
+                                for q in 0..k {
+                                    let (row, col) = (ra + p, rb + q);
+                                    if row <= col && col - row <= bandwidth {
+                                        band[[row, col - row]] += block[[p, q]];
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            rms = (total_sq / total_num.max(1) as f64).sqrt() as f32;
+
+            // Small damping keeps poses without any (confident) edges well defined
+            let damping = 1e-9 * band.column(0).fold(1.0f64, |a, b| a.max(*b));
+            band.column_mut(0).mapv_inplace(|v| v + damping);
+
+            let lower = cholesky_banded(&band)?;
+            let step = if off_band.is_empty() {
+                solve_cholesky_banded(&lower, &rhs)
+            } else {
+                conjugate_gradient(&band, &off_band, &lower, &rhs)
+            };
+            for (i, p) in params.iter_mut().enumerate().skip(1) {
+                *p += &step.slice(s![(i - 1) * k..i * k]);
+            }
+            if step.iter().all(|v| v.abs() < 1e-7) {
+                break;
+            }
+        }
+
+        self.poses = params
+            .iter()
+            .map(|p| Mapping::from_params(p.iter().map(|v| *v as f32).collect()))
+            .collect();
+        Ok(rms)
+    }
+
+    /// Overlap between the footprints of frames `i` and `j` in reference coordinates, as predicted by
+    /// their current poses. This is the intersection over union of the footprints' bounding boxes.
+    pub fn predicted_overlap(&self, i: usize, j: usize) -> f32 {
+        let (min_i, max_i) = self.poses[i].extent(self.size);
+        let (min_j, max_j) = self.poses[j].extent(self.size);
+        let area = |min: &Array1<f32>, max: &Array1<f32>| {
+            (max[0] - min[0]).max(0.0) * (max[1] - min[1]).max(0.0)
+        };
+        let intersection = area(
+            &Zip::from(&min_i).and(&min_j).map_collect(|a, b| a.max(*b)),
+            &Zip::from(&max_i).and(&max_j).map_collect(|a, b| a.min(*b)),
+        );
+        let union = area(&min_i, &max_i) + area(&min_j, &max_j) - intersection;
+        if union > 0.0 {
+            intersection / union
+        } else {
+            0.0
+        }
+    }
+
+    /// Find loop closure candidates, i.e: pairs of frames at least `min_gap` apart which likely see the
+    /// same part of the scene, but are not yet connected by an edge. A pair is a candidate if either its
+    /// predicted overlap or the similarity of its global descriptors (see `global_descriptor`) is above
+    /// `min_score`. Only the best scoring candidate of every frame is kept.
+    pub fn loop_candidates(
+        &self,
+        descriptors: &[Array1<f32>],
+        min_gap: usize,
+        min_score: f32,
+    ) -> Vec<(usize, usize)> {
+        let connected: HashSet<(usize, usize)> = self
+            .edges
+            .iter()
+            .map(|e| (e.i.min(e.j), e.i.max(e.j)))
+            .collect();
+
+        (0..self.poses.len())
+            .filter_map(|i| {
+                ((i + min_gap)..self.poses.len())
+                    .filter(|j| !connected.contains(&(i, *j)))
+                    .map(|j| {
+                        let similarity = descriptors[i].dot(&descriptors[j]);
+                        (j, self.predicted_overlap(i, j).max(similarity))
+                    })
+                    .filter(|(_, score)| *score >= min_score)
+                    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(j, _)| (i, j))
+            })
+            .collect()
+    }
+
+    /// Grid of points spanning a frame, including its corners and center.
+    fn grid(&self) -> Vec<(f64, f64)> {
+        let (w, h) = self.size;
+        let ticks = |len: usize| {
+            (0..GRID_SIZE)
+                .map(move |i| i as f64 * (len as f64 - 1.0).max(0.0) / (GRID_SIZE - 1) as f64)
+        };
+        ticks(h)
+            .flat_map(|y| ticks(w).map(move |x| (x, y)))
+            .collect()
+    }
+}
+
+/// Linear map from the parameters of a transformation type to the full (projective) parameters,
+/// see `Mapping::from_params` and `Mapping::get_params_full` for their order.
+fn kind_basis(kind: TransformationType) -> Result<Array2<f64>> {
+    let mut basis = Array2::zeros((8, kind.num_params()));
+    match kind {
+        TransformationType::Identity => (),
+        TransformationType::Translational => {
+            basis[[4, 0]] = 1.0;
+            basis[[5, 1]] = 1.0;
+        }
+        TransformationType::Homothety => {
+            basis[[4, 0]] = 1.0;
+            basis[[5, 1]] = 1.0;
+            basis[[0, 2]] = 1.0;
+            basis[[3, 2]] = 1.0;
+        }
+        TransformationType::Similarity => {
+            basis[[4, 0]] = 1.0;
+            basis[[5, 1]] = 1.0;
+            basis[[0, 2]] = 1.0;
+            basis[[3, 2]] = 1.0;
+            basis[[2, 3]] = -1.0;
+            basis[[1, 3]] = 1.0;
+        }
+        TransformationType::Affine | TransformationType::Projective => {
+            basis.diag_mut().fill(1.0);
+        }
+        TransformationType::Unknown => {
+            return Err(anyhow!("Mapping type {:?} not supported!", kind));
+        }
+    }
+    Ok(basis)
+}
+
+/// Homography matrix from full parameters.
+fn homography(p: &Array1<f64>) -> [[f64; 3]; 3] {
+    [
+        [1.0 + p[0], p[2], p[4]],
+        [p[1], 1.0 + p[3], p[5]],
+        [p[6], p[7], 1.0],
+    ]
+}
+
+/// Apply a 3x3 matrix (anything indexable as such) to a point.
+fn apply3<M: std::ops::Index<[usize; 2], Output = f64>>(m: &M, x: f64, y: f64) -> (f64, f64) {
+    let c = m[[2, 0]] * x + m[[2, 1]] * y + m[[2, 2]];
+    (
+        (m[[0, 0]] * x + m[[0, 1]] * y + m[[0, 2]]) / c,
+        (m[[1, 0]] * x + m[[1, 1]] * y + m[[1, 2]]) / c,
+    )
+}
+
+/// Invert a 3x3 matrix, if it is not singular.
+fn invert3(m: &[[f64; 3]; 3]) -> Option<Array2<f64>> {
+    let cof =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let det = m[0][0] * cof(1, 2, 1, 2) - m[0][1] * cof(1, 2, 0, 2) + m[0][2] * cof(1, 2, 0, 1);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let adj = [
+        [cof(1, 2, 1, 2), -cof(0, 2, 1, 2), cof(0, 1, 1, 2)],
+        [-cof(1, 2, 0, 2), cof(0, 2, 0, 2), -cof(0, 1, 0, 2)],
+        [cof(1, 2, 0, 1), -cof(0, 2, 0, 1), cof(0, 1, 0, 1)],
+    ];
+    Some(Array2::from_shape_fn((3, 3), |(r, c)| adj[r][c] / det))
+}
+
+/// Warp a point with the homography given by full parameters, and return the warped point
+/// along with the 2x8 jacobian of the warped point w.r.t the parameters.
+fn warp_jacobian(p: &Array1<f64>, x: f64, y: f64) -> (f64, f64, Array2<f64>) {
+    let h = homography(p);
+    let c = h[2][0] * x + h[2][1] * y + h[2][2];
+    let u = (h[0][0] * x + h[0][1] * y + h[0][2]) / c;
+    let v = (h[1][0] * x + h[1][1] * y + h[1][2]) / c;
+
+    let mut jac = Array2::zeros((2, 8));
+    jac[[0, 0]] = x / c;
+    jac[[0, 2]] = y / c;
+    jac[[0, 4]] = 1.0 / c;
+    jac[[0, 6]] = -u * x / c;
+    jac[[0, 7]] = -u * y / c;
+    jac[[1, 1]] = x / c;
+    jac[[1, 3]] = y / c;
+    jac[[1, 5]] = 1.0 / c;
+    jac[[1, 6]] = -v * x / c;
+    jac[[1, 7]] = -v * y / c;
+    (u, v, jac)
+}
+
+/// Solve a symmetric positive definite banded system, where `band[[i, d]]` holds the (i, i+d)
+/// entry of the matrix, using a banded Cholesky factorization.
+pub fn solve_banded(band: &Array2<f64>, rhs: &Array1<f64>) -> Result<Array1<f64>> {
+    Ok(solve_cholesky_banded(&cholesky_banded(band)?, rhs))
+}
+
+/// Banded Cholesky factorization of a symmetric positive definite banded matrix, stored as in
+/// `solve_banded`. The lower factor is returned, where `lower[[i, d]]` holds the (i, i-d) entry.
+fn cholesky_banded(band: &Array2<f64>) -> Result<Array2<f64>> {
+    let (n, bw) = (band.nrows(), band.ncols() - 1);
+    let mut lower = Array2::<f64>::zeros((n, bw + 1));
+
+    for i in 0..n {
+        for j in i.saturating_sub(bw)..=i {
+            let mut sum = band[[j, i - j]];
+            for k in i.saturating_sub(bw)..j {
+                sum -= lower[[i, i - k]] * lower[[j, j - k]];
+            }
+            if i == j {
+                if sum <= 0.0 {
+                    return Err(anyhow!("Normal equations are not positive definite."));
+                }
+                lower[[i, 0]] = sum.sqrt();
+            } else {
+                lower[[i, i - j]] = sum / lower[[j, 0]];
+            }
+        }
+    }
+    Ok(lower)
+}
+
+/// Solve a system given the lower factor found by `cholesky_banded`.
+fn solve_cholesky_banded(lower: &Array2<f64>, rhs: &Array1<f64>) -> Array1<f64> {
+    let (n, bw) = (lower.nrows(), lower.ncols() - 1);
+    let mut y = Array1::<f64>::zeros(n);
+    for i in 0..n {
+        let sum: f64 = (i.saturating_sub(bw)..i)
+            .map(|k| lower[[i, i - k]] * y[k])
+            .sum();
+        y[i] = (rhs[i] - sum) / lower[[i, 0]];
+    }
+    let mut x = Array1::<f64>::zeros(n);
+    for i in (0..n).rev() {
+        let sum: f64 = (i + 1..(i + bw + 1).min(n))
+            .map(|k| lower[[k, k - i]] * x[k])
+            .sum();
+        x[i] = (y[i] - sum) / lower[[i, 0]];
+    }
+    x
+}
+
+/// Solve a symmetric positive definite system made of a banded part (stored as in `solve_banded`),
+/// and of sparse blocks above the band indexed by (block row, block column), using conjugate gradients
+/// preconditioned with the Cholesky factor of the banded part.
+fn conjugate_gradient(
+    band: &Array2<f64>,
+    off_band: &HashMap<(usize, usize), Array2<f64>>,
+    lower: &Array2<f64>,
+    rhs: &Array1<f64>,
+) -> Array1<f64> {
+    let n = band.nrows();
+    let matvec = |v: &Array1<f64>| {
+        let mut out = Array1::<f64>::zeros(n);
+        for ((i, d), value) in band.indexed_iter() {
+            if i + d >= n || *value == 0.0 {
+                continue;
+            }
+            out[i] += value * v[i + d];
+            if d > 0 {
+                out[i + d] += value * v[i];
+            }
+        }
+        for ((a, b), block) in off_band.iter() {
+            let k = block.nrows();
+            let (ra, rb) = (a * k, b * k);
+            let va = v.slice(s![ra..ra + k]).to_owned();
+            let vb = v.slice(s![rb..rb + k]).to_owned();
+            out.slice_mut(s![ra..ra + k])
+                .scaled_add(1.0, &block.dot(&vb));
+            out.slice_mut(s![rb..rb + k])
+                .scaled_add(1.0, &block.t().dot(&va));
+        }
+        out
+    };
+
+    let mut x = Array1::<f64>::zeros(n);
+    let mut r = rhs.clone();
+    let mut z = solve_cholesky_banded(lower, &r);
+    let mut p = z.clone();
+    let mut rz = r.dot(&z);
+    let tolerance = 1e-10 * rhs.dot(rhs).sqrt();
+
+    for _ in 0..n.min(MAX_CG_ITERATIONS) {
+        let ap = matvec(&p);
+        let alpha = rz / p.dot(&ap);
+        x.scaled_add(alpha, &p);
+        r.scaled_add(-alpha, &ap);
+        if r.dot(&r).sqrt() <= tolerance {
+            break;
+        }
+        z = solve_cholesky_banded(lower, &r);
+        let rz_next = r.dot(&z);
+        p = &z + &(&p * (rz_next / rz));
+        rz = rz_next;
+    }
+    x
+}
+
+/// Global descriptor of a (h, w, c) frame used to detect loop closures. This is a thumbnail of the
+/// frame's intensity averaged over channels, normalized to zero mean and unit norm.
+pub fn global_descriptor<S>(frame: &ArrayBase<S, Ix3>) -> Array1<f32>
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    let (h, w, _) = frame.dim();
+    let mut thumbnail = Array1::<f32>::zeros(THUMBNAIL_SIZE * THUMBNAIL_SIZE);
+    let mut counts = Array1::<f32>::zeros(THUMBNAIL_SIZE * THUMBNAIL_SIZE);
+
+    for ((y, x, _), v) in frame.indexed_iter() {
+        let idx = (y * THUMBNAIL_SIZE / h) * THUMBNAIL_SIZE + x * THUMBNAIL_SIZE / w;
+        thumbnail[idx] += v;
+        counts[idx] += 1.0;
+    }
+    thumbnail /= &counts.mapv(|c| c.max(1.0));
+    thumbnail -= thumbnail.mean().unwrap_or(0.0);
+
+    let norm = thumbnail.dot(&thumbnail).sqrt();
+    if norm > 0.0 {
+        thumbnail /= norm;
+    }
+    thumbnail
+}
+
+/// Confidence of a registration between two (h, w, c) frames, as found by `iclk`, computed as the
+/// zero-normalized cross-correlation of the overlapping pixels, clipped to [0, 1], and scaled by the
+/// fraction of pixels that overlap.
+pub fn registration_confidence<S>(
+    im1: &ArrayBase<S, Ix3>,
+    im2: &ArrayBase<S, Ix3>,
+    mapping: &Mapping,
+) -> f32
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    let (h, w, _) = im2.dim();
+    let (warped, valid): (Array3<f32>, _) = mapping.inverse().warp_array3(im1, (h, w), None);
+
+    let pairs: Vec<(f32, f32)> = warped
+        .indexed_iter()
+        .filter(|((y, x, _), _)| valid[[*y, *x]])
+        .map(|((y, x, c), v)| (*v, im2[[y, x, c]]))
+        .collect();
+    if pairs.len() < 2 {
+        return 0.0;
+    }
+
+    let n = pairs.len() as f32;
+    let (mean1, mean2) = pairs
+        .iter()
+        .fold((0.0, 0.0), |(a, b), (v1, v2)| (a + v1 / n, b + v2 / n));
+    let (cov, var1, var2) = pairs.iter().fold((0.0, 0.0, 0.0), |(c, a, b), (v1, v2)| {
+        let (d1, d2) = (v1 - mean1, v2 - mean2);
+        (c + d1 * d2, a + d1 * d1, b + d2 * d2)
+    });
+    if var1 <= 0.0 || var2 <= 0.0 {
+        return 0.0;
+    }
+    let overlap = valid.iter().filter(|v| **v).count() as f32 / (h * w) as f32;
+    (cov / (var1 * var2).sqrt()).clamp(0.0, 1.0) * overlap
+}
+
+#[cfg(test)]
+mod test_bundle {
+    use approx::assert_relative_eq;
+    use ndarray::{array, Array1, Array2, Array3};
+    use ndarray_linalg::solve::Solve;
+
+    use crate::{
+        bundle::{global_descriptor, solve_banded, PoseEdge, PoseGraph},
+        warps::{Mapping, TransformationType},
+    };
+
+    #[test]
+    fn test_solve_banded() {
+        let dense: Array2<f64> = array![
+            [4.0, 1.0, 0.5, 0.0],
+            [1.0, 5.0, 1.0, 0.2],
+            [0.5, 1.0, 6.0, 1.0],
+            [0.0, 0.2, 1.0, 3.0]
+        ];
+        let band = Array2::from_shape_fn(
+            (4, 3),
+            |(i, d)| {
+                if i + d < 4 {
+                    dense[[i, i + d]]
+                } else {
+                    0.0
+                }
+            },
+        );
+        let rhs: Array1<f64> = array![1.0, -2.0, 3.0, 0.5];
+        assert_relative_eq!(
+            solve_banded(&band, &rhs).unwrap(),
+            dense.solve(&rhs).unwrap(),
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_pose_graph_drift() {
+        // Every pairwise measurement is biased, while the longer edges are exact
+        let num_poses = 12;
+        let truth: Vec<_> = (0..num_poses)
+            .map(|i| Mapping::shift(3.0 * i as f32, -1.0 * i as f32))
+            .collect();
+        let pairwise = vec![Mapping::shift(3.2, -0.9); num_poses - 1];
+
+        let mut graph = PoseGraph::from_pairwise(&pairwise, (64, 48));
+        graph.edges = pairwise
+            .iter()
+            .enumerate()
+            .map(|(i, m)| PoseEdge {
+                i,
+                j: i + 1,
+                mapping: m.clone(),
+                confidence: 1.0,
+            })
+            .chain((0..num_poses - 3).map(|i| PoseEdge {
+                i,
+                j: i + 3,
+                mapping: Mapping::shift(9.0, -3.0),
+                confidence: 1.0,
+            }))
+            .collect();
+
+        let drift = |poses: &[Mapping]| {
+            let last = &poses[num_poses - 1].mat - &truth[num_poses - 1].mat;
+            last.mapv(f32::abs).sum()
+        };
+        let before = drift(&graph.poses);
+        graph
+            .optimize(TransformationType::Translational, 10, 10.0)
+            .unwrap();
+        assert!(drift(&graph.poses) < before / 2.0);
+        assert_eq!(graph.pairwise().len(), num_poses - 1);
+    }
+
+    #[test]
+    fn test_loop_closure() {
+        // Pan right then back left, with a biased return such that the last frame drifted away
+        // from the first one, even though they see the same part of the scene.
+        let num_poses = 20;
+        let pairwise: Vec<_> = (0..num_poses - 1)
+            .map(|i| {
+                if i < num_poses / 2 {
+                    Mapping::shift(4.0, 0.0)
+                } else {
+                    Mapping::shift(-3.0, 0.0)
+                }
+            })
+            .collect();
+
+        let mut graph = PoseGraph::from_pairwise(&pairwise, (64, 48));
+        graph.edges = pairwise
+            .iter()
+            .enumerate()
+            .map(|(i, m)| PoseEdge {
+                i,
+                j: i + 1,
+                mapping: m.clone(),
+                confidence: 1.0,
+            })
+            .collect();
+
+        // Frames are identical at both ends of the sweep, and all different in between
+        let descriptors: Vec<_> = (0..num_poses)
+            .map(|i| {
+                let i = if i == num_poses - 1 { 0 } else { i };
+                global_descriptor(&Array3::from_shape_fn((48, 64, 1), |(y, x, _)| {
+                    ((x * 7 + y * 3 + i * 11) % 23) as f32
+                }))
+            })
+            .collect();
+        let candidates = graph.loop_candidates(&descriptors, 10, 0.99);
+        assert!(candidates.contains(&(0, num_poses - 1)));
+
+        // A verified closure brings the last frame back on top of the first one
+        graph.edges.push(PoseEdge {
+            i: 0,
+            j: num_poses - 1,
+            mapping: Mapping::identity(),
+            confidence: 1.0,
+        });
+        let before = graph.poses[num_poses - 1].mat[[0, 2]].abs();
+        graph
+            .optimize(TransformationType::Translational, 10, 100.0)
+            .unwrap();
+        assert!(graph.poses[num_poses - 1].mat[[0, 2]].abs() < before / 2.0);
+    }
+}
@@ -4,6 +4,13 @@ pub use clap::Parser;
 use clap::{Args, Subcommand};
 use photoncube2video::transforms::Transform;
 
+use crate::{
+    demosaic::DemosaicMethod,
+    lk::{GradientOperator, PixelSelection},
+    pyramid::PyramidFilter,
+    warps::TransformationType,
+};
+
 fn validate_normalized(p: &str) -> Result<f32, String> {
     let value = p.parse::<f32>().map_err(|_| "Invalid value")?;
     if (0.0..=1.0).contains(&value) {
@@ -88,6 +95,48 @@ pub struct LKArgs {
     #[arg(long, default_value_t = 16)]
     pub min_size: usize,
 
+    /// Filter used to downscale images when building the pyramid (only used when `--multi`, or by `pano` for mosaic mode and loop closures)
+    #[arg(long, value_enum, default_value = "box")]
+    pub pyramid_filter: PyramidFilter,
+
+    /// Factor by which images are downscaled at every level of the pyramid (only used when `--multi`, or by `pano` for mosaic mode and loop closures)
+    #[arg(long, default_value_t = 2.0)]
+    pub pyramid_scale: f32,
+
+    /// Operator used to compute image gradients
+    #[arg(long, value_enum, default_value = "prewitt")]
+    pub gradient_operator: GradientOperator,
+
+    /// Standard deviation of the derivative of Gaussian (only used when `--gradient-operator=gaussian`)
+    #[arg(long, default_value_t = 1.0)]
+    pub gradient_sigma: f32,
+
+    /// If set, smooth both images with a Gaussian of this standard deviation before matching, which helps with noisy images
+    #[arg(long, default_value = None)]
+    pub smoothing: Option<f32>,
+
+    /// If set, only use the most informative pixels of the reference image, as selected by this method
+    #[arg(long, value_enum, default_value = None)]
+    pub pixel_selection: Option<PixelSelection>,
+
+    /// Maximum number of pixels used at every level (only used when `--pixel-selection` is set)
+    #[arg(long, default_value_t = 4096)]
+    pub pixel_budget: usize,
+
+    /// Coarse-to-fine schedule of mapping types, from the coarsest level onwards. The last type is used for all
+    /// remaining levels, and always for the finest one, e.g: `--schedule translational similarity projective`
+    /// [default: projective at every level]. Not supported by `pano`, which upgrades mapping types at every level
+    #[arg(long, value_enum, num_args(1..))]
+    pub schedule: Vec<TransformationType>,
+
+    /// Number of LK iterations at every level of the `schedule` [default: `iterations`]
+    #[arg(long, num_args(1..), requires = "schedule")]
+    pub schedule_iterations: Vec<u32>,
+
+    /// Early stopping criterion at every level of the `schedule` [default: `early_stop`]
+    #[arg(long, num_args(1..), requires = "schedule")]
+    pub schedule_early_stop: Vec<f32>,
+
     /// Save parameters of optimization to file
     #[arg(long)]
     pub params_path: Option<String>,
@@ -122,14 +171,31 @@ pub struct PanoArgs {
     #[arg(long, default_value_t = 256)]
     pub burst_size: usize,
 
-    /// If enabled, invert the SPAD's response (Bernoulli process)
+    /// If enabled, invert the SPAD's response (Bernoulli process). The panorama is then merged by accumulating
+    /// detection and trial counts per pixel, and the maximum-likelihood flux estimate is saved instead. Counts are
+    /// read straight from the photoncube, so frames cannot be downscaled, transformed or demosaiced
     #[arg(long, action)]
     pub invert_response: bool,
 
+    /// Dark count rate, as the probability of a spurious detection in a single bitplane, which is
+    /// removed from the flux estimate. Only used with `--invert-response`
+    #[arg(long, default_value = None)]
+    pub dark_count: Option<f32>,
+
     /// If enabled, apply sRGB tonemapping to output
     #[arg(long, action)]
     pub tonemap2srgb: bool,
 
+    /// If enabled, create a color panorama. Photoncubes are demosaiced using `cfa-path`, while image sequences
+    /// and videos are used as is. Matching is still done in grayscale. The output holds linear color as a
+    /// 16-bit image, unless `tonemap2srgb` is set, in which case it is sRGB encoded with 8 bits per channel
+    #[arg(long, action)]
+    pub color: bool,
+
+    /// Demosaicing method used for color panoramas of photoncubes, it is applied to granular frames
+    #[arg(long, value_enum, default_value = "bilinear")]
+    pub demosaic: DemosaicMethod,
+
     /// If enabled, swap columns that are out of order and crop to 254x496
     #[arg(long, action)]
     pub colorspad_fix: bool,
@@ -146,11 +212,99 @@ pub struct PanoArgs {
     #[arg(long, default_value = None)]
     pub baseline_path: Option<PathBuf>,
 
+    /// Path of optional blending mask used when merging panoramas (white areas are kept as valid, black is ignored,
+    /// and intermediate values reduce a pixel's contribution). It should have the same size as the frames before
+    /// any transforms or downscaling are applied
+    #[arg(long, default_value = None)]
+    pub blend_mask: Option<PathBuf>,
+
+    /// If enabled, estimate and apply per-frame gains that equalize the brightness of overlapping frames before blending
+    #[arg(long, action)]
+    pub gain_compensation: bool,
+
+    /// If provided, save the gain of every granular frame to this path as a .npy file (requires `gain-compensation`)
+    #[arg(long, default_value = None)]
+    pub gains_out: Option<PathBuf>,
+
+    /// If provided, save auxiliary panorama layers to this directory as .npy files. These are the total
+    /// blending weight, number of contributing frames, and mean/min/max bitplane index of contributing frames
+    #[arg(long, default_value = None)]
+    pub layers_dir: Option<PathBuf>,
+
+    /// Upscale the final panorama by this factor using drizzle-style super-resolution merging,
+    /// which recovers detail beyond the native resolution by exploiting sub-pixel shifts between frames
+    #[arg(long, default_value_t = 1.0)]
+    pub upscale: f32,
+
+    /// Size of the drizzle drops relative to the input pixel size, only used when upscaling.
+    /// Smaller values are sharper but might leave holes in the panorama if there aren't enough frames
+    #[arg(long, default_value_t = 1.0)]
+    pub pixfrac: f32,
+
     /// Number of consecutive binary frames that will be merged together with identity transform and considered as
     /// new granular unit. This greatly speeds up computations and memory requirements, at the cost of potential motion blur
     #[arg(long, default_value_t = 8, value_parser=non_zero)]
     pub granularity: usize,
 
+    /// If enabled, the final panorama is created by warping every bitplane individually using its interpolated
+    /// mapping, instead of merging granular frames. This avoids any motion blur due to `granularity`, and binary
+    /// values are splatted directly from the photoncube
+    #[arg(long, action)]
+    pub bitplane_exact: bool,
+
+    /// Maximum number of granular frames to load into memory at once. Frames are read from the photoncube
+    /// on demand, so memory usage scales with this value instead of the length of the photoncube
+    #[arg(long, default_value_t = 512, value_parser=non_zero)]
+    pub window: usize,
+
+    /// If provided, save the pairwise mappings of every level to this directory as they are estimated
+    #[arg(long, default_value = None)]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// If enabled, skip levels that were already completed according to the checkpoints in `checkpoint-dir`.
+    /// The configuration must be the same as the one that created the checkpoints
+    #[arg(long, action, requires = "checkpoint_dir")]
+    pub resume: bool,
+
+    /// Level at which to start matching (starting at one for the coarsest level), `init-mappings` are used
+    /// to initialize this level instead of the mappings of the previous level
+    #[arg(long, default_value = None, requires = "init_mappings", conflicts_with = "resume")]
+    pub start_level: Option<u32>,
+
+    /// Path of a JSON file with full resolution pairwise mappings between virtual exposures, used with `start-level`
+    #[arg(long, default_value = None, requires = "start_level")]
+    pub init_mappings: Option<PathBuf>,
+
+    /// If provided, save the estimated mappings to this path, along with the timestamps and frame indices
+    /// of every virtual exposure and granular frame. The format (JSON or NPZ) is inferred from the extension
+    #[arg(long, default_value = None)]
+    pub mappings_out: Option<PathBuf>,
+
+    /// Load mappings previously saved with `mappings-out` and skip matching altogether, only the panorama
+    /// is re-rendered. The photoncube, its slicing (start, end, burst-size, granularity...) and all other
+    /// settings that affect the mappings (levels, LK settings, bundle-adjust...) must be the same
+    #[arg(
+        long,
+        default_value = None,
+        conflicts_with_all = ["resume", "start_level"]
+    )]
+    pub mappings_in: Option<PathBuf>,
+
+    /// If provided, refine the pairwise mappings with a global bundle adjustment after the last level. Every
+    /// pair of virtual exposures up to this many apart is registered, and all mappings are jointly optimized
+    #[arg(long, default_value = None)]
+    pub bundle_adjust: Option<usize>,
+
+    /// If enabled, detect loop closures (i.e: when the camera pans back over the same part of the scene) after the
+    /// last level, and use them as additional constraints when refining mappings such that revisits line up
+    #[arg(long, action)]
+    pub loop_closure: bool,
+
+    /// If enabled, register every virtual exposure to a mosaic of all previous ones at the finest level, instead
+    /// of only to the previous one. This reduces drift for slow pans, but is sequential and thus slower
+    #[arg(long, action)]
+    pub mosaic: bool,
+
     /// Assumes the data is bitpacked along the width dimension, to disable unpacking, pass this flag.
     #[arg(long, action)]
     pub not_bitpacked: bool,
@@ -167,5 +321,7 @@ pub enum Commands {
     LK(LKArgs),
 
     /// Estimate pairwise homographies and compose panorama by interpolating warp to all frames.
+    /// The input can be a photoncube (.npy), a directory or glob of PNG/TIFF frames, or a video file,
+    /// for the latter two, `burst-size` and `granularity` are counted in frames instead of bitplanes.
     Pano(PanoArgs),
 }
@@ -0,0 +1,348 @@
+use std::{borrow::Cow, ops::Range, path::Path};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use image::io::Reader as ImageReader;
+use ndarray::{s, stack, Array2, Array3, ArrayView2, ArrayView3, Axis, Zip};
+use numpy::{PyArray3, ToPyArray};
+use photoncube2video::transforms::image_to_array3;
+use pyo3::prelude::*;
+use strum_macros::Display;
+
+use crate::{blend::pyarray_to_array2_bridge, lk::pyarray_to_im_bridge};
+
+/// Colors used to denote red, green, blue and white filters in color filter array pattern images.
+pub const CFA_COLORS: [[u8; 3]; 4] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+
+/// Largest radius of the interpolation kernel, this bounds the period of the color filter array.
+const MAX_RADIUS: usize = 8;
+
+/// Weight of the gradient correction term, as in Malvar-He-Cutler.
+const EDGE_GAIN: f32 = 0.5;
+
+#[pyclass]
+#[derive(Copy, Clone, Debug, Display, ValueEnum, PartialEq)]
+pub enum DemosaicMethod {
+    Bilinear,    // Normalized convolution of every channel's samples
+    Edge,        //  + gradient correction from the sampled channel (Malvar-He-Cutler style)
+    PhotonNoise, //  + correction shrunk according to the photon noise, which also denoises samples
+}
+
+/// Convert a color coded (h, w, 3) pattern into one mask per channel, in RGB(W) order, see `CFA_COLORS`.
+/// Channels without any pixels are omitted, such that RGB filter arrays result in three channels
+/// and RGBW ones in four. The pattern can either be a single period of the filter array or span
+/// the whole frame, it is tiled as needed.
+pub fn cfa_from_pattern(pattern: ArrayView3<u8>) -> Result<Vec<Array2<bool>>> {
+    if pattern.len_of(Axis(2)) != 3 {
+        return Err(anyhow!(
+            "Color filter array pattern should have three channels."
+        ));
+    }
+    let channels: Vec<_> = CFA_COLORS
+        .iter()
+        .map(|color| Zip::from(pattern.lanes(Axis(2))).map_collect(|px| px.iter().eq(color.iter())))
+        .filter(|mask| mask.iter().any(|v| *v))
+        .collect();
+
+    if channels.len() < 3 {
+        return Err(anyhow!(
+            "Color filter array should contain at least red, green and blue filters."
+        ));
+    }
+    Ok(channels)
+}
+
+/// Load a color filter array pattern image, see `cfa_from_pattern`.
+pub fn load_cfa<P: AsRef<Path>>(path: P) -> Result<Vec<Array2<bool>>> {
+    let pattern = image_to_array3(ImageReader::open(path)?.decode()?.into_rgb8());
+    cfa_from_pattern(pattern.view())
+}
+
+/// Tile color filter array masks to the given size, if needed.
+fn tile_cfa(cfa: &[Array2<bool>], size: (usize, usize)) -> Vec<Cow<'_, Array2<bool>>> {
+    cfa.iter()
+        .map(|mask| {
+            if mask.dim() == size {
+                Cow::Borrowed(mask)
+            } else {
+                let (ph, pw) = mask.dim();
+                Cow::Owned(Array2::from_shape_fn(size, |(i, j)| mask[(i % ph, j % pw)]))
+            }
+        })
+        .collect()
+}
+
+/// Tent kernel of given radius, i.e: [1, 2, ..., r+1, ..., 2, 1].
+fn tent(radius: usize) -> Vec<f32> {
+    (0..=2 * radius)
+        .map(|i| (radius + 1 - i.abs_diff(radius)) as f32)
+        .collect()
+}
+
+/// Destination and source ranges when shifting an axis of length `len` by `offset`.
+fn shifted(len: usize, offset: isize) -> (Range<usize>, Range<usize>) {
+    let lo = (-offset).clamp(0, len as isize) as usize;
+    let hi = (len as isize - offset).clamp(lo as isize, len as isize) as usize;
+    (
+        lo..hi,
+        (lo as isize + offset) as usize..(hi as isize + offset) as usize,
+    )
+}
+
+/// Separable convolution with zero padding, using the same (odd length) kernel along both axes.
+fn convolve(arr: &Array2<f32>, kernel: &[f32]) -> Array2<f32> {
+    let radius = (kernel.len() / 2) as isize;
+    let (h, w) = arr.dim();
+
+    let mut tmp = Array2::zeros((h, w));
+    for (i, k) in kernel.iter().enumerate() {
+        let (dst, src) = shifted(h, i as isize - radius);
+        tmp.slice_mut(s![dst, ..])
+            .scaled_add(*k, &arr.slice(s![src, ..]));
+    }
+    let mut out = Array2::zeros((h, w));
+    for (i, k) in kernel.iter().enumerate() {
+        let (dst, src) = shifted(w, i as isize - radius);
+        out.slice_mut(s![.., dst])
+            .scaled_add(*k, &tmp.slice(s![.., src]));
+    }
+    out
+}
+
+/// Interpolate the samples of `frame` where `mask` is set with a tent kernel, whose radius is
+/// increased until every pixel has at least one sample in its neighborhood. If `exclude_center`
+/// is set, a pixel's own sample is not used for its estimate. Fails if some pixels have no samples
+/// within `MAX_RADIUS`, i.e: if the channel is too sparse.
+fn normalized_convolution(
+    frame: &Array2<f32>,
+    mask: &Array2<bool>,
+    exclude_center: bool,
+) -> Result<Array2<f32>> {
+    let weights = mask.mapv(|v| v as u8 as f32);
+    let samples = &weights * frame;
+    let mut out = Array2::zeros(frame.dim());
+    let mut filled = Array2::from_elem(frame.dim(), false);
+
+    for radius in 1..=MAX_RADIUS {
+        let kernel = tent(radius);
+        let mut num = convolve(&samples, &kernel);
+        let mut den = convolve(&weights, &kernel);
+        if exclude_center {
+            let center = kernel[radius] * kernel[radius];
+            num.scaled_add(-center, &samples);
+            den.scaled_add(-center, &weights);
+        }
+
+        Zip::from(&mut out)
+            .and(&mut filled)
+            .and(&num)
+            .and(&den)
+            .for_each(|o, f, n, d| {
+                if !*f && *d > 1e-6 {
+                    *o = n / d;
+                    *f = true;
+                }
+            });
+        if filled.iter().all(|f| *f) {
+            return Ok(out);
+        }
+    }
+    Err(anyhow!(
+        "Color filter array is too sparse, some pixels have no samples of a channel within {MAX_RADIUS} pixels."
+    ))
+}
+
+/// Difference between every sample and the interpolation of its neighboring samples of the same
+/// channel, i.e: the high frequency content of the sampled channel at every pixel.
+fn detail(frame: &Array2<f32>, cfa: &[Cow<Array2<bool>>]) -> Result<Array2<f32>> {
+    let mut detail = Array2::zeros(frame.dim());
+    for mask in cfa {
+        let neighbors = normalized_convolution(frame, mask, true)?;
+        Zip::from(&mut detail)
+            .and(frame)
+            .and(&neighbors)
+            .and(mask.as_ref())
+            .for_each(|d, f, n, m| {
+                if *m {
+                    *d = f - n
+                }
+            });
+    }
+    Ok(detail)
+}
+
+/// Demosaic a (h, w) frame captured behind a periodic color filter array, given as one mask per
+/// channel (see `cfa_from_pattern`), into a (h, w, c) array. Available methods are:
+///
+/// - `Bilinear`: every channel is interpolated from its samples with a tent kernel, whose size
+///     adapts to the period of the filter array.
+/// - `Edge`: a gradient correction is added to the bilinear estimate, namely the difference between
+///     the sample at every pixel and the interpolation of its same-channel neighbors, as in
+///     Malvar-He-Cutler. This reduces color fringing along edges.
+/// - `PhotonNoise`: the frame is assumed to be the mean of `num_trials` binary bitplanes. It is
+///     transformed such that the noise is approximately unit-variance Gaussian (Anscombe transform),
+///     and the gradient correction is shrunk where it cannot be distinguished from noise (Wiener
+///     shrinkage). Samples themselves are denoised the same way. The result is transformed back
+///     with the algebraic inverse, and clipped to [0, 1].
+pub fn demosaic(
+    frame: ArrayView2<f32>,
+    cfa: &[Array2<bool>],
+    method: DemosaicMethod,
+    num_trials: Option<f32>,
+) -> Result<Array3<f32>> {
+    let cfa = tile_cfa(cfa, frame.dim());
+    let (frame, num_trials) = if method == DemosaicMethod::PhotonNoise {
+        let n = num_trials.ok_or(anyhow!(
+            "The number of trials is required for photon-noise aware demosaicing."
+        ))?;
+        (frame.mapv(|v| 2.0 * (n * v.max(0.0) + 3.0 / 8.0).sqrt()), n)
+    } else {
+        (frame.to_owned(), 1.0)
+    };
+
+    // High frequency content, optionally shrunk by the ratio of signal to total local energy
+    let detail = match method {
+        DemosaicMethod::Bilinear => None,
+        DemosaicMethod::Edge => Some(detail(&frame, &cfa)?),
+        DemosaicMethod::PhotonNoise => {
+            let mut detail = detail(&frame, &cfa)?;
+            let energy = convolve(&detail.mapv(|v| v * v), &[0.2; 5]);
+
+            // Noise variance of the detail, assuming about four neighboring samples
+            let noise = 1.25;
+            Zip::from(&mut detail).and(&energy).for_each(|d, e| {
+                *d *= if *e > 0.0 {
+                    (1.0 - noise / e).max(0.0)
+                } else {
+                    0.0
+                };
+            });
+            Some(detail)
+        }
+    };
+
+    let channels: Vec<_> = cfa
+        .iter()
+        .map(|mask| -> Result<Array2<f32>> {
+            let is_noisy = method == DemosaicMethod::PhotonNoise;
+            let mut channel = normalized_convolution(&frame, mask, is_noisy)?;
+
+            Zip::from(&mut channel)
+                .and(&frame)
+                .and(mask.as_ref())
+                .for_each(|c, f, m| {
+                    if *m && !is_noisy {
+                        *c = *f
+                    }
+                });
+            if let Some(detail) = &detail {
+                Zip::from(&mut channel)
+                    .and(detail)
+                    .and(mask.as_ref())
+                    .for_each(|c, d, m| {
+                        // Denoised samples are the interpolation of their neighbors plus their detail
+                        *c += if *m { *d } else { EDGE_GAIN * d };
+                    });
+            }
+            if is_noisy {
+                channel.mapv_inplace(|y| {
+                    (((y / 2.0).powi(2) - 3.0 / 8.0) / num_trials).clamp(0.0, 1.0)
+                });
+            }
+            Ok(channel)
+        })
+        .collect::<Result<_>>()?;
+
+    let views: Vec<_> = channels.iter().map(|c| c.view()).collect();
+    Ok(stack(Axis(2), &views)?)
+}
+
+// --------------------------------------------------------------- Python Interface ---------------------------------------------------------------
+/// Demosaic a (h, w) frame captured behind a periodic color filter array, into a (h, w, c) array.
+/// The filter array is given as a color coded (h, w, 3) uint8 pattern where red, green, blue and
+/// white pixels denote the respective filters. It can be a single period of the filter array.
+/// The number of trials (bitplanes averaged in the frame) is needed for the photon-noise method.
+/// See `demosaic` in the rust docs for more.
+#[pyfunction]
+#[pyo3(
+    name = "demosaic",
+    signature = (frame, pattern, method=DemosaicMethod::Bilinear, num_trials=None)
+)]
+pub fn demosaic_py<'py>(
+    py: Python<'py>,
+    frame: &Bound<'py, PyAny>,
+    pattern: &Bound<'py, PyAny>,
+    method: DemosaicMethod,
+    num_trials: Option<f32>,
+) -> PyResult<Bound<'py, PyArray3<f32>>> {
+    let frame = pyarray_to_array2_bridge::<f32>(frame)?;
+    let pattern = pyarray_to_im_bridge::<u8>(pattern)?;
+    let cfa = cfa_from_pattern(pattern.view())?;
+    Ok(demosaic(frame.view(), &cfa, method, num_trials)?.to_pyarray_bound(py))
+}
+
+#[cfg(test)]
+mod test_demosaic {
+    use approx::assert_relative_eq;
+    use ndarray::{array, s, Array2, Array3};
+
+    use crate::demosaic::{cfa_from_pattern, demosaic, DemosaicMethod};
+
+    fn bayer() -> Vec<Array2<bool>> {
+        let pattern =
+            Array3::from_shape_vec((2, 2, 3), vec![255, 0, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255])
+                .unwrap();
+        cfa_from_pattern(pattern.view()).unwrap()
+    }
+
+    #[test]
+    fn test_cfa_from_pattern() {
+        let cfa = bayer();
+        assert_eq!(cfa.len(), 3);
+        assert_eq!(cfa[1], array![[false, true], [true, false]]);
+    }
+
+    #[test]
+    fn test_demosaic_constant() {
+        let frame = Array2::from_elem((16, 12), 0.25);
+        for method in [
+            DemosaicMethod::Bilinear,
+            DemosaicMethod::Edge,
+            DemosaicMethod::PhotonNoise,
+        ] {
+            let out = demosaic(frame.view(), &bayer(), method, Some(64.0)).unwrap();
+            assert_eq!(out.dim(), (16, 12, 3));
+            assert_relative_eq!(out, Array3::from_elem((16, 12, 3), 0.25), epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_demosaic_sparse() {
+        // Red filters are 18 pixels apart, so some pixels have no red sample within MAX_RADIUS
+        let pattern = Array3::from_shape_fn((18, 18, 3), |(i, j, c)| {
+            match ((i, j) == (0, 0), (i + j) % 2, c) {
+                (true, _, 0) => 255,
+                (true, _, _) => 0,
+                (false, 0, 1) | (false, 1, 2) => 255,
+                _ => 0,
+            }
+        });
+        let cfa = cfa_from_pattern(pattern.view()).unwrap();
+        let frame = Array2::from_elem((36, 36), 0.5);
+        assert!(demosaic(frame.view(), &cfa, DemosaicMethod::Bilinear, None).is_err());
+    }
+
+    #[test]
+    fn test_demosaic_bilinear_ramp() {
+        // A linear ramp is reproduced exactly away from the borders
+        let frame = Array2::from_shape_fn((12, 12), |(_, j)| j as f32 / 12.0);
+        let out = demosaic(frame.view(), &bayer(), DemosaicMethod::Bilinear, None).unwrap();
+        for c in 0..3 {
+            assert_relative_eq!(
+                out.slice(s![2..10, 2..10, c]),
+                frame.slice(s![2..10, 2..10]),
+                epsilon = 1e-5
+            );
+        }
+    }
+}
@@ -1,8 +1,12 @@
 #![warn(unused_extern_crates)]
 
 pub mod blend;
+pub mod bundle;
 pub mod cli;
+pub mod demosaic;
 pub mod lk;
+pub mod pano;
+pub mod pyramid;
 pub mod scripts;
 pub mod transpose;
 pub mod utils;
@@ -11,7 +15,18 @@ pub mod warps;
 use pyo3::prelude::*;
 
 use crate::{
-    lk::{iclk_py, img_pyramid_py, pairwise_iclk_py},
+    blend::{
+        distance_transform_py, drizzle_arrays_py, gain_compensation_py, merge_arrays_py,
+        merge_arrays_with_layers_py, merge_images_py, merge_photon_counts_py,
+        polygon_distance_transform_py, polygon_sdf_py,
+    },
+    demosaic::{demosaic_py, DemosaicMethod},
+    lk::{
+        iclk_py, img_pyramid_py, mosaic_iclk_py, pairwise_iclk_py, register_pairs_py,
+        GradientOperator, PixelSelection,
+    },
+    pano::PanoBuilder,
+    pyramid::PyramidFilter,
     scripts::cli_entrypoint,
     utils::animate_warp_py,
     warps::{Mapping, TransformationType},
@@ -23,10 +38,29 @@ fn spano(m: &Bound<'_, PyModule>) -> PyResult<()> {
 
     m.add_wrapped(wrap_pyfunction!(iclk_py))?;
     m.add_wrapped(wrap_pyfunction!(pairwise_iclk_py))?;
+    m.add_wrapped(wrap_pyfunction!(register_pairs_py))?;
+    m.add_wrapped(wrap_pyfunction!(mosaic_iclk_py))?;
     m.add_wrapped(wrap_pyfunction!(img_pyramid_py))?;
 
+    m.add_wrapped(wrap_pyfunction!(merge_arrays_py))?;
+    m.add_wrapped(wrap_pyfunction!(merge_arrays_with_layers_py))?;
+    m.add_wrapped(wrap_pyfunction!(merge_images_py))?;
+    m.add_wrapped(wrap_pyfunction!(drizzle_arrays_py))?;
+    m.add_wrapped(wrap_pyfunction!(merge_photon_counts_py))?;
+    m.add_wrapped(wrap_pyfunction!(distance_transform_py))?;
+    m.add_wrapped(wrap_pyfunction!(polygon_distance_transform_py))?;
+    m.add_wrapped(wrap_pyfunction!(polygon_sdf_py))?;
+    m.add_wrapped(wrap_pyfunction!(gain_compensation_py))?;
+
+    m.add_wrapped(wrap_pyfunction!(demosaic_py))?;
+
     m.add_class::<Mapping>()?;
     m.add_class::<TransformationType>()?;
+    m.add_class::<PanoBuilder>()?;
+    m.add_class::<DemosaicMethod>()?;
+    m.add_class::<GradientOperator>()?;
+    m.add_class::<PixelSelection>()?;
+    m.add_class::<PyramidFilter>()?;
 
     m.add_wrapped(wrap_pyfunction!(animate_warp_py))?;
     Ok(())
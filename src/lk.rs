@@ -1,17 +1,15 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{anyhow, Result};
+use clap::ValueEnum;
 use conv::{ValueFrom, ValueInto};
 use image::{GrayImage, Luma, Pixel};
-use imageproc::{
-    definitions::{Clamp, Image},
-    gradients::{HORIZONTAL_PREWITT, VERTICAL_PREWITT},
-};
+use imageproc::definitions::{Clamp, Image};
 use itertools::izip;
-use ndarray::{
-    concatenate, par_azip, s, stack, Array, Array1, Array2, Array3, ArrayBase, Axis, NewAxis,
-    RawData,
-};
+use ndarray::{Array2, Array3, ArrayBase, Axis, CowArray, RawData};
 use ndarray_linalg::solve::Inverse;
 use ndarray_ndimage::{correlate, BorderMode};
 use numpy::{
@@ -20,33 +18,107 @@ use numpy::{
 use photoncube2video::{signals::DeferredSignal, transforms::ref_image_to_array3};
 use pyo3::prelude::*;
 use rayon::prelude::*;
+use strum_macros::Display;
 
 use crate::{
+    blend::Mosaic,
+    pyramid::{gaussian_kernel, Pyramid, PyramidFilter},
     utils::get_pbar,
     warps::{Mapping, TransformationType},
 };
 
-/// Compute image gradients using Prewitt operator
+/// Gain of the Prewitt operator, i.e. its response to a unit ramp. All gradient operators are
+/// scaled to this gain, such that LK step sizes and early stopping don't depend on the operator.
+const PREWITT_GAIN: f32 = 6.0;
+
+#[pyclass]
+#[derive(Copy, Clone, Debug, Display, ValueEnum, PartialEq)]
+pub enum GradientOperator {
+    Prewitt,  // 3x3 Prewitt filter
+    Sobel,    // 3x3 Sobel filter, which is more isotropic than Prewitt
+    Scharr,   // 3x3 Scharr filter, optimized for rotational symmetry
+    Central,  // Central difference, without any smoothing
+    Gaussian, // Derivative of Gaussian, with a configurable standard deviation
+}
+
+impl GradientOperator {
+    /// Separable (smoothing, derivative) kernels of the operator, scaled to `PREWITT_GAIN`.
+    /// The standard deviation is only used by the derivative of Gaussian.
+    fn kernels(&self, sigma: f32) -> (Vec<f32>, Vec<f32>) {
+        let (smoothing, derivative) = match self {
+            GradientOperator::Prewitt => (vec![1.0, 1.0, 1.0], vec![-1.0, 0.0, 1.0]),
+            GradientOperator::Sobel => (vec![1.0, 2.0, 1.0], vec![-1.0, 0.0, 1.0]),
+            GradientOperator::Scharr => (vec![3.0, 10.0, 3.0], vec![-1.0, 0.0, 1.0]),
+            GradientOperator::Central => (vec![1.0], vec![-1.0, 0.0, 1.0]),
+            GradientOperator::Gaussian => {
+                let smoothing = gaussian_kernel(sigma);
+                let radius = (smoothing.len() / 2) as f32;
+                let derivative = smoothing
+                    .iter()
+                    .enumerate()
+                    .map(|(i, g)| (i as f32 - radius) * g)
+                    .collect();
+                (smoothing, derivative)
+            }
+        };
+
+        let radius = (derivative.len() / 2) as f32;
+        let gain = smoothing.iter().sum::<f32>()
+            * derivative
+                .iter()
+                .enumerate()
+                .map(|(i, d)| (i as f32 - radius) * d)
+                .sum::<f32>();
+        let derivative = derivative.iter().map(|d| d * PREWITT_GAIN / gain).collect();
+        (smoothing, derivative)
+    }
+}
+
+/// How image gradients are computed for LK, see `gradients`. If `smoothing` is set, images are
+/// blurred with a Gaussian kernel of that standard deviation before matching, which is applied
+/// to both the template and the reference image at every level of the pyramid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GradientSettings {
+    pub operator: GradientOperator,
+    pub sigma: f32,
+    pub smoothing: Option<f32>,
+}
+
+impl Default for GradientSettings {
+    fn default() -> Self {
+        Self {
+            operator: GradientOperator::Prewitt,
+            sigma: 1.0,
+            smoothing: None,
+        }
+    }
+}
+
+/// Compute image gradients using the given operator, with reflected borders. The standard
+/// deviation is only used by the derivative of Gaussian operator.
 /// Returned (dx, dy) pair as HxWxC arrays.
-pub fn gradients<S>(arr: &ArrayBase<S, Ix3>) -> (Array3<f32>, Array3<f32>)
+pub fn gradients<S>(
+    arr: &ArrayBase<S, Ix3>,
+    operator: GradientOperator,
+    sigma: f32,
+) -> (Array3<f32>, Array3<f32>)
 where
     S: RawData<Elem = f32> + ndarray::Data,
 {
+    let (smoothing, derivative) = operator.kernels(sigma);
+    let kernel = |rows: &[f32], cols: &[f32]| {
+        Array3::from_shape_fn((rows.len(), cols.len(), 1), |(y, x, _)| rows[y] * cols[x])
+    };
+
     let dx = correlate(
         &arr.view(),
-        &Array3::from_shape_vec((3, 3, 1), HORIZONTAL_PREWITT.to_vec())
-            .expect("Filter should contain 9 elements.")
-            .mapv(|v| v as f32)
-            .view(),
+        &kernel(&smoothing, &derivative).view(),
         BorderMode::Reflect,
         0,
     );
     let dy = correlate(
         &arr.view(),
-        &Array3::from_shape_vec((3, 3, 1), VERTICAL_PREWITT.to_vec())
-            .expect("Filter should contain 9 elements.")
-            .mapv(|v| v as f32)
-            .view(),
+        &kernel(&derivative, &smoothing).view(),
         BorderMode::Reflect,
         0,
     );
@@ -55,7 +127,7 @@ where
 
 /// Estimate the warp that maps `img2` to `img1` using the Pyramidal (or multi-level)
 /// Inverse Compositional Lucas-Kanade algorithm. This is akin to calling `iclk` on each level
-/// of `img_pyramid`.
+/// of a `Pyramid`.
 /// See `iclk_py` for more details.
 #[allow(clippy::type_complexity)]
 #[allow(clippy::too_many_arguments)]
@@ -70,6 +142,7 @@ pub fn iclk<P>(
     max_levels: Option<u32>,
     stop_early: Option<f32>,
     patience: Option<u32>,
+    settings: Option<&LKSettings>,
     message: bool,
 ) -> Result<(Mapping, HashMap<u32, Vec<Vec<f32>>>)>
 where
@@ -97,10 +170,46 @@ where
         max_levels,
         stop_early,
         patience,
+        settings,
         message,
     )
 }
 
+/// Settings of a single pyramid level in a coarse-to-fine schedule, see `iclk_array`.
+/// Iteration and early stopping settings that aren't set fall back to the global ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LevelSettings {
+    pub kind: TransformationType,
+    pub max_iters: Option<u32>,
+    pub stop_early: Option<f32>,
+}
+
+/// Optional settings of the LK algorithm, see `iclk_array`: the prefilter and scale factor of the
+/// pyramid, how gradients are computed (see `GradientSettings`), an optional budget of informative
+/// template pixels (see `PixelSelection`), and a coarse-to-fine schedule of mapping kinds (see
+/// `LevelSettings`). By default, a box filtered pyramid that halves the size at every level with
+/// Prewitt gradients is used, all pixels are matched and every level keeps the kind of the initial mapping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LKSettings {
+    pub pyramid_filter: PyramidFilter,
+    pub scale: f32,
+    pub gradients: GradientSettings,
+    pub pixel_selection: Option<(PixelSelection, usize)>,
+    pub schedule: Vec<LevelSettings>,
+}
+
+impl Default for LKSettings {
+    fn default() -> Self {
+        Self {
+            pyramid_filter: PyramidFilter::Box,
+            scale: 2.0,
+            gradients: GradientSettings::default(),
+            pixel_selection: None,
+            schedule: vec![],
+        }
+    }
+}
+
 /// See `iclk_py` for more details.
 #[allow(clippy::type_complexity)]
 #[allow(clippy::too_many_arguments)]
@@ -115,78 +224,128 @@ pub fn iclk_array<S>(
     max_levels: Option<u32>,
     stop_early: Option<f32>,
     patience: Option<u32>,
+    settings: Option<&LKSettings>,
     message: bool,
 ) -> Result<(Mapping, HashMap<u32, Vec<Vec<f32>>>)>
 where
     S: RawData<Elem = f32> + ndarray::Data + Sync,
 {
-    let mut all_params_history = HashMap::new();
-
-    // Early out with single scale matching
-    if !multi {
-        let msg = if message { Some("Matching") } else { None };
-        let (mapping, params_history) = _iclk_single(
-            im1,
-            im2,
-            init_mapping,
-            im1_weights,
-            max_iters,
-            stop_early,
-            patience,
-            msg,
-        )?;
-        all_params_history.insert(1, params_history);
-        return Ok((mapping, all_params_history));
-    }
-
-    // Perform multi-scale matching
+    // Single scale matching simply uses a pyramid with a single level
     let min_dimension = min_dimension.unwrap_or(16);
     let min_dimensions = (min_dimension, min_dimension);
     let max_levels = max_levels.unwrap_or(8);
-    let stack_im1 = img_pyramid(im1, min_dimensions, max_levels);
-    let stack_im2 = img_pyramid(im2, min_dimensions, max_levels);
+    let default_settings = LKSettings::default();
+    let settings = settings.unwrap_or(&default_settings);
+    let (scale, filter) = (settings.scale, settings.pyramid_filter);
+    let pyramid1 = FramePyramid::new(im1, multi, scale, filter, min_dimensions, max_levels)?
+        .with_gradients(settings.gradients);
+    let pyramid2 = FramePyramid::new(im2, multi, scale, filter, min_dimensions, max_levels)?
+        .with_gradients(settings.gradients)
+        .with_selection(settings.pixel_selection);
+    let pyramid_weights = im1_weights
+        .map(|weights| FramePyramid::new(weights, multi, scale, filter, min_dimensions, max_levels))
+        .transpose()?;
 
-    let num_lvls = stack_im1.len().min(stack_im2.len());
+    _iclk_pyramid(
+        &pyramid1,
+        &pyramid2,
+        pyramid_weights.as_ref().map(|p| p.levels()),
+        init_mapping,
+        usize::MAX,
+        max_iters,
+        stop_early,
+        patience,
+        &settings.schedule,
+        message,
+    )
+}
+
+/// Multi-scale matching given the pyramids of both images, and optionally the pyramid of the
+/// weights of image 1. Levels that only exist in one of the pyramids are ignored. Template data
+/// of image 2 is taken from its pyramid's cache, see `FramePyramid::template`.
+///
+/// Only the `max_levels` finest levels are used, such that multi-level pyramids can also be used for
+/// single scale matching.
+///
+/// If a schedule is given, its entries are applied from the coarsest level onwards, and the last
+/// entry is used for all remaining levels. If there are fewer levels than entries, the coarsest
+/// entries are skipped instead, such that the finest level always uses the last entry.
+/// The mapping is cast to every level's kind.
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+fn _iclk_pyramid(
+    pyramid1: &FramePyramid,
+    pyramid2: &FramePyramid,
+    stack_weights: Option<&[CowArray<f32, Ix3>]>,
+    init_mapping: Mapping,
+    max_levels: usize,
+    max_iters: Option<u32>,
+    stop_early: Option<f32>,
+    patience: Option<u32>,
+    schedule: &[LevelSettings],
+    message: bool,
+) -> Result<(Mapping, HashMap<u32, Vec<Vec<f32>>>)> {
+    let (stack_im1, stack_im2) = (pyramid1.levels(), pyramid2.levels());
+    let num_lvls = stack_im1.len().min(stack_im2.len()).min(max_levels);
     let mut mapping = init_mapping;
     let mut all_params_history = HashMap::new();
 
-    let stack_weights = im1_weights.map_or(vec![None; num_lvls], |weights| {
-        img_pyramid(weights, min_dimensions, max_levels)
-            .into_iter()
-            .map(Some)
-            .collect()
-    });
+    let stack_weights: Vec<Option<&CowArray<f32, Ix3>>> = stack_weights
+        .map_or(vec![None; num_lvls], |weights| {
+            weights.iter().map(Some).collect()
+        });
 
-    for (i, (im1, im2, weights)) in izip!(
+    for (i, (im1, (lvl, im2), weights)) in izip!(
         stack_im1[..num_lvls].iter().rev(),
-        stack_im2[..num_lvls].iter().rev(),
+        stack_im2[..num_lvls].iter().enumerate().rev(),
         stack_weights[..num_lvls].iter().rev()
     )
     .enumerate()
     {
-        // Compute mapping at lowest resolution first and double resolution it at each iteration
-        let current_scale = (1 << (num_lvls - i - 1)) as f32;
+        // Compute mapping at lowest resolution first and upscale it at each iteration
+        let current_scale = pyramid2.pyramid().scale().powi((num_lvls - i - 1) as i32);
 
         // Perform optimization at lvl
         let params_history;
         let msg = format!("Matching scale 1/{:}", &current_scale);
         let msg = if message { Some(msg) } else { None };
 
+        // Apply level's settings from schedule if any, anchored such that the finest level gets the last one
+        let offset = schedule.len().saturating_sub(num_lvls);
+        let settings = schedule.get(i + offset).or(schedule.last());
+        let (max_iters, stop_early) = match settings {
+            Some(settings) => {
+                mapping = Mapping::from_matrix(mapping.mat, settings.kind);
+                (
+                    settings.max_iters.or(max_iters),
+                    settings.stop_early.or(stop_early),
+                )
+            }
+            None => (max_iters, stop_early),
+        };
+
+        let template = pyramid2.template(lvl, mapping.kind)?;
         (mapping, params_history) = _iclk_single(
             im1,
             im2,
+            &template,
             mapping,
-            weights.as_ref(),
+            *weights,
             max_iters,
             stop_early,
             patience,
             msg.as_deref(),
         )?;
 
-        // Re-normalize mapping to scale of next level of pyramid
-        // But not on last iteration (since we're already at full scale)
+        // Re-normalize mapping to scale of next level of pyramid, using the exact ratio of
+        // level sizes. But not on last iteration (since we're already at full scale)
         if i + 1 < num_lvls {
-            mapping = mapping.rescale(0.5);
+            let up1 = pyramid1.pyramid().upsampling(lvl);
+            let up2 = pyramid2.pyramid().upsampling(lvl);
+            mapping = Mapping::from_matrix(
+                up2.mat.dot(&mapping.mat).dot(&up1.inverse().mat),
+                mapping.kind,
+            );
         }
 
         // Save level's param history
@@ -196,10 +355,368 @@ where
     Ok((mapping, all_params_history))
 }
 
+/// Number of selected pixels processed at once by a parallel task, see `Template::fold_pixels`.
+const PIXEL_CHUNK_SIZE: usize = 1024;
+
+/// Strategy used to restrict the LK objective to a subset of informative template pixels, which
+/// greatly speeds up matching of large frames since flat regions contribute nothing to it.
+/// The number of selected pixels is given by a per-level budget.
+#[pyclass]
+#[derive(Copy, Clone, Debug, Display, ValueEnum, PartialEq)]
+pub enum PixelSelection {
+    Gradient,  // Pixels with the largest gradient magnitude
+    ShiTomasi, // Pixels with the largest Shi-Tomasi score, i.e. smallest structure tensor eigenvalue
+    Random,    // Stratified random sampling, one pixel per cell of a regular grid
+}
+
+/// Precomputed data of a template image (`im2` in `iclk`) for a given kind of mapping, namely its
+/// gradients, the pixels that take part in the objective, and the inverse of its Hessian. These
+/// only depend on the template, so they can be computed once and shared by every registration that
+/// uses the same template.
+///
+/// Steepest-descent images are never stored, since the warp's jacobian has a closed form in (x, y)
+/// (see `warp_jacobian`), they are instead computed on the fly from the gradients when needed.
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// Kind of mapping the template was computed for.
+    pub kind: TransformationType,
+    /// Pixel selection strategy and budget the template was computed with, if any.
+    pub selection: Option<(PixelSelection, usize)>,
+    /// Horizontal and vertical gradients of the template, of shape (H, W, C).
+    dx: Array3<f32>,
+    dy: Array3<f32>,
+    /// Sorted row-major indices of the selected pixels, or None if all pixels are used.
+    pixels: Option<Vec<usize>>,
+    /// Inverse of the Gauss-Newton Hessian, of shape (N, N) where N is the number of parameters.
+    hessian_inv: Array2<f32>,
+}
+
+impl Template {
+    /// Compute the gradients, selected pixels and inverse Hessian of a template image. The Hessian
+    /// is accumulated in parallel, without materializing any steepest-descent images.
+    pub fn new<S>(
+        im2: &ArrayBase<S, Ix3>,
+        kind: TransformationType,
+        selection: Option<(PixelSelection, usize)>,
+        gradient_settings: GradientSettings,
+    ) -> Result<Self>
+    where
+        S: RawData<Elem = f32> + ndarray::Data,
+    {
+        if kind == TransformationType::Unknown {
+            return Err(anyhow!("Mapping type {:?} not supported!", kind));
+        }
+        let (_, w, c) = im2.dim();
+        let num_params = kind.num_params();
+        let (dx, dy) = gradients(im2, gradient_settings.operator, gradient_settings.sigma);
+        let pixels = selection.and_then(|(method, budget)| select_pixels(&dx, &dy, method, budget));
+
+        let mut template = Self {
+            kind,
+            selection,
+            dx,
+            dy,
+            pixels,
+            hessian_inv: Array2::zeros((0, 0)),
+        };
+        let (dx_slice, dy_slice) = (
+            template
+                .dx
+                .as_slice()
+                .expect("Gradients should be contiguous."),
+            template
+                .dy
+                .as_slice()
+                .expect("Gradients should be contiguous."),
+        );
+
+        let hessian = template.fold_pixels(|x, y, hessian: &mut [f32; 64]| {
+            let jacobian = warp_jacobian(kind, x as f32, y as f32);
+            let mut sd = [0.0f32; 8];
+            for ch in 0..c {
+                let idx = (y * w + x) * c + ch;
+                for (sd, j0, j1) in izip!(sd.iter_mut(), jacobian[0], jacobian[1]) {
+                    *sd = dx_slice[idx] * j0 + dy_slice[idx] * j1;
+                }
+                for (row, sd_i) in hessian.chunks_exact_mut(8).zip(sd).take(num_params) {
+                    for (h, sd_j) in row.iter_mut().zip(sd).take(num_params) {
+                        *h += sd_i * sd_j;
+                    }
+                }
+            }
+        });
+        let hessian = Array2::from_shape_fn((num_params, num_params), |(i, j)| hessian[i * 8 + j]);
+        template.hessian_inv = if num_params == 0 {
+            hessian
+        } else {
+            hessian.inv()?
+        };
+        Ok(template)
+    }
+
+    /// Number of pixels that take part in the objective.
+    pub fn num_pixels(&self) -> usize {
+        self.pixels
+            .as_ref()
+            .map_or(self.dx.dim().0 * self.dx.dim().1, |pixels| pixels.len())
+    }
+
+    /// Accumulate `op(x, y, acc)` over all selected pixels in parallel, and return the sum of
+    /// all partial accumulators. Pixels are processed row by row if all of them are selected.
+    fn fold_pixels<const N: usize, F>(&self, op: F) -> [f32; N]
+    where
+        F: Fn(usize, usize, &mut [f32; N]) + Sync,
+    {
+        let (h, w, _) = self.dx.dim();
+        let sum = |mut a: [f32; N], b: [f32; N]| {
+            a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+            a
+        };
+
+        match &self.pixels {
+            None => (0..h)
+                .into_par_iter()
+                .map(|y| {
+                    let mut acc = [0.0f32; N];
+                    (0..w).for_each(|x| op(x, y, &mut acc));
+                    acc
+                })
+                .reduce(|| [0.0f32; N], sum),
+            Some(pixels) => pixels
+                .par_chunks(PIXEL_CHUNK_SIZE)
+                .map(|chunk| {
+                    let mut acc = [0.0f32; N];
+                    chunk.iter().for_each(|i| op(i % w, i / w, &mut acc));
+                    acc
+                })
+                .reduce(|| [0.0f32; N], sum),
+        }
+    }
+}
+
+/// Select at most `budget` informative pixels given the template's gradients, see `PixelSelection`.
+/// Returns their sorted row-major indices, or None if the budget covers the whole template.
+fn select_pixels(
+    dx: &Array3<f32>,
+    dy: &Array3<f32>,
+    method: PixelSelection,
+    budget: usize,
+) -> Option<Vec<usize>> {
+    let (h, w, _) = dx.dim();
+    if budget >= h * w {
+        return None;
+    }
+
+    // Per pixel structure tensor entries (gx^2, gx*gy, gy^2), summed over channels
+    let tensor: Vec<[f32; 3]> = dx
+        .lanes(Axis(2))
+        .into_iter()
+        .zip(dy.lanes(Axis(2)))
+        .map(|(gx, gy)| {
+            izip!(gx, gy).fold([0.0; 3], |[xx, xy, yy], (gx, gy)| {
+                [xx + gx * gx, xy + gx * gy, yy + gy * gy]
+            })
+        })
+        .collect();
+
+    let scores: Vec<f32> = match method {
+        PixelSelection::Gradient => tensor.iter().map(|[xx, _, yy]| xx + yy).collect(),
+        PixelSelection::ShiTomasi => (0..h * w)
+            .into_par_iter()
+            .map(|i| {
+                // Sum the tensor over a 3x3 window and take its smallest eigenvalue
+                let (x, y) = (i % w, i / w);
+                let [mut xx, mut xy, mut yy] = [0.0f32; 3];
+                for wy in y.saturating_sub(1)..(y + 2).min(h) {
+                    for wx in x.saturating_sub(1)..(x + 2).min(w) {
+                        let [txx, txy, tyy] = tensor[wy * w + wx];
+                        (xx, xy, yy) = (xx + txx, xy + txy, yy + tyy);
+                    }
+                }
+                (xx + yy) / 2.0 - (((xx - yy) / 2.0).powi(2) + xy * xy).sqrt()
+            })
+            .collect(),
+        PixelSelection::Random => {
+            // Split template into roughly `budget` square cells and pick one pixel in each,
+            // using a fixed hash of the cell index such that the selection is deterministic
+            let cell = ((h * w) as f32 / budget.max(1) as f32).sqrt().ceil() as usize;
+            let mut pixels: Vec<usize> = (0..h.div_ceil(cell))
+                .flat_map(|cy| (0..w.div_ceil(cell)).map(move |cx| (cx, cy)))
+                .map(|(cx, cy)| {
+                    let (cw, ch) = (cell.min(w - cx * cell), cell.min(h - cy * cell));
+                    let hash = splitmix64((cy * w + cx) as u64) as usize;
+                    let offset = hash % (cw * ch);
+                    (cy * cell + offset / cw) * w + cx * cell + offset % cw
+                })
+                .collect();
+            pixels.truncate(budget);
+            pixels.sort_unstable();
+            return Some(pixels);
+        }
+    };
+
+    // Keep the pixels with the highest scores
+    let mut pixels: Vec<usize> = (0..h * w).collect();
+    pixels.select_nth_unstable_by(budget.max(1) - 1, |a, b| scores[*b].total_cmp(&scores[*a]));
+    pixels.truncate(budget);
+    pixels.sort_unstable();
+    Some(pixels)
+}
+
+/// Simple integer hash, used to draw reproducible pseudo-random samples.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Jacobian of the warp with respect to its parameters, evaluated at (x, y) and at the identity,
+/// as the two rows (du/dp, dv/dp). Only the first `kind.num_params()` entries are used.
+fn warp_jacobian(kind: TransformationType, x: f32, y: f32) -> [[f32; 8]; 2] {
+    match kind {
+        TransformationType::Translational => [
+            [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ],
+        TransformationType::Homothety => [
+            [1.0, 0.0, x, 0.0, 0.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, y, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ],
+        TransformationType::Similarity => [
+            [1.0, 0.0, x, -y, 0.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, y, x, 0.0, 0.0, 0.0, 0.0],
+        ],
+        TransformationType::Affine => [
+            [x, 0.0, y, 0.0, 1.0, 0.0, 0.0, 0.0],
+            [0.0, x, 0.0, y, 0.0, 1.0, 0.0, 0.0],
+        ],
+        TransformationType::Projective => [
+            [x, 0.0, y, 0.0, 1.0, 0.0, -x * x, -x * y],
+            [0.0, x, 0.0, y, 0.0, 1.0, -x * y, -y * y],
+        ],
+        TransformationType::Identity | TransformationType::Unknown => [[0.0; 8]; 2],
+    }
+}
+
+/// Bilinear interpolation taps of a (h, w) grid at a sub-pixel location, with the same conventions
+/// as `Mapping::warp_array3_into` without background. Returns the row-major indices of the four
+/// neighbours and their weights, or None if the location is out of bounds.
+fn bilinear_taps(size: (usize, usize), x: f32, y: f32) -> Option<([usize; 4], [f32; 4])> {
+    let (h, w) = size;
+    if !(0.0 <= x && x <= (w as f32) - 1.0 && 0.0 <= y && y <= (h as f32) - 1.0) {
+        return None;
+    }
+    let (left, top) = (x.floor(), y.floor());
+    let (right_weight, bottom_weight) = (x - left, y - top);
+    let (l, t) = (left as usize, top as usize);
+    let (r, b) = ((l + 1).min(w - 1), (t + 1).min(h - 1));
+
+    Some((
+        [t * w + l, t * w + r, b * w + l, b * w + r],
+        [
+            (1.0 - bottom_weight) * (1.0 - right_weight),
+            (1.0 - bottom_weight) * right_weight,
+            bottom_weight * (1.0 - right_weight),
+            bottom_weight * right_weight,
+        ],
+    ))
+}
+
+/// Pyramid of a frame, largest level first, along with the template data of every level, which
+/// is computed lazily for each kind of mapping the first time it's needed.
+pub struct FramePyramid<'a> {
+    pyramid: Pyramid<'a>,
+    templates: Vec<Mutex<Vec<Arc<Template>>>>,
+    selection: Option<(PixelSelection, usize)>,
+    gradients: GradientSettings,
+}
+
+impl<'a> FramePyramid<'a> {
+    /// Create a pyramid that downscales the frame by `scale` at every level, see `Pyramid::new`,
+    /// or a single level pyramid if `multi` is false. The first level borrows the frame.
+    pub fn new<S>(
+        frame: &'a ArrayBase<S, Ix3>,
+        multi: bool,
+        scale: f32,
+        filter: PyramidFilter,
+        min_dimensions: (usize, usize),
+        max_levels: u32,
+    ) -> Result<Self>
+    where
+        S: RawData<Elem = f32> + ndarray::Data,
+    {
+        let pyramid = if multi {
+            Pyramid::new(frame, scale, filter, min_dimensions, max_levels)?
+        } else {
+            Pyramid::single(frame)
+        };
+        let templates = pyramid
+            .levels()
+            .iter()
+            .map(|_| Mutex::new(vec![]))
+            .collect();
+        Ok(Self {
+            pyramid,
+            templates,
+            selection: None,
+            gradients: GradientSettings::default(),
+        })
+    }
+
+    /// Set how template gradients are computed, and smooth every level if needed, see
+    /// `GradientSettings`. This should be set before any template data is computed.
+    pub fn with_gradients(mut self, gradients: GradientSettings) -> Self {
+        if let Some(sigma) = gradients.smoothing {
+            self.pyramid = self.pyramid.smoothed(sigma);
+        }
+        self.gradients = gradients;
+        self
+    }
+
+    /// Restrict the templates of every level to a budget of informative pixels, see `PixelSelection`.
+    pub fn with_selection(mut self, selection: Option<(PixelSelection, usize)>) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Levels of the pyramid, largest first.
+    pub fn levels(&self) -> &[CowArray<'a, f32, Ix3>] {
+        self.pyramid.levels()
+    }
+
+    /// Underlying pyramid of the frame.
+    pub fn pyramid(&self) -> &Pyramid<'a> {
+        &self.pyramid
+    }
+
+    /// Template data of the given level for a kind of mapping, computing it if needed.
+    pub fn template(&self, level: usize, kind: TransformationType) -> Result<Arc<Template>> {
+        let mut templates = self.templates[level]
+            .lock()
+            .map_err(|_| anyhow!("Template cache is poisoned."))?;
+        if let Some(template) = templates
+            .iter()
+            .find(|t| t.kind == kind && t.selection == self.selection)
+        {
+            return Ok(template.clone());
+        }
+        let template = Arc::new(Template::new(
+            &self.levels()[level],
+            kind,
+            self.selection,
+            self.gradients,
+        )?);
+        templates.push(template.clone());
+        Ok(template)
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn _iclk_single<S>(
     im1: &ArrayBase<S, Ix3>,
     im2: &ArrayBase<S, Ix3>,
+    template: &Template,
     init_mapping: Mapping,
     im1_weights: Option<&ArrayBase<S, Ix3>>,
     max_iters: Option<u32>,
@@ -210,164 +727,42 @@ fn _iclk_single<S>(
 where
     S: RawData<Elem = f32> + ndarray::Data + Sync,
 {
+    if init_mapping.kind == TransformationType::Identity {
+        return Ok((Mapping::identity(), vec![vec![]]));
+    }
+    if init_mapping.kind != template.kind {
+        return Err(anyhow!(
+            "Template was computed for {:?} mappings, got {:?}.",
+            template.kind,
+            init_mapping.kind
+        ));
+    }
+
     // Initialize values
     let mut params = init_mapping.inverse().get_params();
-    let (h, w, c) = im2.dim();
-    let num_points = w * h;
+    let (_, w, c) = im2.dim();
     let num_params = params.len();
+    let kind = init_mapping.kind;
 
-    let points = Array::from_shape_fn((num_points, 2), |(i, j)| if j == 0 { i % w } else { i / w });
-    let xs = points.column(0).mapv(|v| v as f32);
-    let ys = points.column(1).mapv(|v| v as f32);
-    let mut img1_array_with_weights;
-
-    let (img1_array, has_weights) = if let Some(weights) = im1_weights {
-        // Concatenate weights as last channel of img1_array if has_weights
-        img1_array_with_weights = concatenate(Axis(2), &[im1.view(), weights.view()])?;
-        img1_array_with_weights = img1_array_with_weights.as_standard_layout().to_owned();
-        (img1_array_with_weights.view(), true)
-    } else {
-        (im1.view(), false)
-    };
-    let mut warped_im1gray_pixels = Array2::<f32>::zeros((num_points, c + has_weights as usize));
-    let (dx, dy) = gradients(im2);
-    let img2_pixels = im2.view().into_shape((num_points, c))?;
-    let mut valid = Array1::<bool>::from_elem(num_points, false);
-
-    // These can be cached, so we init them before the main loop
-    let grad_im2 = stack![
-        Axis(2),
-        dx.view().into_shape((num_points, c))?,
-        dy.view().into_shape((num_points, c))?
-    ]; // (HWxCx2)
-    let ones: Array1<f32> = ArrayBase::ones(num_points);
-    let zeros: Array1<f32> = ArrayBase::zeros(num_points);
-
-    let steepest_descent_ic = match init_mapping.kind {
-        TransformationType::Identity => return Ok((Mapping::identity(), vec![vec![]])),
-        TransformationType::Translational => {
-            // Jacobian is the identity, so just return the gradients
-            grad_im2
-        }
-        TransformationType::Homothety => {
-            let mut steepest_descent_ic = Array3::zeros((num_points, c, 3));
-
-            let jacobian_p = stack![
-                Axis(1),
-                stack![Axis(1), ones, zeros, xs],
-                stack![Axis(1), zeros, ones, ys]
-            ]; // (HWx2xN)
-
-            // Perform the batch matrix multiply of grad_im2 @ jacobian_p
-            par_azip!(
-                (
-                    mut v in steepest_descent_ic.axis_iter_mut(Axis(0)),
-                    a in grad_im2.axis_iter(Axis(0)),
-                    b in jacobian_p.axis_iter(Axis(0))
-                )
-                {v.assign(&a.dot(&b))}
-            );
-            steepest_descent_ic
-        }
-        TransformationType::Similarity => {
-            let mut steepest_descent_ic = Array3::zeros((num_points, c, 4));
-
-            let jacobian_p = stack![
-                Axis(1),
-                stack![Axis(1), ones, zeros, xs, -ys.clone()],
-                stack![Axis(1), zeros, ones, ys, xs]
-            ]; // (HWx2xN)
-
-            // Perform the batch matrix multiply of grad_im2 @ jacobian_p
-            par_azip!(
-                (
-                    mut v in steepest_descent_ic.axis_iter_mut(Axis(0)),
-                    a in grad_im2.axis_iter(Axis(0)),
-                    b in jacobian_p.axis_iter(Axis(0))
-                )
-                {v.assign(&a.dot(&b))}
-            );
-            steepest_descent_ic
-        }
-        TransformationType::Affine => {
-            let mut steepest_descent_ic = Array3::zeros((num_points, c, 6));
-
-            let jacobian_p = stack![
-                Axis(1),
-                stack![Axis(1), xs, zeros, ys, zeros, ones, zeros],
-                stack![Axis(1), zeros, xs, zeros, ys, zeros, ones]
-            ]; // (HWx2xN)
-
-            // Perform the batch matrix multiply of grad_im2 @ jacobian_p
-            par_azip!(
-                (
-                    mut v in steepest_descent_ic.axis_iter_mut(Axis(0)),
-                    a in grad_im2.axis_iter(Axis(0)),
-                    b in jacobian_p.axis_iter(Axis(0))
-                )
-                {v.assign(&a.dot(&b))}
-            );
-            steepest_descent_ic
-        }
-        TransformationType::Projective => {
-            let mut steepest_descent_ic = Array3::zeros((num_points, c, 8));
-
-            // dW_dp evaluated at (x, p) and p=0 (identity transform)
-            let jacobian_p = {
-                let minus_xx: Vec<f32> = xs.iter().map(|i1| -i1 * i1).collect();
-                let minus_yy: Vec<f32> = ys.iter().map(|i1| -i1 * i1).collect();
-                let minus_xy: Vec<f32> = xs.iter().zip(&ys).map(|(i1, i2)| -i1 * i2).collect();
-                stack![
-                    Axis(1),
-                    stack![
-                        Axis(1),
-                        xs,
-                        zeros,
-                        ys,
-                        zeros,
-                        ones,
-                        zeros,
-                        minus_xx,
-                        minus_xy
-                    ],
-                    stack![
-                        Axis(1),
-                        zeros,
-                        xs,
-                        zeros,
-                        ys,
-                        zeros,
-                        ones,
-                        minus_xy,
-                        minus_yy
-                    ]
-                ]
-            }; // (HWx2xN)
-
-            // Perform the batch matrix multiply of grad_im2 @ jacobian_p
-            par_azip!(
-                (
-                    mut v in steepest_descent_ic.axis_iter_mut(Axis(0)),
-                    a in grad_im2.axis_iter(Axis(0)),
-                    b in jacobian_p.axis_iter(Axis(0))
-                )
-                {v.assign(&a.dot(&b))}
-            );
-            steepest_descent_ic
-        }
-        TransformationType::Unknown => {
-            return Err(anyhow!(
-                "Mapping type {:?} not supported!",
-                init_mapping.kind
-            ))
-        }
-    };
-    let hessian: Array2<f32> = steepest_descent_ic
-        .axis_iter(Axis(0))
-        .map(|cn| cn.t().dot(&cn))
-        .fold(Array2::<f32>::zeros((num_params, num_params)), |a, b| a + b);
-    let hessian_inv = hessian.inv().unwrap();
-    let steepest_descent_ic_t = steepest_descent_ic.permuted_axes([0, 2, 1]);
+    // Get contiguous views of all data, weights are sampled alongside img1
+    let (h1, w1, _) = im1.dim();
+    let img1 = im1.as_standard_layout();
+    let img1_slice = img1.as_slice().expect("Data should be contiguous.");
+    let weights = im1_weights.map(|weights| weights.as_standard_layout());
+    let weights_slice = weights
+        .as_ref()
+        .map(|weights| weights.as_slice().expect("Weights should be contiguous."));
+    let img2 = im2.as_standard_layout();
+    let img2_slice = img2.as_slice().expect("Data should be contiguous.");
+    let dx = template
+        .dx
+        .as_slice()
+        .expect("Gradients should be contiguous.");
+    let dy = template
+        .dy
+        .as_slice()
+        .expect("Gradients should be contiguous.");
+    let hessian_inv = &template.hessian_inv;
 
     // Tracking variables
     let pbar = get_pbar(max_iters.unwrap_or(250) as usize, message);
@@ -382,43 +777,43 @@ where
         // Create mapping from params and use it to sample points from img1
         // TODO: Warp with background or without?
         let mapping = Mapping::from_params(params);
-        mapping.warp_array3_into::<f32, _, _, _, _, _>(
-            &img1_array,
-            &mut warped_im1gray_pixels,
-            &mut valid,
-            &points,
-            None,
-            None,
-        );
+        let m = &mapping.mat;
 
-        // Calculate parameter update dp
-        let sd_param_updates = (
-            steepest_descent_ic_t.axis_iter(Axis(0)),
-            warped_im1gray_pixels.axis_iter(Axis(0)),
-            img2_pixels.axis_iter(Axis(0)),
-            valid.as_slice().unwrap().par_iter(),
-        )
-            // Zip together all three iterators and valid flag
-            .into_par_iter()
-            // Drop them if the warped value from is out-of-bounds
-            // Calculate parameter update according to formula
-            .filter_map(|(sd, p1, p2, &is_valid)| {
-                if is_valid {
-                    let weight = if has_weights { p1[c] } else { 1.0 };
-                    let diff = p1.slice(s![..c]).to_owned() - p2.to_owned();
-                    Some(sd.dot(&diff.slice(s![.., NewAxis])) * weight)
-                } else {
-                    None
+        // Calculate parameter update dp, by sampling img1 and computing steepest-descent
+        // images on the fly, over all selected pixels in parallel. Out-of-bounds samples are dropped.
+        let sd_param_updates = template.fold_pixels(|x, y, update: &mut [f32; 8]| {
+            let (xf, yf) = (x as f32, y as f32);
+            let d = (m[(2, 0)] * xf + m[(2, 1)] * yf + m[(2, 2)]).max(1e-8);
+            let u = (m[(0, 0)] * xf + m[(0, 1)] * yf + m[(0, 2)]) / d;
+            let v = (m[(1, 0)] * xf + m[(1, 1)] * yf + m[(1, 2)]) / d;
+
+            let Some((taps, tap_weights)) = bilinear_taps((h1, w1), u, v) else {
+                return;
+            };
+            let weight = weights_slice.map_or(1.0, |weights_slice| {
+                izip!(taps, tap_weights)
+                    .map(|(t, tw)| weights_slice[t] * tw)
+                    .sum::<f32>()
+            });
+
+            let jacobian = warp_jacobian(kind, xf, yf);
+            for ch in 0..c {
+                let idx = (y * w + x) * c + ch;
+                let p1: f32 = izip!(taps, tap_weights)
+                    .map(|(t, tw)| img1_slice[t * c + ch] * tw)
+                    .sum();
+                let diff = (p1 - img2_slice[idx]) * weight;
+                for (update, j0, j1) in izip!(update.iter_mut(), jacobian[0], jacobian[1]) {
+                    *update += (dx[idx] * j0 + dy[idx] * j1) * diff;
                 }
-            })
-            // Sum them together, here we use reduce with a base value of zero
-            .reduce(|| Array2::<f32>::zeros((num_params, 1)), |a, b| a + b);
+            }
+        });
+        let sd_param_updates = Array2::from_shape_fn((num_params, 1), |(n, _)| sd_param_updates[n]);
         let dp: Array2<f32> = hessian_inv.dot(&sd_param_updates);
         let mapping_dp = Mapping::from_params(dp.clone().into_raw_vec());
 
         // Update the parameters
-        params = Mapping::from_matrix(mapping.mat.dot(&mapping_dp.mat.inv()?), init_mapping.kind)
-            .get_params();
+        params = Mapping::from_matrix(mapping.mat.dot(&mapping_dp.mat.inv()?), kind).get_params();
         params_history.push(params.clone());
 
         // Push back dp update, pop old one if deque is full
@@ -453,46 +848,287 @@ pub fn pairwise_iclk<S>(
     max_levels: Option<u32>,
     stop_early: Option<f32>,
     patience: Option<u32>,
+    settings: Option<&LKSettings>,
     message: bool,
 ) -> Result<(Vec<Mapping>, Vec<HashMap<u32, Vec<Vec<f32>>>>)>
 where
     S: RawData<Elem = f32> + ndarray::Data + Sync,
 {
+    // Match every frame to the next one
+    let pairs: Vec<(usize, usize)> = (1..frames.len()).map(|i| (i - 1, i)).collect();
+    let mut results = register_pairs(
+        frames,
+        &pairs,
+        init_mappings,
+        multi,
+        max_iters,
+        min_dimension,
+        max_levels,
+        stop_early,
+        patience,
+        settings,
+        message,
+    )?;
+
+    // Return raw pairwise warps (N-1 in total)
+    Ok(pairs.iter().filter_map(|pair| results.remove(pair)).unzip())
+}
+
+/// Estimate the registration between arbitrary pairs of frames using iclk, in parallel.
+/// Every pair (i, j) is matched with frame i as `im1` and frame j as `im2`, starting from
+/// the corresponding initial mapping, see `iclk_array`. This enables any match graph, such as
+/// 2D grid scans, multi-row panoramas, or registering frames to many others as a redundancy check.
+///
+/// The pyramid of each frame is computed once and reused by every pair that frame is part of, see
+/// `register_pyramids` to also reuse them across calls.
+/// Returns the mapping and parameter history of every pair, keyed by pair, hence pairs must be unique.
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+pub fn register_pairs<S>(
+    frames: &[ArrayBase<S, Ix3>],
+    pairs: &[(usize, usize)],
+    init_mappings: &[Mapping],
+    multi: bool,
+    max_iters: Option<u32>,
+    min_dimension: Option<usize>,
+    max_levels: Option<u32>,
+    stop_early: Option<f32>,
+    patience: Option<u32>,
+    settings: Option<&LKSettings>,
+    message: bool,
+) -> Result<HashMap<(usize, usize), (Mapping, HashMap<u32, Vec<Vec<f32>>>)>>
+where
+    S: RawData<Elem = f32> + ndarray::Data + Sync,
+{
+    if let Some((i, j)) = pairs
+        .iter()
+        .find(|(i, j)| *i >= frames.len() || *j >= frames.len())
+    {
+        return Err(anyhow!(
+            "Pair ({i}, {j}) is out of bounds for {} frames.",
+            frames.len()
+        ));
+    }
+
+    // Build pyramids of all frames that are used at least once, their template
+    // data is then computed lazily and shared by all pairs that need it
+    let pyramids = frame_pyramids(
+        frames,
+        pairs.iter().flat_map(|(i, j)| [*i, *j]),
+        multi,
+        min_dimension,
+        max_levels,
+        settings,
+    )?;
+    register_pyramids(
+        &pyramids,
+        pairs,
+        init_mappings,
+        multi,
+        max_iters,
+        stop_early,
+        patience,
+        settings.map(|s| &s.schedule[..]),
+        message,
+    )
+}
+
+/// Build the pyramids of the given frames, keyed by frame index, see `FramePyramid`. These can
+/// be shared by many calls to `register_pyramids`, such that the template data of every frame
+/// is only computed once. The schedule of the settings, if any, is not used here.
+#[allow(clippy::too_many_arguments)]
+pub fn frame_pyramids<'a, S>(
+    frames: &'a [ArrayBase<S, Ix3>],
+    indices: impl IntoIterator<Item = usize>,
+    multi: bool,
+    min_dimension: Option<usize>,
+    max_levels: Option<u32>,
+    settings: Option<&LKSettings>,
+) -> Result<HashMap<usize, FramePyramid<'a>>>
+where
+    S: RawData<Elem = f32> + ndarray::Data + Sync,
+{
+    let min_dimension = min_dimension.unwrap_or(16);
+    let max_levels = max_levels.unwrap_or(8);
+    let default_settings = LKSettings::default();
+    let settings = settings.unwrap_or(&default_settings);
+    let used: HashSet<usize> = indices.into_iter().collect();
+    used.into_par_iter()
+        .map(|i| {
+            let frame = frames.get(i).ok_or(anyhow!(
+                "Frame {i} is out of bounds for {} frames.",
+                frames.len()
+            ))?;
+            let pyramid = FramePyramid::new(
+                frame,
+                multi,
+                settings.scale,
+                settings.pyramid_filter,
+                (min_dimension, min_dimension),
+                max_levels,
+            )?
+            .with_gradients(settings.gradients)
+            .with_selection(settings.pixel_selection);
+            Ok((i, pyramid))
+        })
+        .collect()
+}
+
+/// Estimate the registration between arbitrary pairs of frames given their pyramids, see
+/// `frame_pyramids` and `register_pairs`. If `multi` is false, only the finest level of every
+/// pyramid is matched, which allows single and multi-scale matching to share the same pyramids.
+/// Apart from the coarse-to-fine schedule, LK settings are those the pyramids were built with.
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+pub fn register_pyramids(
+    pyramids: &HashMap<usize, FramePyramid>,
+    pairs: &[(usize, usize)],
+    init_mappings: &[Mapping],
+    multi: bool,
+    max_iters: Option<u32>,
+    stop_early: Option<f32>,
+    patience: Option<u32>,
+    schedule: Option<&[LevelSettings]>,
+    message: bool,
+) -> Result<HashMap<(usize, usize), (Mapping, HashMap<u32, Vec<Vec<f32>>>)>> {
+    if pairs.len() != init_mappings.len() {
+        return Err(anyhow!(
+            "Expected one initial mapping per pair, got {} mappings for {} pairs.",
+            init_mappings.len(),
+            pairs.len()
+        ));
+    }
+    if let Some((i, j)) = pairs
+        .iter()
+        .find(|(i, j)| !pyramids.contains_key(i) || !pyramids.contains_key(j))
+    {
+        return Err(anyhow!(
+            "No pyramid was given for a frame of pair ({i}, {j})."
+        ));
+    }
+    let mut seen = HashSet::new();
+    if let Some((i, j)) = pairs.iter().find(|pair| !seen.insert(**pair)) {
+        return Err(anyhow!(
+            "Pair ({i}, {j}) is given more than once, results are keyed by pair."
+        ));
+    }
+
     let msg = if message { Some("Matching") } else { None };
-    let pbar = get_pbar(frames.len() - 1, msg);
+    let pbar = get_pbar(pairs.len(), msg);
 
-    // Iterate over sliding window of pairwise frames (in parallel!)
-    let (mappings, hists) = frames
-        .par_windows(2)
+    let results = pairs
+        .par_iter()
         .zip(init_mappings)
-        .map(|(window, init_mapping)| {
-            pbar.inc(1);
-            iclk_array(
-                &window[0],
-                &window[1],
-                init_mapping.clone(),
+        .map(|(&(i, j), init_mapping)| {
+            let result = _iclk_pyramid(
+                &pyramids[&i],
+                &pyramids[&j],
                 None,
-                multi,
+                init_mapping.clone(),
+                if multi { usize::MAX } else { 1 },
                 max_iters,
-                min_dimension,
-                max_levels,
                 stop_early,
                 patience,
+                schedule.unwrap_or_default(),
                 false,
-            )
+            )?;
+            pbar.inc(1);
+            Ok(((i, j), result))
         })
-        // Collect to force reorder
-        .collect::<Result<Vec<_>>>()?
-        .into_iter()
-        .unzip();
+        .collect::<Result<HashMap<_, _>>>()?;
 
-    // Return raw pairwise warps (N-1 in total)
     pbar.finish_and_clear();
-    Ok((mappings, hists))
+    Ok(results)
+}
+
+/// Estimate pairwise registration by matching every frame to a mosaic of all previous frames,
+/// instead of only to the previous frame, which reduces drift when panning slowly.
+///
+/// The mapping of every frame is predicted by composing the mapping of the previous frame with the
+/// initial pairwise mapping (i.e: from a coarser level), and the mosaic is rendered in the predicted
+/// viewport. The frame is then registered to it with `iclk_array` using the mosaic's coverage as
+/// weights, and added to the mosaic. Mappings are of the widest kind among the initial mappings.
+#[allow(clippy::too_many_arguments)]
+pub fn mosaic_iclk<S>(
+    frames: &[ArrayBase<S, Ix3>],
+    init_mappings: &[Mapping],
+    multi: bool,
+    max_iters: Option<u32>,
+    min_dimension: Option<usize>,
+    max_levels: Option<u32>,
+    stop_early: Option<f32>,
+    patience: Option<u32>,
+    settings: Option<&LKSettings>,
+    message: bool,
+) -> Result<Vec<Mapping>>
+where
+    S: RawData<Elem = f32> + ndarray::Data + Sync,
+{
+    let Some(first) = frames.first() else {
+        return Ok(vec![]);
+    };
+    if init_mappings.len() != frames.len() - 1 {
+        return Err(anyhow!(
+            "Expected one initial mapping per pair of consecutive frames, got {} mappings for {} frames.",
+            init_mappings.len(),
+            frames.len()
+        ));
+    }
+    let kind = init_mappings
+        .iter()
+        .map(|m| m.kind)
+        .max_by_key(|k| k.num_params())
+        .unwrap_or(TransformationType::Translational);
+    let (h, w, c) = first.dim();
+    let identity = Mapping::from_params(vec![0.0; kind.num_params()]);
+    let mut mosaic = Mosaic::new(c);
+    mosaic.add(first, &identity);
+
+    let msg = if message {
+        Some("Matching to mosaic")
+    } else {
+        None
+    };
+    let pbar = get_pbar(frames.len().saturating_sub(1), msg);
+    let mut pose = identity.clone();
+    let mut mappings = vec![];
+
+    for (frame, init_mapping) in frames.iter().skip(1).zip(init_mappings) {
+        // Use the initial pairwise mapping to predict where the frame lands on the mosaic
+        let predicted =
+            Mapping::from_matrix(pose.transform(None, Some(init_mapping.clone())).mat, kind);
+        let (viewport, coverage) = mosaic.viewport(&predicted, (w, h));
+        let (residual, _) = iclk_array(
+            &viewport.view(),
+            &frame.view(),
+            identity.clone(),
+            Some(&coverage.view()),
+            multi,
+            max_iters,
+            min_dimension,
+            max_levels,
+            stop_early,
+            patience,
+            settings,
+            false,
+        )?;
+
+        let next = Mapping::from_matrix(predicted.transform(None, Some(residual)).mat, kind);
+        let step =
+            Mapping::from_matrix(pose.inverse().transform(None, Some(next.clone())).mat, kind);
+        mosaic.add(frame, &next);
+        mappings.push(step);
+        pose = next;
+        pbar.inc(1);
+    }
+
+    pbar.finish_and_clear();
+    Ok(mappings)
 }
 
 /// Given an image, return an image pyramid with the largest size first and halving the size
 /// every time until either the max-levels are reached or the minimum size is reached.
+/// This is a box filtered `Pyramid`, see `Pyramid::new` for other filters and scale factors.
 pub fn img_pyramid<S>(
     im: &ArrayBase<S, Ix3>,
     min_dimensions: (usize, usize),
@@ -501,25 +1137,9 @@ pub fn img_pyramid<S>(
 where
     S: RawData<Elem = f32> + ndarray::Data,
 {
-    let mut stack = vec![im.to_owned()];
-    let (min_width, min_height) = min_dimensions;
-    let (mut h, mut w, _c) = im.dim();
-
-    for _ in 0..max_levels {
-        if w >= min_width * 2 && h >= min_height * 2 {
-            (h, w) = (h / 2, w / 2);
-
-            let resized = (stack[stack.len() - 1]
-                .slice(s![0..h*2;2, 0..w*2;2, ..])
-                .to_owned()
-                + stack[stack.len() - 1].slice(s![0..h*2;2, 1..w*2;2, ..])
-                + stack[stack.len() - 1].slice(s![1..h*2;2, 0..w*2;2, ..])
-                + stack[stack.len() - 1].slice(s![1..h*2;2, 1..w*2;2, ..]))
-                / 4.0;
-            stack.push(resized);
-        }
-    }
-    stack
+    Pyramid::new(im, 2.0, PyramidFilter::Box, min_dimensions, max_levels)
+        .expect("Pyramid with a scale factor of two should be valid")
+        .into_levels()
 }
 
 // --------------------------------------------------------------- Python Interface ---------------------------------------------------------------
@@ -575,14 +1195,49 @@ pub fn pyarray_to_im_bridge<T: Element>(im: &Bound<'_, PyAny>) -> PyResult<Array
     Ok(im)
 }
 
+/// Convert a coarse-to-fine schedule given as `(kind, max_iters, stop_early)` tuples, see `iclk_py`.
+fn schedule_from_py(
+    schedule: Option<Vec<(String, Option<u32>, Option<f32>)>>,
+) -> PyResult<Vec<LevelSettings>> {
+    schedule
+        .unwrap_or_default()
+        .iter()
+        .map(|(kind, max_iters, stop_early)| {
+            Ok(LevelSettings {
+                kind: TransformationType::from_str_py(kind)?,
+                max_iters: *max_iters,
+                stop_early: *stop_early,
+            })
+        })
+        .collect()
+}
+
 /// Main iclk routine, which works for an arbitrary number of channels.
 /// This returns the mapping that warps image 2 onto image 1's reference frame.
 /// The param history however, corresponds to the inverse mappings, i.e from 1 to 2.
 ///
-/// Weights can be specified for image 1. They are concatenated to the reference image and
-/// warped together. The warped weights then affect that pixel's loss and is effectively
+/// Weights can be specified for image 1. They are sampled at the same warped locations as
+/// the reference image. The warped weights then affect that pixel's loss and is effectively
 /// discarded from the optimization step if it's zero.
 ///
+/// The objective can be restricted to at most `pixel_budget` informative pixels of image 2 at
+/// every level of the pyramid, as chosen by `pixel_selection`, which is much faster for large
+/// images. All pixels are used if no selection method is given.
+///
+/// Levels of the pyramid are prefiltered with `pyramid_filter` and downscaled by `pyramid_scale`.
+///
+/// Gradients of image 2 are computed with `gradient_operator`, where `gradient_sigma` is the
+/// standard deviation of the derivative of Gaussian operator. Both images can also be smoothed
+/// with a Gaussian kernel of standard deviation `smoothing` before matching, which helps with
+/// noisy images such as low-photon virtual exposures.
+///
+/// A coarse-to-fine schedule can be given as a list of `(kind, max_iters, stop_early)` tuples,
+/// which are applied from the coarsest level of the pyramid onwards, the last one being used for
+/// all remaining levels. The full resolution level always uses the last one, even if the pyramid
+/// has fewer levels than the schedule. For instance, `[("translational", None, None), ("similarity", None, None),
+/// ("projective", None, None)]` avoids unstable projective fits on tiny levels. Unset iteration
+/// and early stopping settings default to `max_iters` and `stop_early`.
+///
 /// Note: No input validation is performed here, im1 and im2 can have different sizes but
 ///     the im2 gradients need to have the same size as im2 and im1 weights should match im1.
 ///
@@ -590,7 +1245,7 @@ pub fn pyarray_to_im_bridge<T: Element>(im: &Bound<'_, PyAny>) -> PyResult<Array
 #[pyfunction]
 #[pyo3(
     name = "iclk",
-    signature = (im1, im2, init_mapping=None, im1_weights=None, multi=true, max_iters=250, min_dimension=16, max_levels=8, stop_early=1e-3, patience=10, message=false)
+    signature = (im1, im2, init_mapping=None, im1_weights=None, multi=true, max_iters=250, min_dimension=16, max_levels=8, stop_early=1e-3, patience=10, message=false, pyramid_filter=PyramidFilter::Box, gradient_operator=GradientOperator::Prewitt, gradient_sigma=1.0, smoothing=None, pixel_selection=None, pixel_budget=4096, schedule=None, pyramid_scale=2.0)
 )]
 #[allow(clippy::type_complexity)]
 #[allow(clippy::too_many_arguments)]
@@ -607,6 +1262,14 @@ pub fn iclk_py<'py>(
     stop_early: f32,
     patience: u32,
     message: bool,
+    pyramid_filter: PyramidFilter,
+    gradient_operator: GradientOperator,
+    gradient_sigma: f32,
+    smoothing: Option<f32>,
+    pixel_selection: Option<PixelSelection>,
+    pixel_budget: usize,
+    schedule: Option<Vec<(String, Option<u32>, Option<f32>)>>,
+    pyramid_scale: f32,
 ) -> Result<(Mapping, HashMap<u32, Vec<Vec<f32>>>)> {
     let _defer = DeferredSignal::new(py, "SIGINT")?;
 
@@ -614,6 +1277,18 @@ pub fn iclk_py<'py>(
     let im2 = pyarray_to_im_bridge(im2)?;
     let weights = im1_weights.map(|a| pyarray_to_im_bridge(a)).transpose()?;
 
+    let settings = LKSettings {
+        pyramid_filter,
+        scale: pyramid_scale,
+        gradients: GradientSettings {
+            operator: gradient_operator,
+            sigma: gradient_sigma,
+            smoothing,
+        },
+        pixel_selection: pixel_selection.map(|method| (method, pixel_budget)),
+        schedule: schedule_from_py(schedule)?,
+    };
+
     iclk_array(
         &im1,
         &im2,
@@ -625,15 +1300,17 @@ pub fn iclk_py<'py>(
         Some(max_levels),
         Some(stop_early),
         Some(patience),
+        Some(&settings),
         message,
     )
 }
 
-/// Estimate pairwise registration using iclk
+/// Estimate pairwise registration using iclk.
+/// A coarse-to-fine `schedule` and `pyramid_scale` can be given as with `iclk`, and are used for every pair.
 #[pyfunction]
 #[pyo3(
     name = "pairwise_iclk",
-    signature = (frames, init_mappings=None, multi=true, max_iters=250, min_dimension=16, max_levels=8, stop_early=1e-3, patience=10, message=false)
+    signature = (frames, init_mappings=None, multi=true, max_iters=250, min_dimension=16, max_levels=8, stop_early=1e-3, patience=10, message=false, pixel_selection=None, pixel_budget=4096, schedule=None, pyramid_scale=2.0)
 )]
 #[allow(clippy::type_complexity)]
 #[allow(clippy::too_many_arguments)]
@@ -648,6 +1325,10 @@ pub fn pairwise_iclk_py<'py>(
     stop_early: f32,
     patience: u32,
     message: bool,
+    pixel_selection: Option<PixelSelection>,
+    pixel_budget: usize,
+    schedule: Option<Vec<(String, Option<u32>, Option<f32>)>>,
+    pyramid_scale: f32,
 ) -> Result<(Vec<Mapping>, Vec<HashMap<u32, Vec<Vec<f32>>>>)> {
     let _defer = DeferredSignal::new(py, "SIGINT")?;
 
@@ -665,31 +1346,177 @@ pub fn pairwise_iclk_py<'py>(
         Some(max_levels),
         Some(stop_early),
         Some(patience),
+        Some(&LKSettings {
+            pixel_selection: pixel_selection.map(|method| (method, pixel_budget)),
+            schedule: schedule_from_py(schedule)?,
+            scale: pyramid_scale,
+            ..Default::default()
+        }),
         message,
     )
 }
 
-/// Given an image, return an image pyramid with the largest size first and halving the size
-/// every time until either the max-levels are reached or the minimum size is reached.
+/// Estimate pairwise registration by matching every frame to a mosaic of previous frames.
+/// If no initial pairwise mappings are given, they default to zero mappings of the given `kind`.
+/// A coarse-to-fine `schedule` and `pyramid_scale` can be given as with `iclk`, and are used to register
+/// every frame to the mosaic, whose mappings are then cast to `kind`. See `mosaic_iclk` for more details.
+#[pyfunction]
+#[pyo3(
+    name = "mosaic_iclk",
+    signature = (frames, init_mappings=None, kind="translational", multi=true, max_iters=250, min_dimension=16, max_levels=8, stop_early=1e-3, patience=10, message=false, pixel_selection=None, pixel_budget=4096, schedule=None, pyramid_scale=2.0)
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn mosaic_iclk_py<'py>(
+    py: Python<'py>,
+    frames: Vec<Bound<'py, PyAny>>,
+    init_mappings: Option<Vec<Mapping>>,
+    kind: &str,
+    multi: bool,
+    max_iters: u32,
+    min_dimension: usize,
+    max_levels: u32,
+    stop_early: f32,
+    patience: u32,
+    message: bool,
+    pixel_selection: Option<PixelSelection>,
+    pixel_budget: usize,
+    schedule: Option<Vec<(String, Option<u32>, Option<f32>)>>,
+    pyramid_scale: f32,
+) -> Result<Vec<Mapping>> {
+    let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+    let frames: Vec<Array3<f32>> = frames
+        .iter()
+        .map(pyarray_to_im_bridge::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let kind = TransformationType::from_str_py(kind)?;
+    let init_mappings =
+        init_mappings.unwrap_or(vec![
+            Mapping::from_params(vec![0.0; kind.num_params()]);
+            frames.len().saturating_sub(1)
+        ]);
+
+    mosaic_iclk(
+        &frames,
+        &init_mappings,
+        multi,
+        Some(max_iters),
+        Some(min_dimension),
+        Some(max_levels),
+        Some(stop_early),
+        Some(patience),
+        Some(&LKSettings {
+            pixel_selection: pixel_selection.map(|method| (method, pixel_budget)),
+            schedule: schedule_from_py(schedule)?,
+            scale: pyramid_scale,
+            ..Default::default()
+        }),
+        message,
+    )
+}
+
+/// Estimate the registration between arbitrary pairs of frames using iclk, in parallel.
+/// A coarse-to-fine `schedule` and `pyramid_scale` can be given as with `iclk`, and are used for every pair.
+/// See `register_pairs` for more details.
+#[pyfunction]
+#[pyo3(
+    name = "register_pairs",
+    signature = (frames, pairs, init_mappings=None, multi=true, max_iters=250, min_dimension=16, max_levels=8, stop_early=1e-3, patience=10, message=false, pixel_selection=None, pixel_budget=4096, schedule=None, pyramid_scale=2.0)
+)]
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+pub fn register_pairs_py<'py>(
+    py: Python<'py>,
+    frames: Vec<Bound<'py, PyAny>>,
+    pairs: Vec<(usize, usize)>,
+    init_mappings: Option<Vec<Mapping>>,
+    multi: bool,
+    max_iters: u32,
+    min_dimension: usize,
+    max_levels: u32,
+    stop_early: f32,
+    patience: u32,
+    message: bool,
+    pixel_selection: Option<PixelSelection>,
+    pixel_budget: usize,
+    schedule: Option<Vec<(String, Option<u32>, Option<f32>)>>,
+    pyramid_scale: f32,
+) -> Result<HashMap<(usize, usize), (Mapping, HashMap<u32, Vec<Vec<f32>>>)>> {
+    let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+    let frames: Vec<Array3<f32>> = frames
+        .iter()
+        .map(pyarray_to_im_bridge::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    register_pairs(
+        &frames,
+        &pairs,
+        &init_mappings.unwrap_or(vec![Mapping::from_params(vec![0.0; 8]); pairs.len()]),
+        multi,
+        Some(max_iters),
+        Some(min_dimension),
+        Some(max_levels),
+        Some(stop_early),
+        Some(patience),
+        Some(&LKSettings {
+            pixel_selection: pixel_selection.map(|method| (method, pixel_budget)),
+            schedule: schedule_from_py(schedule)?,
+            scale: pyramid_scale,
+            ..Default::default()
+        }),
+        message,
+    )
+}
+
+/// Given an image, return an image pyramid with the largest size first and downscaling by
+/// `scale` every time until either the max-levels are reached or the minimum size is reached.
+/// Levels are prefiltered with `filter`, see `Pyramid` for more details.
+///
+/// The `kind` of pyramid can be "image", in which case the levels are returned as is,
+/// "laplacian", which returns band-pass levels followed by the smallest level, or "gradient",
+/// which returns the (dx, dy) gradients of every level computed with `gradient_operator`, where
+/// `gradient_sigma` is the standard deviation of the derivative of Gaussian operator.
 #[pyfunction]
 #[pyo3(
     name = "img_pyramid",
-    signature = (im, min_dimension=16, max_levels=8),
+    signature = (im, min_dimension=16, max_levels=8, scale=2.0, filter=PyramidFilter::Box, kind="image", gradient_operator=GradientOperator::Prewitt, gradient_sigma=1.0),
 )]
+#[allow(clippy::too_many_arguments)]
 pub fn img_pyramid_py<'py>(
     py: Python<'py>,
     im: &Bound<'py, PyAny>,
     min_dimension: usize,
     max_levels: u32,
-) -> PyResult<Vec<Py<PyAny>>> {
+    scale: f32,
+    filter: PyramidFilter,
+    kind: &str,
+    gradient_operator: GradientOperator,
+    gradient_sigma: f32,
+) -> Result<Vec<Py<PyAny>>> {
     let _defer = DeferredSignal::new(py, "SIGINT")?;
 
-    Ok(img_pyramid(
-        &pyarray_to_im_bridge(im)?,
+    let im = pyarray_to_im_bridge(im)?;
+    let pyramid = Pyramid::new(
+        &im,
+        scale,
+        filter,
         (min_dimension, min_dimension),
         max_levels,
-    )
-    .iter()
-    .map(|a| a.to_pyarray_bound(py).to_owned().into_py(py))
-    .collect())
+    )?;
+    let to_py = |a: &Array3<f32>| a.to_pyarray_bound(py).to_owned().into_py(py);
+
+    match kind.to_lowercase().as_str() {
+        "image" => Ok(pyramid.into_levels().iter().map(to_py).collect()),
+        "laplacian" => Ok(pyramid.laplacian().iter().map(to_py).collect()),
+        "gradient" => Ok(pyramid
+            .gradients(gradient_operator, gradient_sigma)
+            .iter()
+            .map(|(dx, dy)| (to_py(dx), to_py(dy)).into_py(py))
+            .collect()),
+        _ => Err(anyhow!(
+            "Invalid pyramid kind: Expected one of image, laplacian or gradient, got {kind}."
+        )),
+    }
 }
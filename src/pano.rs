@@ -0,0 +1,2599 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, read_to_string, rename, write, File},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use ffmpeg_sidecar::command::FfmpegCommand;
+use glob::glob;
+use image::{
+    imageops::{resize, FilterType},
+    io::Reader as ImageReader,
+    GrayImage, ImageBuffer, Luma, Rgb,
+};
+use imageproc::definitions::Clamp;
+use indicatif::ParallelProgressIterator;
+use ndarray::{
+    arr0, azip, s, stack, Array1, Array2, Array3, ArrayView3, Axis, Ix0, OwnedRepr, Slice,
+};
+use ndarray_npy::{NpzReader, NpzWriter};
+use numpy::ToPyArray;
+use photoncube2video::{
+    cube::PhotonCube,
+    ffmpeg::ensure_ffmpeg,
+    signals::DeferredSignal,
+    transforms::{
+        apply_transforms, array2_to_grayimage, array3_to_image, image_to_array3,
+        interpolate_where_mask, process_colorspad, unpack_single, Transform,
+    },
+};
+use pyo3::{prelude::*, types::PyDict};
+use rayon::{
+    iter::{
+        IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+    },
+    slice::ParallelSlice,
+};
+use serde_json::{json, Value};
+use strum::VariantArray;
+use tempfile::{tempdir, TempDir};
+
+use crate::{
+    blend::{
+        drizzle_images, gain_compensation, linear_to_srgb, merge_arrays_iter,
+        merge_arrays_with_layers_iter, merge_images, merge_photon_counts, splat_bitplanes,
+        srgb_to_linear, MergeLayers, PhotonCounts,
+    },
+    bundle::{global_descriptor, registration_confidence, PoseEdge, PoseGraph},
+    cli::{Cli, PanoArgs},
+    demosaic::{demosaic, load_cfa, DemosaicMethod},
+    lk::{
+        frame_pyramids, mosaic_iclk, pairwise_iclk, register_pyramids, FramePyramid,
+        GradientOperator, GradientSettings, LKSettings, PixelSelection,
+    },
+    pyramid::PyramidFilter,
+    utils::get_pbar,
+    warps::{Mapping, TransformationType},
+};
+
+/// Number of Gauss-Newton iterations used by bundle adjustment.
+const BUNDLE_ADJUST_ITERATIONS: u32 = 20;
+
+/// Residual (in pixels) above which edges are down-weighted during bundle adjustment.
+const BUNDLE_ADJUST_HUBER: f32 = 2.0;
+
+/// Size of the longest side of the thumbnails used to estimate gains, see `Pano::estimate_gains`.
+const GAIN_THUMBNAIL_SIZE: u32 = 256;
+
+/// Minimum number of virtual exposures between the two ends of a loop closure.
+const LOOP_CLOSURE_MIN_GAP: usize = 8;
+
+/// Minimum predicted overlap or descriptor similarity of loop closure candidates.
+const LOOP_CLOSURE_MIN_SCORE: f32 = 0.5;
+
+/// Minimum registration confidence for a loop closure to be used.
+const LOOP_CLOSURE_MIN_CONFIDENCE: f32 = 0.5;
+
+/// Configuration of the panorama pipeline. Frames of a photoncube are first averaged in groups of
+/// `granularity` bitplanes, then virtual exposures of `burst_size` bitplanes are matched pairwise in
+/// a coarse-to-fine manner, and the resulting mappings are interpolated to every granular frame.
+///
+/// Conventional frames (an image sequence or a video) can be used instead of a photoncube, in which
+/// case `burst_size`, `granularity`, and the range are counted in frames instead of bitplanes.
+///
+/// Use the setters to change the defaults (which match those of the CLI), then call `build`
+/// to open a source of frames and `Pano::register` to estimate mappings.
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct PanoBuilder {
+    burst_size: usize,
+    granularity: usize,
+    step: usize,
+    wrt: f32,
+    start: Option<usize>,
+    end: Option<isize>,
+    max_lvls: u32,
+    min_size: usize,
+    downscale: f32,
+    iterations: u32,
+    early_stop: f32,
+    patience: u32,
+    lk: LKSettings,
+    transforms: Vec<Transform>,
+    bitpacked: bool,
+    colorspad_fix: bool,
+    cfa_path: Option<PathBuf>,
+    inpaint_paths: Vec<PathBuf>,
+    color: bool,
+    demosaic: DemosaicMethod,
+    window: usize,
+    checkpoint_dir: Option<PathBuf>,
+    resume: bool,
+    start_level: Option<u32>,
+    init_mappings: Option<Vec<Mapping>>,
+    bundle_adjust: Option<usize>,
+    loop_closure: bool,
+    mosaic: bool,
+    blend_mask: Option<PathBuf>,
+    gain_compensation: bool,
+    layers: bool,
+    upscale: f32,
+    pixfrac: f32,
+    invert_response: bool,
+    dark_count: Option<f32>,
+    bitplane_exact: bool,
+    tonemap2srgb: bool,
+}
+
+impl Default for PanoBuilder {
+    fn default() -> Self {
+        Self {
+            burst_size: 256,
+            granularity: 8,
+            step: 1,
+            wrt: 0.5,
+            start: None,
+            end: None,
+            max_lvls: 8,
+            min_size: 16,
+            downscale: 1.0,
+            iterations: 250,
+            early_stop: 1e-3,
+            patience: 10,
+            lk: LKSettings::default(),
+            transforms: vec![],
+            bitpacked: true,
+            colorspad_fix: false,
+            cfa_path: None,
+            inpaint_paths: vec![],
+            color: false,
+            demosaic: DemosaicMethod::Bilinear,
+            window: 512,
+            checkpoint_dir: None,
+            resume: false,
+            start_level: None,
+            init_mappings: None,
+            bundle_adjust: None,
+            loop_closure: false,
+            mosaic: false,
+            blend_mask: None,
+            gain_compensation: false,
+            layers: false,
+            upscale: 1.0,
+            pixfrac: 1.0,
+            invert_response: false,
+            dark_count: None,
+            bitplane_exact: false,
+            tonemap2srgb: false,
+        }
+    }
+}
+
+// Note: Methods in this `impl` block are _not_ exposed to python
+impl PanoBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a builder from the CLI's global and pano-specific arguments.
+    pub fn from_args(args: &Cli, pano_args: &PanoArgs) -> Self {
+        Self::new()
+            .burst_size(pano_args.burst_size)
+            .granularity(pano_args.granularity)
+            .step(pano_args.step)
+            .wrt(pano_args.wrt)
+            .range(pano_args.start, pano_args.end)
+            .levels(pano_args.lk_args.max_lvls, pano_args.lk_args.min_size)
+            .downscale(pano_args.lk_args.downscale)
+            .lk_settings(
+                pano_args.lk_args.iterations,
+                pano_args.lk_args.early_stop,
+                pano_args.lk_args.patience,
+            )
+            .pixel_selection(
+                pano_args
+                    .lk_args
+                    .pixel_selection
+                    .map(|method| (method, pano_args.lk_args.pixel_budget)),
+            )
+            .pyramid_filter(pano_args.lk_args.pyramid_filter)
+            .pyramid_scale(pano_args.lk_args.pyramid_scale)
+            .gradients(GradientSettings {
+                operator: pano_args.lk_args.gradient_operator,
+                sigma: pano_args.lk_args.gradient_sigma,
+                smoothing: pano_args.lk_args.smoothing,
+            })
+            .transforms(args.transform.clone())
+            .bitpacked(!pano_args.not_bitpacked)
+            .colorspad_fix(pano_args.colorspad_fix)
+            .cfa_path(pano_args.cfa_path.clone())
+            .inpaint_paths(pano_args.inpaint_path.clone())
+            .color(pano_args.color)
+            .demosaic(pano_args.demosaic)
+            .window(pano_args.window)
+            .checkpoint_dir(pano_args.checkpoint_dir.clone())
+            .resume(pano_args.resume)
+            .bundle_adjust(pano_args.bundle_adjust)
+            .loop_closure(pano_args.loop_closure)
+            .mosaic(pano_args.mosaic)
+            .blend_mask(pano_args.blend_mask.clone())
+            .gain_compensation(pano_args.gain_compensation)
+            .layers(pano_args.layers_dir.is_some())
+            .drizzle(pano_args.upscale, pano_args.pixfrac)
+            .invert_response(pano_args.invert_response, pano_args.dark_count)
+            .bitplane_exact(pano_args.bitplane_exact)
+            .tonemap2srgb(pano_args.tonemap2srgb)
+    }
+
+    /// Number of bitplanes that are averaged together to form a virtual exposure.
+    pub fn burst_size(mut self, burst_size: usize) -> Self {
+        self.burst_size = burst_size;
+        self
+    }
+
+    /// Number of consecutive bitplanes that are merged together with an identity transform.
+    pub fn granularity(mut self, granularity: usize) -> Self {
+        self.granularity = granularity;
+        self
+    }
+
+    /// Only match every `step` virtual exposure to the next.
+    pub fn step(mut self, step: usize) -> Self {
+        self.step = step;
+        self
+    }
+
+    /// Normalized index (i.e [0, 1]) of the frame with identity warp.
+    pub fn wrt(mut self, wrt: f32) -> Self {
+        self.wrt = wrt;
+        self
+    }
+
+    /// Range of bitplanes to use, start is inclusive and end is exclusive.
+    pub fn range(mut self, start: Option<usize>, end: Option<isize>) -> Self {
+        self.start = start;
+        self.end = end;
+        self
+    }
+
+    /// Maximum number of pyramid levels, and minimum edge length of the smallest one.
+    pub fn levels(mut self, max_lvls: u32, min_size: usize) -> Self {
+        self.max_lvls = max_lvls;
+        self.min_size = min_size;
+        self
+    }
+
+    /// Downscale frames by this factor before any processing.
+    pub fn downscale(mut self, downscale: f32) -> Self {
+        self.downscale = downscale;
+        self
+    }
+
+    /// Maximum number of LK iterations, and early stopping criteria, see `iclk`.
+    pub fn lk_settings(mut self, iterations: u32, early_stop: f32, patience: u32) -> Self {
+        self.iterations = iterations;
+        self.early_stop = early_stop;
+        self.patience = patience;
+        self
+    }
+
+    /// Restrict LK templates to a budget of informative pixels at every level, see `lk::PixelSelection`.
+    pub fn pixel_selection(mut self, pixel_selection: Option<(PixelSelection, usize)>) -> Self {
+        self.lk.pixel_selection = pixel_selection;
+        self
+    }
+
+    /// Filter used to downscale frames when building pyramids, which are only used by multi-scale
+    /// matching (i.e: in mosaic mode and for loop closures), see `pyramid::PyramidFilter`.
+    pub fn pyramid_filter(mut self, pyramid_filter: PyramidFilter) -> Self {
+        self.lk.pyramid_filter = pyramid_filter;
+        self
+    }
+
+    /// Factor by which frames are downscaled at every level of the pyramids used by multi-scale
+    /// matching (i.e: in mosaic mode and for loop closures).
+    pub fn pyramid_scale(mut self, pyramid_scale: f32) -> Self {
+        self.lk.scale = pyramid_scale;
+        self
+    }
+
+    /// How LK gradients are computed, and whether virtual exposures are smoothed before matching,
+    /// see `lk::GradientSettings`.
+    pub fn gradients(mut self, gradients: GradientSettings) -> Self {
+        self.lk.gradients = gradients;
+        self
+    }
+
+    /// Transforms (i.e: flip-ud) applied to every frame after downscaling.
+    pub fn transforms(mut self, transforms: Vec<Transform>) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Whether the photoncube is bitpacked along the width dimension.
+    pub fn bitpacked(mut self, bitpacked: bool) -> Self {
+        self.bitpacked = bitpacked;
+        self
+    }
+
+    /// Whether to swap columns that are out of order and crop to 254x496 (for ColorSPAD data).
+    pub fn colorspad_fix(mut self, colorspad_fix: bool) -> Self {
+        self.colorspad_fix = colorspad_fix;
+        self
+    }
+
+    /// Path of color filter array to use for demosaicing.
+    pub fn cfa_path(mut self, cfa_path: Option<PathBuf>) -> Self {
+        self.cfa_path = cfa_path;
+        self
+    }
+
+    /// Paths of inpainting masks used to filter out dead/hot pixels.
+    pub fn inpaint_paths(mut self, inpaint_paths: Vec<PathBuf>) -> Self {
+        self.inpaint_paths = inpaint_paths;
+        self
+    }
+
+    /// Whether to also load color frames, see `Pano::load_color_frames`. This requires a color filter
+    /// array for photoncubes. Matching is always done on grayscale frames.
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Demosaicing method used for color frames of photoncubes.
+    pub fn demosaic(mut self, demosaic: DemosaicMethod) -> Self {
+        self.demosaic = demosaic;
+        self
+    }
+
+    /// Maximum number of granular frames that are loaded at once when merging.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Directory in which the pairwise mappings of every level are saved as they are estimated.
+    pub fn checkpoint_dir(mut self, checkpoint_dir: Option<PathBuf>) -> Self {
+        self.checkpoint_dir = checkpoint_dir;
+        self
+    }
+
+    /// Whether to skip levels that have already been completed, as found in the checkpoint directory.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Start matching from the given level (starting at one for the coarsest), using the provided
+    /// full resolution pairwise mappings as initialization instead of those of the previous level.
+    pub fn start_from(mut self, level: Option<u32>, mappings: Option<Vec<Mapping>>) -> Self {
+        self.start_level = level;
+        self.init_mappings = mappings;
+        self
+    }
+
+    /// If provided, refine the pairwise mappings with a global bundle adjustment once all levels
+    /// are matched. Additional pairs of virtual exposures up to `span` apart are registered.
+    pub fn bundle_adjust(mut self, span: Option<usize>) -> Self {
+        self.bundle_adjust = span;
+        self
+    }
+
+    /// If enabled, detect when the camera revisits a part of the scene once all levels are matched,
+    /// and register these loop closures to jointly refine mappings, see `Pano::bundle_adjust`.
+    pub fn loop_closure(mut self, loop_closure: bool) -> Self {
+        self.loop_closure = loop_closure;
+        self
+    }
+
+    /// If enabled, virtual exposures are registered to a mosaic of all previous ones at the finest
+    /// level, instead of only to the previous one, see `lk::mosaic_iclk`. Frames are placed on the mosaic
+    /// using the pairwise mappings of the previous level.
+    pub fn mosaic(mut self, mosaic: bool) -> Self {
+        self.mosaic = mosaic;
+        self
+    }
+
+    /// Path of a blending mask used when merging the panorama, with the same size as the frames before
+    /// any downscaling or transforms. White areas are kept, black ones are ignored, and intermediate
+    /// values reduce a pixel's contribution.
+    pub fn blend_mask(mut self, blend_mask: Option<PathBuf>) -> Self {
+        self.blend_mask = blend_mask;
+        self
+    }
+
+    /// Whether to equalize the brightness of overlapping granular frames before merging,
+    /// see `Pano::estimate_gains`.
+    pub fn gain_compensation(mut self, gain_compensation: bool) -> Self {
+        self.gain_compensation = gain_compensation;
+        self
+    }
+
+    /// Whether to compute auxiliary layers when rendering the panorama, see `Pano::render`.
+    pub fn layers(mut self, layers: bool) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    /// Upscale the panorama by `upscale` using drizzle merging with drops of relative size `pixfrac`,
+    /// see `drizzle_arrays`. No drizzling is done if `upscale` is one.
+    pub fn drizzle(mut self, upscale: f32, pixfrac: f32) -> Self {
+        self.upscale = upscale;
+        self.pixfrac = pixfrac;
+        self
+    }
+
+    /// Whether to merge photon counts and estimate the flux instead of blending frames, see
+    /// `Pano::photon_counts`, with an optional dark count rate that is removed from the estimate.
+    pub fn invert_response(mut self, invert_response: bool, dark_count: Option<f32>) -> Self {
+        self.invert_response = invert_response;
+        self.dark_count = dark_count;
+        self
+    }
+
+    /// Whether to splat every bitplane individually onto the panorama, see `Pano::splat`.
+    pub fn bitplane_exact(mut self, bitplane_exact: bool) -> Self {
+        self.bitplane_exact = bitplane_exact;
+        self
+    }
+
+    /// Whether to apply sRGB tonemapping to the rendered panorama.
+    pub fn tonemap2srgb(mut self, tonemap2srgb: bool) -> Self {
+        self.tonemap2srgb = tonemap2srgb;
+        self
+    }
+
+    /// Check that the configuration is consistent.
+    pub fn validate(&self) -> Result<()> {
+        if self.granularity == 0 || self.step == 0 || self.window == 0 {
+            return Err(anyhow!(
+                "Arguments `granularity`, `step` and `window` must be non-zero."
+            ));
+        }
+        if self.burst_size <= self.granularity {
+            return Err(anyhow!(
+                "Argument `granularity` must be smaller than `burst-size`."
+            ));
+        }
+        if self.burst_size % self.granularity != 0 {
+            return Err(anyhow!(
+                "Argument `granularity` must evenly divide `burst-size`."
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.wrt) {
+            return Err(anyhow!("Argument `wrt` must be between 0.0 and 1.0."));
+        }
+        if self.downscale <= 0.0 {
+            return Err(anyhow!("Argument `downscale` must be positive."));
+        }
+        if self.lk.scale.is_nan() || self.lk.scale <= 1.0 {
+            return Err(anyhow!("Argument `pyramid-scale` must be larger than one."));
+        }
+        if self.resume && self.checkpoint_dir.is_none() {
+            return Err(anyhow!(
+                "A checkpoint directory is required to resume from."
+            ));
+        }
+        if self.resume && self.start_level.is_some() {
+            return Err(anyhow!("Cannot both resume and start from a given level."));
+        }
+        if self.start_level.is_some() != self.init_mappings.is_some() {
+            return Err(anyhow!(
+                "Both a starting level and initial mappings are needed to start from a given level."
+            ));
+        }
+        if self.bundle_adjust.is_some_and(|span| span < 2) {
+            return Err(anyhow!(
+                "Bundle adjustment needs a span of at least two virtual exposures."
+            ));
+        }
+        if self.upscale <= 0.0 || self.pixfrac <= 0.0 {
+            return Err(anyhow!(
+                "Arguments `upscale` and `pixfrac` must be positive."
+            ));
+        }
+        if self.upscale != 1.0 && self.layers {
+            return Err(anyhow!("Layers cannot be computed when upscaling."));
+        }
+        if self.invert_response
+            && (!self.transforms.is_empty()
+                || self.colorspad_fix
+                || self.cfa_path.is_some()
+                || self.downscale != 1.0
+                || self.upscale != 1.0
+                || self.layers
+                || self.gain_compensation)
+        {
+            return Err(anyhow!(
+                "Argument `invert-response` cannot be used with transforms, colorspad fixes, demosaicing, \
+                downscaling, upscaling, layers or gain compensation."
+            ));
+        }
+        if self.color
+            && (self.invert_response
+                || self.bitplane_exact
+                || self.upscale != 1.0
+                || self.layers
+                || self.gain_compensation)
+        {
+            return Err(anyhow!(
+                "Argument `color` cannot be used with `invert-response`, `bitplane-exact`, upscaling, \
+                layers or gain compensation."
+            ));
+        }
+        if self.bitplane_exact
+            && (!self.transforms.is_empty()
+                || self.colorspad_fix
+                || self.cfa_path.is_some()
+                || self.upscale != 1.0
+                || self.layers
+                || self.gain_compensation)
+        {
+            return Err(anyhow!(
+                "Argument `bitplane-exact` cannot be used with transforms, colorspad fixes, demosaicing, \
+                upscaling, layers or gain compensation."
+            ));
+        }
+        Ok(())
+    }
+
+    /// Open photoncube and load any color filter array or inpainting masks.
+    pub fn open_cube<P: AsRef<Path>>(&self, path: P) -> Result<PhotonCube> {
+        let mut cube = PhotonCube::open(path.as_ref())?;
+        if let Some(cfa_path) = &self.cfa_path {
+            cube.load_cfa(cfa_path.to_path_buf())?;
+        }
+        for inpaint_path in self.inpaint_paths.iter() {
+            cube.load_mask(inpaint_path.to_path_buf())?;
+        }
+        Ok(cube)
+    }
+
+    /// Open a source of frames, which can either be a photoncube (`.npy` file), a directory or glob
+    /// of PNG/TIFF images, or any video file that ffmpeg can decode.
+    pub fn open_source<P: AsRef<Path>>(&self, path: P) -> Result<PanoSource> {
+        let path = path.as_ref();
+        let is_glob = path.to_string_lossy().contains(['*', '?', '[']);
+
+        if path.extension().is_some_and(|ext| ext == "npy") && !is_glob {
+            if self.color && self.cfa_path.is_none() {
+                return Err(anyhow!(
+                    "Color panoramas from photoncubes require a color filter array."
+                ));
+            }
+            return Ok(PanoSource::Cube(self.open_cube(path)?));
+        }
+        if self.colorspad_fix || self.cfa_path.is_some() || !self.inpaint_paths.is_empty() {
+            return Err(anyhow!(
+                "ColorSPAD fixes, demosaicing and inpainting are only supported for photoncubes."
+            ));
+        }
+
+        let (paths, tmp_dir) = if path.is_dir() {
+            (list_images(path.join("*"))?, None)
+        } else if is_glob {
+            (list_images(path)?, None)
+        } else if path.is_file() {
+            let (paths, tmp_dir) = decode_video(path)?;
+            (paths, Some(tmp_dir))
+        } else {
+            return Err(anyhow!("Input {} does not exist.", path.display()));
+        };
+
+        if paths.is_empty() {
+            return Err(anyhow!("No frames found at {}.", path.display()));
+        }
+        Ok(PanoSource::Sequence {
+            paths,
+            _tmp_dir: tmp_dir,
+        })
+    }
+
+    /// Range of frames to use out of `len` frames, negative ends are counted from the back.
+    pub fn bounds(&self, len: usize) -> Range<usize> {
+        let start = self.start.unwrap_or(0).min(len);
+        let end = match self.end {
+            None => len,
+            Some(end) if end < 0 => len.saturating_sub(end.unsigned_abs()),
+            Some(end) => (end as usize).min(len),
+        };
+        start..end.max(start)
+    }
+
+    /// Restrict view of photoncube to the configured range of bitplanes.
+    pub fn slice<'a>(&self, view: &ArrayView3<'a, u8>) -> ArrayView3<'a, u8> {
+        let bounds = self.bounds(view.len_of(Axis(0)));
+        view.clone().slice_axis_move(Axis(0), Slice::from(bounds))
+    }
+
+    /// Open the source of frames and determine the number of levels and virtual exposures.
+    /// No frames are loaded here, instead they are read from the memory-mapped photoncube
+    /// (or from the image files) on demand, see `Pano::load_frames`.
+    pub fn build<P: AsRef<Path>>(&self, path: P) -> Result<Pano> {
+        self.validate()?;
+        let source = self.open_source(&path)?;
+        if matches!(source, PanoSource::Sequence { .. })
+            && (self.invert_response || self.bitplane_exact)
+        {
+            return Err(anyhow!(
+                "Arguments `invert-response` and `bitplane-exact` can only be used with photoncubes."
+            ));
+        }
+        let (num_bitplanes, h, w) = match &source {
+            PanoSource::Cube(cube) => {
+                let view = cube.view()?;
+                let (num_bitplanes, h, w) = self.slice(&view).dim();
+                (num_bitplanes, h, if self.bitpacked { w * 8 } else { w })
+            }
+            PanoSource::Sequence { paths, .. } => {
+                let (w, h) = image::image_dimensions(&paths[0])?;
+                (self.bounds(paths.len()).len(), h as usize, w as usize)
+            }
+        };
+
+        let (lvls_h, lvls_w) = (
+            f32::log2(h as f32 / self.downscale / self.min_size as f32).ceil(),
+            f32::log2(w as f32 / self.downscale / self.min_size as f32).ceil(),
+        );
+        let num_lvls = (lvls_h as u32).min(lvls_w as u32).min(self.max_lvls);
+
+        let num_ves = (num_bitplanes / self.burst_size) / self.step;
+        if num_ves < 2 {
+            return Err(anyhow!(
+                "At least two virtual exposures are needed, got {num_ves}."
+            ));
+        }
+
+        let cfa_channels = match (&self.cfa_path, &source) {
+            (Some(cfa_path), PanoSource::Cube(_)) => Some(load_cfa(cfa_path)?),
+            _ => None,
+        };
+
+        let mut pano = Pano {
+            config: self.clone(),
+            path: path.as_ref().to_path_buf(),
+            source,
+            cfa_channels,
+            num_lvls,
+            num_ves,
+            num_bitplanes,
+            full_size: (h, w),
+            frame_size: (0, 0),
+            blend_mask: None,
+        };
+
+        // Size of frames after downscaling and transforms, which might crop or transpose them
+        let [frame] = &pano.load_frames(0..1)?[..] else {
+            unreachable!("Source should have at least one frame")
+        };
+        pano.frame_size = frame.dimensions();
+
+        // Load blending mask and apply the same downscaling and transforms as the frames
+        pano.blend_mask = self
+            .blend_mask
+            .as_ref()
+            .map(|path| pano.load_mask(path))
+            .transpose()?;
+        Ok(pano)
+    }
+}
+
+/// Source of the frames of a panorama, see `PanoBuilder::open_source`.
+pub enum PanoSource {
+    /// Memory-mapped photoncube, with any color filter array or inpainting masks loaded.
+    Cube(PhotonCube),
+    /// Paths of conventional frames, in order. If the frames were decoded from a video,
+    /// they live in a temporary directory which is deleted when the source is dropped.
+    Sequence {
+        paths: Vec<PathBuf>,
+        _tmp_dir: Option<TempDir>,
+    },
+}
+
+/// List all PNG and TIFF images that match a glob pattern, sorted by path.
+fn list_images<P: AsRef<Path>>(pattern: P) -> Result<Vec<PathBuf>> {
+    let pattern = pattern.as_ref();
+    let mut paths = glob(
+        pattern
+            .to_str()
+            .ok_or(anyhow!("Invalid path {}.", pattern.display()))?,
+    )?
+    .filter_map(|entry| entry.ok())
+    .filter(|path| {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ["png", "tif", "tiff"].contains(&ext.to_lowercase().as_str()))
+    })
+    .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Decode all frames of a video into a temporary directory using ffmpeg.
+/// Returns the paths of the decoded frames, in order, and the directory holding them.
+fn decode_video(path: &Path) -> Result<(Vec<PathBuf>, TempDir)> {
+    ensure_ffmpeg(true);
+    let tmp_dir = tempdir()?;
+    let pattern = tmp_dir.path().join("frame%06d.png");
+
+    let mut child = FfmpegCommand::new()
+        .hide_banner()
+        .input(
+            path.to_str()
+                .ok_or(anyhow!("Invalid path {}.", path.display()))?,
+        )
+        // Do not drop or duplicate frames
+        .args(["-vsync", "0"])
+        .output(pattern.to_str().expect("Temporary path should be valid"))
+        .spawn()?;
+    // Consume ffmpeg's output so it does not block on a full pipe
+    child.iter()?.for_each(drop);
+    if !child.wait()?.success() {
+        return Err(anyhow!("Could not decode video {}.", path.display()));
+    }
+
+    let paths = list_images(tmp_dir.path().join("frame*.png"))?;
+    Ok((paths, tmp_dir))
+}
+
+/// Load a conventional frame as a grayscale array of linear intensities in [0, 1].
+/// Frames are assumed to be sRGB encoded, and are linearized before being converted to luminance
+/// (with the same Rec. 709 weights as the `image` crate), such that they match photoncube frames.
+fn load_gray(path: &Path) -> Result<Array2<f32>> {
+    let rgb = load_rgb(path)?;
+    Ok(rgb.map_axis(Axis(2), |px| {
+        0.2126 * px[0] + 0.7152 * px[1] + 0.0722 * px[2]
+    }))
+}
+
+/// Load a conventional frame as a (h, w, 3) array of linear RGB intensities in [0, 1].
+/// Frames are assumed to be sRGB encoded.
+fn load_rgb(path: &Path) -> Result<Array3<f32>> {
+    let img = ImageReader::open(path)?.decode()?.into_rgb32f();
+    let (w, h) = img.dimensions();
+    Ok(Array3::from_shape_vec((h as usize, w as usize, 3), img.into_raw())?.mapv(srgb_to_linear))
+}
+
+/// Load pairwise mappings from a JSON file, which either contains a list of mappings
+/// or an object with a "pairwise" list of mappings. See `Mapping::to_json` for their format.
+pub fn load_pairwise_mappings<P: AsRef<Path>>(path: P) -> Result<Vec<Mapping>> {
+    let value: Value = serde_json::from_str(&read_to_string(path)?)?;
+    let mappings = if value.is_array() {
+        &value
+    } else {
+        &value["pairwise"]
+    };
+    mappings
+        .as_array()
+        .ok_or(anyhow!("Expected a list of mappings."))?
+        .iter()
+        .map(Mapping::from_json)
+        .collect()
+}
+
+/// Mappings estimated by `Pano::register`.
+#[derive(Debug, Clone)]
+pub struct PanoMappings {
+    /// Pairwise mappings between virtual exposures estimated at every level, coarsest first.
+    /// These are relative to the resolution of their level.
+    pub levels: Vec<Vec<Mapping>>,
+    /// Level number of the first entry in `levels`, starting at one for the coarsest level.
+    pub start_level: u32,
+    /// Final pairwise mappings between virtual exposures, at full resolution.
+    pub pairwise: Vec<Mapping>,
+    /// Mappings interpolated to every granular frame, relative to the `wrt` frame.
+    pub interpolated: Vec<Mapping>,
+}
+
+impl PanoMappings {
+    /// Save mappings to a JSON or NPZ file (depending on the extension), along with the accumulated
+    /// mappings of every virtual exposure, the index of the first bitplane and the timestamp
+    /// (center, in bitplanes) of every virtual exposure and granular frame, and the configuration hash.
+    ///
+    /// Note: In NPZ files, matrices are stacked into (N, 3, 3) arrays and the transformation types
+    ///     are saved separately as their index into `TransformationType.variants()`.
+    pub fn save<P: AsRef<Path>>(&self, pano: &Pano, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let accumulated = Mapping::accumulate_wrt_idx(self.pairwise.clone(), pano.config.wrt);
+        let (ve_indices, ve_timestamps) = pano.ve_timing();
+        let (frame_indices, frame_timestamps) = pano.frame_timing();
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => {
+                let to_json =
+                    |maps: &[Mapping]| maps.iter().map(|m| m.to_json()).collect::<Vec<_>>();
+                let value = json!({
+                    "config_hash": pano.config_hash(),
+                    "num_lvls": pano.num_lvls,
+                    "start_level": self.start_level,
+                    "levels": self.levels.iter().map(|l| to_json(l)).collect::<Vec<_>>(),
+                    "pairwise": to_json(&self.pairwise),
+                    "accumulated": to_json(&accumulated),
+                    "interpolated": to_json(&self.interpolated),
+                    "ve_indices": ve_indices,
+                    "ve_timestamps": ve_timestamps,
+                    "frame_indices": frame_indices,
+                    "frame_timestamps": frame_timestamps,
+                });
+                write(path, serde_json::to_string_pretty(&value)?)?;
+            }
+            Some("npz") => {
+                let mut npz = NpzWriter::new(File::create(path)?);
+                for (name, maps) in [
+                    ("pairwise", &self.pairwise),
+                    ("accumulated", &accumulated),
+                    ("interpolated", &self.interpolated),
+                ] {
+                    let (mats, kinds) = mappings_to_arrays(maps);
+                    npz.add_array(format!("{name}.npy"), &mats)?;
+                    npz.add_array(format!("{name}_kinds.npy"), &kinds)?;
+                }
+                for (i, level) in self.levels.iter().enumerate() {
+                    let (mats, kinds) = mappings_to_arrays(level);
+                    let level = self.start_level as usize + i;
+                    npz.add_array(format!("level_{level}.npy"), &mats)?;
+                    npz.add_array(format!("level_{level}_kinds.npy"), &kinds)?;
+                }
+                npz.add_array("start_level.npy", &arr0(self.start_level))?;
+                npz.add_array(
+                    "config_hash.npy",
+                    &Array1::from_vec(pano.config_hash().into_bytes()),
+                )?;
+                npz.add_array("ve_indices.npy", &Array1::from_vec(ve_indices))?;
+                npz.add_array("ve_timestamps.npy", &Array1::from_vec(ve_timestamps))?;
+                npz.add_array("frame_indices.npy", &Array1::from_vec(frame_indices))?;
+                npz.add_array("frame_timestamps.npy", &Array1::from_vec(frame_timestamps))?;
+                npz.finish()?;
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Mappings can only be saved as .json or .npz, got {}.",
+                    path.display()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Load mappings saved with `save`, and check they are compatible with `pano`, that is, they were
+    /// estimated from the same source with the same configuration, see `Pano::config_hash`.
+    /// Interpolated mappings are recomputed from the pairwise ones if they are missing.
+    pub fn load<P: AsRef<Path>>(pano: &Pano, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let (config_hash, levels, start_level, pairwise, interpolated) =
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("json") => {
+                    let value: Value = serde_json::from_str(&read_to_string(path)?)?;
+                    let from_json = |value: &Value| -> Result<Option<Vec<Mapping>>> {
+                        value
+                            .as_array()
+                            .map(|maps| maps.iter().map(Mapping::from_json).collect())
+                            .transpose()
+                    };
+                    let levels = value["levels"]
+                        .as_array()
+                        .map(|levels| {
+                            levels
+                                .iter()
+                                .map(|l| from_json(l).map(Option::unwrap_or_default))
+                                .collect::<Result<Vec<_>>>()
+                        })
+                        .transpose()?
+                        .unwrap_or_default();
+                    let start_level = value["start_level"].as_u64().unwrap_or(1) as u32;
+                    let pairwise = from_json(&value["pairwise"])?
+                        .ok_or(anyhow!("Mappings file should contain pairwise mappings."))?;
+                    (
+                        value["config_hash"].as_str().map(str::to_owned),
+                        levels,
+                        start_level,
+                        pairwise,
+                        from_json(&value["interpolated"])?,
+                    )
+                }
+                Some("npz") => {
+                    let mut npz = NpzReader::new(File::open(path)?)?;
+                    let names = npz.names()?;
+                    let has = |name: &str| names.iter().any(|n| n == &format!("{name}.npy"));
+                    let start_level = if has("start_level") {
+                        npz.by_name::<OwnedRepr<u32>, Ix0>("start_level.npy")?
+                            .into_scalar()
+                    } else {
+                        1
+                    };
+                    let config_hash = if has("config_hash") {
+                        let bytes: Array1<u8> = npz.by_name("config_hash.npy")?;
+                        Some(String::from_utf8(bytes.to_vec())?)
+                    } else {
+                        None
+                    };
+                    let mut read = |name: &str| -> Result<Vec<Mapping>> {
+                        let mats: Array3<f32> = npz.by_name(&format!("{name}.npy"))?;
+                        let kinds: Array1<i32> = npz.by_name(&format!("{name}_kinds.npy"))?;
+                        arrays_to_mappings(&mats, &kinds)
+                    };
+                    let pairwise = read("pairwise")?;
+                    let interpolated = has("interpolated")
+                        .then(|| read("interpolated"))
+                        .transpose()?;
+                    let levels = (start_level..)
+                        .map(|level| format!("level_{level}"))
+                        .take_while(|name| has(name))
+                        .map(|name| read(&name))
+                        .collect::<Result<Vec<_>>>()?;
+                    (config_hash, levels, start_level, pairwise, interpolated)
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "Mappings can only be loaded from .json or .npz, got {}.",
+                        path.display()
+                    ))
+                }
+            };
+
+        if config_hash.as_deref() != Some(pano.config_hash().as_str()) {
+            return Err(anyhow!(
+                "Mappings {} were estimated from a different source or configuration.",
+                path.display()
+            ));
+        }
+        if pairwise.len() != pano.num_ves - 1 {
+            return Err(anyhow!(
+                "Expected {} pairwise mappings, got {}.",
+                pano.num_ves - 1,
+                pairwise.len()
+            ));
+        }
+        let interpolated = match interpolated {
+            Some(maps) if maps.len() == pano.num_frames() => maps,
+            Some(maps) => {
+                return Err(anyhow!(
+                    "Expected {} interpolated mappings, got {}.",
+                    pano.num_frames(),
+                    maps.len()
+                ))
+            }
+            None => pano.interpolate(&pairwise),
+        };
+        Ok(Self {
+            levels,
+            start_level,
+            pairwise,
+            interpolated,
+        })
+    }
+}
+
+/// Stack mappings into a (N, 3, 3) array of matrices and an array of transformation type indices.
+fn mappings_to_arrays(mappings: &[Mapping]) -> (Array3<f32>, Array1<i32>) {
+    let mats = Array3::from_shape_fn((mappings.len(), 3, 3), |(i, j, k)| mappings[i].mat[(j, k)]);
+    let kinds = mappings
+        .iter()
+        .map(|m| {
+            TransformationType::VARIANTS
+                .iter()
+                .position(|k| *k == m.kind)
+                .expect("Kind should be a variant") as i32
+        })
+        .collect();
+    (mats, kinds)
+}
+
+/// Inverse of `mappings_to_arrays`.
+fn arrays_to_mappings(mats: &Array3<f32>, kinds: &Array1<i32>) -> Result<Vec<Mapping>> {
+    mats.outer_iter()
+        .zip(kinds)
+        .map(|(mat, &kind)| {
+            let kind = TransformationType::VARIANTS
+                .get(kind as usize)
+                .ok_or(anyhow!("Invalid transformation type index {kind}."))?;
+            Ok(Mapping::from_matrix(mat.to_owned(), *kind))
+        })
+        .collect()
+}
+
+/// Panorama rendered by `Pano::render`.
+pub enum PanoCanvas {
+    /// Grayscale panorama, sRGB encoded if `tonemap2srgb` is enabled.
+    Gray(GrayImage),
+    /// Color panorama, as a (h, w, c) array of linear intensities in [0, 1].
+    Color(Array3<f32>),
+}
+
+/// Output of `Pano::render`.
+pub struct PanoRender {
+    /// Rendered panorama.
+    pub canvas: PanoCanvas,
+    /// Auxiliary layers, if enabled and not rendering the baseline.
+    pub layers: Option<MergeLayers>,
+    /// Gain of every granular frame, if gain compensation is enabled, see `Pano::estimate_gains`.
+    pub gains: Option<Array1<f32>>,
+}
+
+/// Convert photon counts to an image, either of the detection probability, or of the
+/// flux estimate normalized by its maximum if `invert_response` is set.
+fn counts_to_image(
+    counts: &PhotonCounts,
+    invert_response: bool,
+    dark_count: Option<f32>,
+) -> GrayImage {
+    if !invert_response {
+        let probability = counts.probability().index_axis_move(Axis(2), 0);
+        return array2_to_grayimage(probability.mapv(|v| <u8 as Clamp<f32>>::clamp(v * 255.0)));
+    }
+
+    let flux = counts.flux(dark_count).index_axis_move(Axis(2), 0);
+    let max_flux = flux.fold(0.0f32, |a, b| a.max(*b));
+    let scale = if max_flux > 0.0 {
+        255.0 / max_flux
+    } else {
+        0.0
+    };
+    array2_to_grayimage(flux.mapv(|v| <u8 as Clamp<f32>>::clamp(v * scale)))
+}
+
+/// Apply sRGB tonemapping to an image with linear intensities.
+fn tonemap_srgb(mut img: GrayImage) -> GrayImage {
+    img.pixels_mut().for_each(|p| {
+        p.0[0] = <u8 as Clamp<f32>>::clamp(linear_to_srgb(p.0[0] as f32 / 255.0) * 255.0)
+    });
+    img
+}
+
+/// A memory-mapped photoncube (or sequence of frames), ready to be registered and merged into a
+/// panorama. Granular frames are only loaded when needed, such that memory usage does not scale
+/// with the length of the photoncube. Created with `PanoBuilder::build`.
+pub struct Pano {
+    config: PanoBuilder,
+    path: PathBuf,
+    source: PanoSource,
+    cfa_channels: Option<Vec<Array2<bool>>>,
+    /// Number of pyramid levels used for hierarchical matching.
+    pub num_lvls: u32,
+    /// Number of virtual exposures that are matched.
+    pub num_ves: usize,
+    /// Number of bitplanes the granular frames span (or number of frames for conventional sources).
+    pub num_bitplanes: usize,
+    /// Size (height, width) of bitplanes, before any downscaling or transforms.
+    pub full_size: (usize, usize),
+    /// Size (width, height) of granular frames, after any downscaling and transforms.
+    pub frame_size: (u32, u32),
+    /// Blending mask, with the same downscaling and transforms as the granular frames.
+    pub blend_mask: Option<Array2<f32>>,
+}
+
+impl Pano {
+    /// Configuration used to open the photoncube.
+    pub fn config(&self) -> &PanoBuilder {
+        &self.config
+    }
+
+    /// Underlying photoncube, with any color filter array or inpainting masks loaded.
+    /// Returns `None` if frames come from an image sequence or video.
+    pub fn cube(&self) -> Option<&PhotonCube> {
+        match &self.source {
+            PanoSource::Cube(cube) => Some(cube),
+            PanoSource::Sequence { .. } => None,
+        }
+    }
+
+    /// Index of the first bitplane (in the whole photoncube) and timestamp (center, in bitplanes)
+    /// of every virtual exposure.
+    pub fn ve_timing(&self) -> (Vec<usize>, Vec<f64>) {
+        let start = self.config.start.unwrap_or(0);
+        let stride = self.config.step * self.config.burst_size;
+        (0..self.num_ves)
+            .map(|i| {
+                let first = start + i * stride;
+                (first, first as f64 + self.config.burst_size as f64 / 2.0)
+            })
+            .unzip()
+    }
+
+    /// Index of the first bitplane (in the whole photoncube) and timestamp (center, in bitplanes)
+    /// of every granular frame.
+    pub fn frame_timing(&self) -> (Vec<usize>, Vec<f64>) {
+        let start = self.config.start.unwrap_or(0);
+        (0..self.num_frames())
+            .map(|i| {
+                let first = start + i * self.config.granularity;
+                let len = self
+                    .config
+                    .granularity
+                    .min(self.num_bitplanes - i * self.config.granularity);
+                (first, first as f64 + len as f64 / 2.0)
+            })
+            .unzip()
+    }
+
+    /// Number of granular frames per virtual exposure.
+    pub fn num_frames_per_chunk(&self) -> usize {
+        self.config.burst_size / self.config.granularity
+    }
+
+    /// Total number of granular frames.
+    pub fn num_frames(&self) -> usize {
+        self.num_bitplanes.div_ceil(self.config.granularity)
+    }
+
+    /// Load and pre-process a range of granular frames from the photoncube.
+    /// We unpack the bitplanes, average them in groups of `granularity`,
+    /// apply colorspad corrections, and optionally downscale.
+    /// Any transforms (i.e: flip-ud) are applied here too.
+    ///
+    /// If a color filter array is given, frames are demosaiced with the configured method (as in
+    /// `load_color_frames`) and reduced to a single channel: the white channel for RGBW filter
+    /// arrays, or the mean of all channels otherwise.
+    ///
+    /// For image sequences and videos, frames are converted to grayscale and averaged
+    /// in groups of `granularity` frames instead.
+    pub fn load_frames(&self, range: Range<usize>) -> Result<Vec<GrayImage>> {
+        let granularity = self.config.granularity;
+        let (h, w) = self.full_size;
+        let range = (range.start * granularity)..(range.end * granularity).min(self.num_bitplanes);
+
+        // Convert mean frame to img and apply transforms
+        let finish = |frame: Array2<f32>| {
+            let mut img = array2_to_grayimage(frame.mapv(|v| (v * 255.0) as u8));
+
+            if self.config.downscale != 1.0 {
+                img = resize(
+                    &img,
+                    (w as f32 / self.config.downscale).round() as u32,
+                    (h as f32 / self.config.downscale).round() as u32,
+                    FilterType::CatmullRom,
+                );
+            }
+
+            apply_transforms(img, &self.config.transforms[..])
+        };
+
+        match &self.source {
+            PanoSource::Cube(cube) => {
+                let view = cube.view()?;
+                let slice = self.config.slice(&view);
+
+                slice
+                    .slice_axis(Axis(0), Slice::from(range))
+                    .axis_chunks_iter(Axis(0), granularity)
+                    .into_par_iter()
+                    .map(|group| {
+                        let mut frame = self.mean_bitplanes(group);
+
+                        // Demosaic frame, keeping the white channel or the mean of all channels
+                        if let Some(cfa) = &self.cfa_channels {
+                            let demosaiced = demosaic(
+                                frame.view(),
+                                cfa,
+                                self.config.demosaic,
+                                Some(granularity as f32),
+                            )?;
+                            frame = if cfa.len() == 4 {
+                                demosaiced.index_axis_move(Axis(2), 3)
+                            } else {
+                                demosaiced
+                                    .mean_axis(Axis(2))
+                                    .expect("Demosaiced frame should have channels")
+                            };
+                        }
+
+                        // Inpaint any hot/dead pixels
+                        if let Some(mask) = &cube.inpaint_mask {
+                            frame = interpolate_where_mask(&frame, mask, false)?;
+                        }
+                        Ok(finish(frame))
+                    })
+                    .collect()
+            }
+            PanoSource::Sequence { paths, .. } => {
+                let offset = self.config.bounds(paths.len()).start;
+                paths[(range.start + offset)..(range.end + offset)]
+                    .par_chunks(granularity)
+                    .map(|group| {
+                        let mut frame = Array2::zeros((h, w));
+                        for path in group {
+                            let gray = load_gray(path)?;
+                            if gray.dim() != (h, w) {
+                                return Err(anyhow!(
+                                    "All frames need to be of same size, {} has size {:?} instead of {:?}.",
+                                    path.display(),
+                                    gray.dim(),
+                                    (h, w)
+                                ));
+                            }
+                            frame += &gray;
+                        }
+                        frame.mapv_inplace(|v| v / (group.len() as f32));
+                        Ok(finish(frame))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Sum a group of bitplanes into a single frame holding the number of detections of every pixel.
+    fn sum_bitplanes(&self, group: ArrayView3<u8>) -> Array2<f32> {
+        // Iterate over all bitplanes in group,
+        // Unpack every frame in group as a f32 array and sum them together
+        group
+            .axis_iter(Axis(0))
+            .map(|bitplane| {
+                if self.config.bitpacked {
+                    unpack_single::<f32>(&bitplane, 1).unwrap()
+                } else {
+                    bitplane.mapv(|v| v as f32)
+                }
+            })
+            // Sum frames together (.sum not implemented for this type)
+            .reduce(|acc, e| acc + e)
+            .unwrap()
+    }
+
+    /// Average a group of bitplanes into a single frame, and apply any colorspad fixes.
+    fn mean_bitplanes(&self, group: ArrayView3<u8>) -> Array2<f32> {
+        // Compute mean values
+        let mut frame = self.sum_bitplanes(group);
+        frame.mapv_inplace(|v| v / (self.config.granularity as f32));
+
+        // Apply any frame-level fixes (only for ColorSPAD at the moment)
+        if self.config.colorspad_fix {
+            frame = process_colorspad(frame);
+        }
+        frame
+    }
+
+    /// Accumulate the detection and trial counts of all granular frames onto a canvas using the given
+    /// mappings (one per frame), see `merge_photon_counts`. Counts are summed straight from the bitplanes,
+    /// and hot/dead pixels (as given by the inpainting masks) and pixels outside of `valid` are excluded
+    /// from both counts instead of being interpolated, such that they follow the SPAD's Bernoulli
+    /// statistics exactly. Frames are streamed from the photoncube `window` frames at a time.
+    ///
+    /// This requires a photoncube whose granular frames are not resampled in any way, that is, without
+    /// downscaling, transforms, colorspad fixes or demosaicing.
+    pub fn photon_counts(
+        &self,
+        mappings: &[Mapping],
+        valid: Option<&Array2<bool>>,
+        message: Option<&str>,
+    ) -> Result<PhotonCounts> {
+        let cube = self.cube().ok_or(anyhow!(
+            "Photon counts can only be accumulated from photoncubes."
+        ))?;
+        if self.config.downscale != 1.0
+            || !self.config.transforms.is_empty()
+            || self.config.colorspad_fix
+            || self.cfa_channels.is_some()
+        {
+            return Err(anyhow!(
+                "Photon counts cannot be accumulated with downscaling, transforms, colorspad fixes or demosaicing."
+            ));
+        }
+        if mappings.len() != self.num_frames() {
+            return Err(anyhow!(
+                "Expected {} mappings, got {}.",
+                self.num_frames(),
+                mappings.len()
+            ));
+        }
+        if valid.is_some_and(|v| v.dim() != self.full_size) {
+            return Err(anyhow!("Validity mask and frames need to be of same size."));
+        }
+
+        // Exclude any hot/dead pixels along with pixels that are not valid
+        let valid = match (valid, &cube.inpaint_mask) {
+            (Some(valid), Some(mask)) => Some(valid & &mask.mapv(|v| !v)),
+            (Some(valid), None) => Some(valid.clone()),
+            (None, Some(mask)) => Some(mask.mapv(|v| !v)),
+            (None, None) => None,
+        };
+        let valid = valid.map(|v| vec![v]);
+
+        // Shift mappings such that all frames fit on a common canvas, which is then filled window by window
+        let (h, w) = self.full_size;
+        let (extent, offset) = Mapping::maximum_extent(mappings, &[(w, h)]);
+        let size = (extent[1].ceil() as usize, extent[0].ceil() as usize);
+        let mappings: Vec<_> = mappings
+            .iter()
+            .map(|m| m.transform(None, Some(offset.clone())))
+            .collect();
+        let mut counts = PhotonCounts {
+            detections: Array3::zeros((size.0, size.1, 1)),
+            trials: Array2::zeros(size),
+        };
+
+        let view = cube.view()?;
+        let slice = self.config.slice(&view);
+        let (granularity, window, num_frames) = (
+            self.config.granularity,
+            self.config.window,
+            self.num_frames(),
+        );
+        let pbar = get_pbar(num_frames, message);
+        for start in (0..num_frames).step_by(window) {
+            let end = (start + window).min(num_frames);
+            let range = (start * granularity)..(end * granularity).min(self.num_bitplanes);
+            let frames: Vec<(Array3<f32>, usize)> = slice
+                .slice_axis(Axis(0), Slice::from(range))
+                .axis_chunks_iter(Axis(0), granularity)
+                .into_par_iter()
+                .map(|group| {
+                    let frame = self.sum_bitplanes(group).insert_axis(Axis(2));
+                    (frame, group.len_of(Axis(0)))
+                })
+                .collect();
+
+            // Only the very last frame might span fewer bitplanes, so it is merged separately
+            let num_full = frames.iter().filter(|(_, n)| *n == granularity).count();
+            for (frames, mappings) in [
+                (&frames[..num_full], &mappings[start..start + num_full]),
+                (&frames[num_full..], &mappings[start + num_full..end]),
+            ] {
+                let Some((_, num_trials)) = frames.first() else {
+                    continue;
+                };
+                let arrays: Vec<_> = frames.iter().map(|(frame, _)| frame.view()).collect();
+                let window_counts = merge_photon_counts(
+                    mappings,
+                    &arrays,
+                    *num_trials as f32,
+                    valid.as_deref(),
+                    Some(size),
+                    None,
+                )?;
+                counts.detections += &window_counts.detections;
+                counts.trials += &window_counts.trials;
+            }
+            pbar.inc((end - start) as u64);
+        }
+        pbar.finish_and_clear();
+        Ok(counts)
+    }
+
+    /// Number of channels of color frames, this is either three (RGB) or four (RGBW).
+    pub fn num_channels(&self) -> usize {
+        self.cfa_channels
+            .as_ref()
+            .map_or(3, |channels| channels.len())
+    }
+
+    /// Load a range of granular frames in color, as (h, w, c) arrays of linear intensities in [0, 1],
+    /// after the same downscaling and transforms as `load_frames`. The channels are in RGB(W) order.
+    ///
+    /// For photoncubes, granular frames are demosaiced using the configured method (see `demosaic`)
+    /// right after the bitplanes are averaged, and inpainted if needed. Image sequences and videos are
+    /// assumed to be sRGB encoded and are linearized.
+    pub fn load_color_frames(&self, range: Range<usize>) -> Result<Vec<Array3<f32>>> {
+        if !self.config.color {
+            return Err(anyhow!(
+                "Color frames were not enabled, see `PanoBuilder::color`."
+            ));
+        }
+        let granularity = self.config.granularity;
+        let (h, w) = self.full_size;
+        let range = (range.start * granularity)..(range.end * granularity).min(self.num_bitplanes);
+
+        // Apply downscaling and transforms to every channel, and stack them
+        let finish = |channels: Vec<Array2<f32>>| -> Result<Array3<f32>> {
+            let channels = channels
+                .into_iter()
+                .map(|channel| {
+                    let (ch, cw) = channel.dim();
+                    let mut img = ImageBuffer::<Luma<f32>, _>::from_raw(
+                        cw as u32,
+                        ch as u32,
+                        channel.iter().copied().collect(),
+                    )
+                    .expect("Buffer should match image size");
+
+                    if self.config.downscale != 1.0 {
+                        img = resize(
+                            &img,
+                            (w as f32 / self.config.downscale).round() as u32,
+                            (h as f32 / self.config.downscale).round() as u32,
+                            FilterType::CatmullRom,
+                        );
+                    }
+                    let img = apply_transforms(img, &self.config.transforms[..]);
+                    let (iw, ih) = img.dimensions();
+                    Ok(Array2::from_shape_vec(
+                        (ih as usize, iw as usize),
+                        img.into_raw(),
+                    )?)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let views: Vec<_> = channels.iter().map(|c| c.view()).collect();
+            Ok(stack(Axis(2), &views)?)
+        };
+
+        match &self.source {
+            PanoSource::Cube(cube) => {
+                let cfa = self
+                    .cfa_channels
+                    .as_ref()
+                    .expect("Color filter array should be loaded in color mode");
+                let view = cube.view()?;
+                let slice = self.config.slice(&view);
+
+                slice
+                    .slice_axis(Axis(0), Slice::from(range))
+                    .axis_chunks_iter(Axis(0), granularity)
+                    .into_par_iter()
+                    .map(|group| {
+                        let frame = self.mean_bitplanes(group);
+
+                        // Demosaic frame, then inpaint any hot/dead pixels in every channel
+                        let demosaiced = demosaic(
+                            frame.view(),
+                            cfa,
+                            self.config.demosaic,
+                            Some(granularity as f32),
+                        )?;
+                        let channels = demosaiced
+                            .axis_iter(Axis(2))
+                            .map(|channel| {
+                                let channel = channel.to_owned();
+                                match &cube.inpaint_mask {
+                                    Some(mask) => {
+                                        Ok(interpolate_where_mask(&channel, mask, false)?)
+                                    }
+                                    None => Ok(channel),
+                                }
+                            })
+                            .collect::<Result<Vec<_>>>()?;
+                        finish(channels)
+                    })
+                    .collect()
+            }
+            PanoSource::Sequence { paths, .. } => {
+                let offset = self.config.bounds(paths.len()).start;
+                paths[(range.start + offset)..(range.end + offset)]
+                    .par_chunks(granularity)
+                    .map(|group| {
+                        let mut frame = Array3::zeros((h, w, 3));
+                        for path in group {
+                            let rgb = load_rgb(path)?;
+                            if rgb.dim() != (h, w, 3) {
+                                return Err(anyhow!(
+                                    "All frames need to be of same size, {} has size {:?} instead of {:?}.",
+                                    path.display(),
+                                    rgb.dim(),
+                                    (h, w, 3)
+                                ));
+                            }
+                            frame += &rgb;
+                        }
+                        frame.mapv_inplace(|v| v / (group.len() as f32));
+                        finish(frame.axis_iter(Axis(2)).map(|c| c.to_owned()).collect())
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Iterate over all items returned by `load`, which is called with ranges of `window` granular frames.
+    fn windowed<'a, T: 'a>(
+        &'a self,
+        load: impl Fn(Range<usize>) -> Result<Vec<T>> + 'a,
+    ) -> impl Iterator<Item = Result<T>> + 'a {
+        let (window, num_frames) = (self.config.window, self.num_frames());
+        (0..num_frames).step_by(window).flat_map(move |start| {
+            match load(start..(start + window).min(num_frames)) {
+                Ok(frames) => frames.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            }
+        })
+    }
+
+    /// Iterate over all granular frames, which are loaded `window` frames at a time.
+    pub fn frames(&self) -> impl Iterator<Item = Result<GrayImage>> + '_ {
+        self.windowed(|range| self.load_frames(range))
+    }
+
+    /// Iterate over all granular frames in color, which are loaded `window` frames at a time.
+    pub fn color_frames(&self) -> impl Iterator<Item = Result<Array3<f32>>> + '_ {
+        self.windowed(|range| self.load_color_frames(range))
+    }
+
+    /// Estimate pairwise mappings between virtual exposures with a coarse-to-fine approach, and
+    /// interpolate them to every granular frame. Virtual exposures are created on demand at every
+    /// level, and only the granular frames needed to create them are loaded at any given time.
+    ///
+    /// If provided, `callback` is called after every level with the level's number (starting at one
+    /// for the coarsest), the upgraded pairwise mappings, and the virtual exposures that were matched.
+    /// If enabled, bundle adjustment and loop closure are performed once all levels are matched,
+    /// see `bundle_adjust`.
+    pub fn register(
+        &self,
+        verbose: bool,
+        mut callback: Option<&mut dyn FnMut(u32, &[Mapping], &[GrayImage]) -> Result<()>>,
+    ) -> Result<PanoMappings> {
+        let (num_lvls, num_ves) = (self.num_lvls, self.num_ves);
+        let mut mappings: Vec<Mapping> = vec![Mapping::from_params(vec![0.0; 2]); num_ves - 1];
+        let mut levels = vec![];
+        let mut last_ves = None;
+        let mut start_level = 1;
+
+        if let (Some(level), Some(init)) = (self.config.start_level, &self.config.init_mappings) {
+            if !(1..=num_lvls).contains(&level) {
+                return Err(anyhow!(
+                    "Starting level must be between 1 and {num_lvls}, got {level}."
+                ));
+            }
+            if init.len() != num_ves - 1 {
+                return Err(anyhow!(
+                    "Expected {} initial mappings, got {}.",
+                    num_ves - 1,
+                    init.len()
+                ));
+            }
+
+            // Bring mappings to the resolution of the previous level
+            mappings = init
+                .iter()
+                .map(|m| m.rescale((1 << (num_lvls - level + 1)) as f32))
+                .collect();
+            start_level = level;
+        } else if self.config.resume {
+            // Skip all levels that have a valid checkpoint
+            while start_level <= num_lvls {
+                let Some(checkpoint) = self.load_checkpoint(start_level)? else {
+                    break;
+                };
+                mappings = checkpoint.iter().map(|m| m.upgrade()).collect();
+                levels.push(checkpoint);
+                start_level += 1;
+            }
+            if verbose && start_level > 1 {
+                println!("Resuming after level {}/{}.", start_level - 1, num_lvls);
+            }
+        }
+
+        for lvl in (0..(num_lvls + 1).saturating_sub(start_level)).rev() {
+            // Bring mappings to the resolution of this level
+            mappings = mappings.iter().map(|m| m.rescale(0.5)).collect();
+            let message = format!("({}/{}): Loading Data...", num_lvls - lvl, num_lvls);
+            let virtual_exposures =
+                self.virtual_exposures(&mappings, 1 << lvl, verbose.then_some(message.as_str()))?;
+
+            // Estimate pairwise registration
+            if verbose {
+                print!("({}/{}): Matching... ", num_lvls - lvl, num_lvls);
+            }
+            let frames: Vec<Array3<f32>> = virtual_exposures
+                .iter()
+                .map(|ve| image_to_array3(ve.clone()).mapv(f32::from))
+                .collect();
+            if self.config.mosaic && lvl == 0 {
+                mappings = mosaic_iclk(
+                    &frames,
+                    &mappings,
+                    true,
+                    Some(self.config.iterations),
+                    Some(self.config.min_size),
+                    None,
+                    Some(self.config.early_stop),
+                    Some(self.config.patience),
+                    Some(&self.config.lk),
+                    verbose,
+                )?;
+            } else {
+                (mappings, _) = pairwise_iclk(
+                    &frames,
+                    &mappings[..],
+                    false,
+                    None,
+                    None,
+                    Some(self.config.iterations),
+                    Some(self.config.early_stop),
+                    Some(self.config.patience),
+                    Some(&self.config.lk),
+                    verbose,
+                )?;
+            }
+            levels.push(mappings.clone());
+            if verbose {
+                println!("Done.");
+            }
+            self.save_checkpoint(num_lvls - lvl, &mappings)?;
+
+            // Augment mapping type every iteration
+            mappings = mappings.iter().map(|m| m.upgrade()).collect();
+
+            if let Some(callback) = callback.as_mut() {
+                callback(num_lvls - lvl, &mappings, &virtual_exposures)?;
+            }
+            last_ves = Some(virtual_exposures);
+        }
+
+        if self.config.bundle_adjust.is_some() || self.config.loop_closure {
+            // Virtual exposures are re-created if all levels were loaded from checkpoints
+            let ves = match last_ves {
+                Some(ves) => ves,
+                None => self.virtual_exposures(
+                    &mappings,
+                    1,
+                    verbose.then_some("Bundle Adjustment: Loading Data..."),
+                )?,
+            };
+            let last = levels
+                .last()
+                .ok_or(anyhow!("No pairwise mappings to adjust."))?;
+            let span = self.config.bundle_adjust.unwrap_or(1);
+            mappings = self
+                .bundle_adjust(last, &ves, span, self.config.loop_closure, verbose)?
+                .iter()
+                .map(|m| m.upgrade())
+                .collect();
+        }
+
+        // If resuming, levels before the start level were loaded from checkpoints
+        let start_level = if self.config.resume { 1 } else { start_level };
+        let interpolated = self.interpolate(&mappings);
+        Ok(PanoMappings {
+            levels,
+            start_level,
+            pairwise: mappings,
+            interpolated,
+        })
+    }
+
+    /// Create virtual exposures by merging `num_frames_per_chunk` granular frames using the given
+    /// pairwise mappings (at the resolution of the level), and downscaling the result.
+    fn virtual_exposures(
+        &self,
+        mappings: &[Mapping],
+        downscale: u32,
+        message: Option<&str>,
+    ) -> Result<Vec<GrayImage>> {
+        let num_frames_per_chunk = self.num_frames_per_chunk();
+        let (w, h) = self.frame_size;
+
+        // Interpolate mappings to all bitplanes
+        let acc_maps = Mapping::accumulate(mappings.to_vec());
+        let interpd_maps = Mapping::interpolate_array(
+            Array1::linspace(0.0, (self.num_ves - 1) as f32, self.num_ves).to_vec(),
+            acc_maps,
+            Array1::linspace(
+                0.0,
+                (self.num_ves - 1) as f32,
+                self.num_ves * num_frames_per_chunk,
+            )
+            .to_vec(),
+        );
+
+        let pbar = get_pbar(self.num_ves, message);
+        let virtual_exposures = interpd_maps
+            .par_chunks(num_frames_per_chunk)
+            .enumerate()
+            .progress_with(pbar.clone())
+            .map(|(i, maps)| {
+                let start = i * self.config.step * num_frames_per_chunk;
+                let frames = self.load_frames(start..start + num_frames_per_chunk)?;
+                let img = merge_images(
+                    &Mapping::with_respect_to_idx(maps.to_vec(), 0.5),
+                    &frames,
+                    None,
+                    None,
+                    Some((w as usize, h as usize)),
+                    None,
+                )?;
+
+                Ok(resize(
+                    &img,
+                    (w as f32 / downscale as f32).round() as u32,
+                    (h as f32 / downscale as f32).round() as u32,
+                    FilterType::CatmullRom,
+                ))
+            })
+            .collect::<Result<Vec<GrayImage>>>()?;
+        pbar.finish_and_clear();
+        Ok(virtual_exposures)
+    }
+
+    /// Globally refine full resolution pairwise mappings between virtual exposures. Every pair of
+    /// virtual exposures up to `span` apart is registered, starting from the accumulated pairwise
+    /// mappings, and all absolute mappings are then jointly optimized over the resulting pose graph.
+    /// Edges are weighted by the confidence of their registration, see `registration_confidence`.
+    ///
+    /// If `loop_closure` is enabled, pairs of distant virtual exposures that see the same part of the
+    /// scene are also found, either from their predicted overlap or from their thumbnails, and are
+    /// registered with a multi-scale approach. Only confident registrations are added to the graph.
+    ///
+    /// The pyramid and template data of every virtual exposure are computed once and shared by all
+    /// edges, including loop closures, whose finest level is the one used by single scale edges.
+    pub fn bundle_adjust(
+        &self,
+        pairwise: &[Mapping],
+        virtual_exposures: &[GrayImage],
+        span: usize,
+        loop_closure: bool,
+        verbose: bool,
+    ) -> Result<Vec<Mapping>> {
+        let kind = pairwise
+            .iter()
+            .map(|m| m.kind)
+            .max_by_key(|k| k.num_params())
+            .unwrap_or(TransformationType::Identity);
+        if kind.num_params() == 0 {
+            return Ok(pairwise.to_vec());
+        }
+
+        let frames: Vec<Array3<f32>> = virtual_exposures
+            .iter()
+            .map(|ve| image_to_array3(ve.clone()).mapv(f32::from))
+            .collect();
+        let (h, w, _) = frames[0].dim();
+        let mut graph = PoseGraph::from_pairwise(pairwise, (w, h));
+        let pyramids = frame_pyramids(
+            &frames,
+            0..frames.len(),
+            loop_closure,
+            Some(self.config.min_size),
+            None,
+            Some(&self.config.lk),
+        )?;
+
+        // Adjacent pairs were already registered by the last level
+        if verbose {
+            println!("Bundle Adjustment: Registering pairs up to {span} apart.");
+        }
+        let pairs: Vec<(usize, usize)> = (2..=span)
+            .flat_map(|k| (0..frames.len().saturating_sub(k)).map(move |i| (i, i + k)))
+            .collect();
+        let mut edges =
+            self.register_edges(&graph, &frames, &pyramids, &pairs, kind, false, verbose)?;
+        edges.extend(
+            pairwise
+                .par_iter()
+                .enumerate()
+                .map(|(i, mapping)| PoseEdge {
+                    i,
+                    j: i + 1,
+                    mapping: mapping.clone(),
+                    confidence: registration_confidence(&frames[i], &frames[i + 1], mapping),
+                }),
+        );
+        graph.edges = edges;
+
+        if loop_closure {
+            let descriptors: Vec<_> = frames.par_iter().map(global_descriptor).collect();
+            let candidates = graph.loop_candidates(
+                &descriptors,
+                (span + 1).max(LOOP_CLOSURE_MIN_GAP),
+                LOOP_CLOSURE_MIN_SCORE,
+            );
+            if verbose {
+                println!("Loop Closure: Verifying {} candidates.", candidates.len());
+            }
+            let closures: Vec<_> = self
+                .register_edges(&graph, &frames, &pyramids, &candidates, kind, true, verbose)?
+                .into_iter()
+                .filter(|e| e.confidence >= LOOP_CLOSURE_MIN_CONFIDENCE)
+                .collect();
+            if verbose {
+                println!("Loop Closure: Found {} closures.", closures.len());
+            }
+            graph.edges.extend(closures);
+        }
+
+        let rms = graph.optimize(kind, BUNDLE_ADJUST_ITERATIONS, BUNDLE_ADJUST_HUBER)?;
+        if verbose {
+            println!(
+                "Bundle Adjustment: Optimized {} edges, RMS residual of {:.3}px.",
+                graph.edges.len(),
+                rms
+            );
+        }
+        Ok(graph.pairwise())
+    }
+
+    /// Register pairs of frames given their pyramids, starting from the relative mapping predicted by
+    /// the graph's poses, and return them as edges along with their confidence.
+    #[allow(clippy::too_many_arguments)]
+    fn register_edges(
+        &self,
+        graph: &PoseGraph,
+        frames: &[Array3<f32>],
+        pyramids: &HashMap<usize, FramePyramid>,
+        pairs: &[(usize, usize)],
+        kind: TransformationType,
+        multi: bool,
+        verbose: bool,
+    ) -> Result<Vec<PoseEdge>> {
+        let init_mappings: Vec<Mapping> = pairs
+            .iter()
+            .map(|(i, j)| {
+                let init = graph.poses[*i]
+                    .inverse()
+                    .transform(None, Some(graph.poses[*j].clone()));
+                Mapping::from_matrix(init.mat, kind)
+            })
+            .collect();
+        let results = register_pyramids(
+            pyramids,
+            pairs,
+            &init_mappings,
+            multi,
+            Some(self.config.iterations),
+            Some(self.config.early_stop),
+            Some(self.config.patience),
+            Some(&self.config.lk.schedule[..]),
+            verbose,
+        )?;
+
+        Ok(results
+            .into_par_iter()
+            .map(|((i, j), (mapping, _))| {
+                let confidence = registration_confidence(&frames[i], &frames[j], &mapping);
+                PoseEdge {
+                    i,
+                    j,
+                    mapping,
+                    confidence,
+                }
+            })
+            .collect())
+    }
+
+    /// Hash of the configuration and photoncube path, used to ensure checkpoints are compatible.
+    /// Only settings that affect the estimated pairwise mappings are considered, so any such setting
+    /// that is added to `PanoBuilder` must also be added here.
+    pub fn config_hash(&self) -> String {
+        let c = &self.config;
+        let description = format!(
+            "{:?}",
+            (
+                &self.path,
+                (c.burst_size, c.granularity, c.step, c.start, c.end),
+                (c.max_lvls, c.min_size, c.downscale),
+                (c.iterations, c.early_stop, c.patience),
+                (
+                    &c.transforms,
+                    c.bitpacked,
+                    c.colorspad_fix,
+                    &c.cfa_path,
+                    &c.inpaint_paths,
+                ),
+                // Frames are only demosaiced before registration if a CFA is given
+                c.cfa_path.as_ref().map(|_| c.demosaic),
+                (c.bundle_adjust, c.loop_closure, c.mosaic),
+                &c.lk,
+            )
+        );
+
+        // FNV-1a, which unlike the std hasher is stable across runs and rust versions
+        let hash = description
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        format!("{hash:016x}")
+    }
+
+    /// Save the pairwise mappings of a level (starting at one for the coarsest) to the checkpoint
+    /// directory, if any, along with the level index and configuration hash.
+    fn save_checkpoint(&self, level: u32, mappings: &[Mapping]) -> Result<()> {
+        let Some(dir) = &self.config.checkpoint_dir else {
+            return Ok(());
+        };
+        create_dir_all(dir)?;
+
+        let checkpoint = json!({
+            "level": level,
+            "num_lvls": self.num_lvls,
+            "config_hash": self.config_hash(),
+            "mappings": mappings.iter().map(|m| m.to_json()).collect::<Vec<_>>(),
+        });
+
+        // Write to a temporary file first such that an interruption never leaves a partial checkpoint
+        let path = dir.join(format!("lvl-{level}.json"));
+        let tmp_path = path.with_extension("json.tmp");
+        write(&tmp_path, serde_json::to_string_pretty(&checkpoint)?)?;
+        rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Load the pairwise mappings of a level from the checkpoint directory, if they exist.
+    fn load_checkpoint(&self, level: u32) -> Result<Option<Vec<Mapping>>> {
+        let Some(dir) = &self.config.checkpoint_dir else {
+            return Ok(None);
+        };
+        let path = dir.join(format!("lvl-{level}.json"));
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let checkpoint: Value = serde_json::from_str(&read_to_string(&path)?)?;
+        if checkpoint["config_hash"].as_str() != Some(self.config_hash().as_str())
+            || checkpoint["num_lvls"].as_u64() != Some(self.num_lvls as u64)
+        {
+            return Err(anyhow!(
+                "Checkpoint {} was created with a different configuration.",
+                path.display()
+            ));
+        }
+
+        let mappings = checkpoint["mappings"]
+            .as_array()
+            .ok_or(anyhow!("Checkpoint should contain mappings."))?
+            .iter()
+            .map(Mapping::from_json)
+            .collect::<Result<Vec<_>>>()?;
+        if mappings.len() != self.num_ves - 1 {
+            return Err(anyhow!(
+                "Checkpoint {} has {} mappings, expected {}.",
+                path.display(),
+                mappings.len(),
+                self.num_ves - 1
+            ));
+        }
+        Ok(Some(mappings))
+    }
+
+    /// Interpolate pairwise mappings between virtual exposures to every granular frame.
+    pub fn interpolate(&self, pairwise: &[Mapping]) -> Vec<Mapping> {
+        let acc_maps = Mapping::accumulate_wrt_idx(pairwise.to_vec(), self.config.wrt);
+        Mapping::interpolate_array(
+            Array1::linspace(0.0, (self.num_ves - 1) as f32, self.num_ves).to_vec(),
+            acc_maps,
+            Array1::linspace(0.0, (self.num_ves - 1) as f32, self.num_frames()).to_vec(),
+        )
+    }
+
+    /// Interpolate pairwise mappings between virtual exposures to every bitplane, each bitplane is
+    /// placed at its offset within its granular frame.
+    pub fn bitplane_mappings(&self, pairwise: &[Mapping]) -> Vec<Mapping> {
+        let granularity = self.config.granularity as f32;
+        let last = (self.num_ves - 1) as f32;
+        let spacing = last / (self.num_frames() - 1).max(1) as f32;
+        Mapping::interpolate_array(
+            Array1::linspace(0.0, last, self.num_ves).to_vec(),
+            Mapping::accumulate_wrt_idx(pairwise.to_vec(), self.config.wrt),
+            (0..self.num_bitplanes)
+                .map(|i| (((i as f32 + 0.5) / granularity - 0.5) * spacing).clamp(0.0, last))
+                .collect(),
+        )
+    }
+
+    /// Mappings of the baseline method, which only uses the first (coarsest) level's mappings
+    /// and keeps them constant for the duration of a virtual exposure.
+    pub fn baseline(&self, mappings: &PanoMappings) -> Result<Vec<Mapping>> {
+        let coarsest = mappings
+            .levels
+            .first()
+            .ok_or(anyhow!("At least one level of mappings is required."))?;
+        let num_frames_per_chunk = self.num_frames_per_chunk();
+
+        // Accumulate wrt center frame
+        let acc_maps = Mapping::accumulate_wrt_idx(coarsest.clone(), self.config.wrt);
+
+        // Scale back to original size
+        let scaled_mappings: Vec<_> = acc_maps
+            .iter()
+            .map(|m| m.rescale(1.0 / ((1 << (self.num_lvls - mappings.start_level)) as f32)))
+            .collect();
+
+        // Interpolate from all virtual exposures to all granular frames (if step != 1 these are not equal)
+        let interpd_maps = Mapping::interpolate_array(
+            Array1::linspace(0.0, (self.num_ves - 1) as f32, self.num_ves).to_vec(),
+            scaled_mappings,
+            Array1::linspace(0.0, (self.num_ves - 1) as f32, self.num_frames()).to_vec(),
+        );
+
+        // Repeat mapping such that it is constant for the duration of a burst frame
+        Ok(interpd_maps
+            .into_iter()
+            .step_by(num_frames_per_chunk)
+            .flat_map(|n| std::iter::repeat(n).take(num_frames_per_chunk))
+            .take(self.num_frames())
+            .collect())
+    }
+
+    /// Merge all granular frames into a panorama using the given mappings (one per frame),
+    /// see `merge_arrays`. Frames are streamed from the photoncube, multiplied by their `gains` if
+    /// provided, and optionally, the merge can be done with auxiliary layers, see `merge_arrays_with_layers`.
+    pub fn canvas(
+        &self,
+        mappings: &[Mapping],
+        gains: Option<&Array1<f32>>,
+        weights: Option<&[Array2<f32>]>,
+        with_layers: bool,
+        message: Option<&str>,
+    ) -> Result<(GrayImage, Option<MergeLayers>)> {
+        // Errors from loading frames are deferred until merging is done
+        let mut error = None;
+        let frames = self
+            .frames()
+            .enumerate()
+            .map_while(|(i, frame)| match frame {
+                Ok(frame) => {
+                    let gain = gains.map_or(1.0, |g| g[i]);
+                    Some(image_to_array3(frame).mapv(|v| f32::from(v) * gain))
+                }
+                Err(e) => {
+                    error = Some(e);
+                    None
+                }
+            });
+        let (w, h) = self.frame_size;
+        let frame_size = (h as usize, w as usize, 1);
+
+        let (canvas, layers) = if with_layers {
+            let (canvas, layers) = merge_arrays_with_layers_iter(
+                mappings, frames, frame_size, weights, None, None, message,
+            )?;
+            (canvas, Some(layers))
+        } else {
+            let canvas =
+                merge_arrays_iter(mappings, frames, frame_size, weights, None, None, message)?;
+            (canvas, None)
+        };
+        if let Some(e) = error {
+            return Err(e);
+        }
+        Ok((
+            array3_to_image(canvas.mapv(<u8 as Clamp<f32>>::clamp)),
+            layers,
+        ))
+    }
+
+    /// Merge all granular frames in color into a panorama using the given mappings (one per frame).
+    /// Frames are streamed from the source, and the result is a (h, w, c) array of linear intensities.
+    pub fn color_canvas(
+        &self,
+        mappings: &[Mapping],
+        weights: Option<&[Array2<f32>]>,
+        message: Option<&str>,
+    ) -> Result<Array3<f32>> {
+        // Errors from loading frames are deferred until merging is done
+        let mut error = None;
+        let frames = self.color_frames().map_while(|frame| match frame {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                error = Some(e);
+                None
+            }
+        });
+        let (w, h) = self.frame_size;
+        let frame_size = (h as usize, w as usize, self.num_channels());
+        let canvas = merge_arrays_iter(mappings, frames, frame_size, weights, None, None, message)?;
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+        Ok(canvas)
+    }
+
+    /// Render the panorama with the configured merging method, using either the final mappings or,
+    /// if `baseline` is set, those of the baseline method (see `baseline`). Returns the panorama,
+    /// along with its auxiliary layers and the estimated gains if these are enabled.
+    ///
+    /// Depending on the configuration, granular frames are merged in color, drizzled onto an upscaled
+    /// canvas, merged as photon counts from which the flux is estimated (see `photon_counts`), or blended
+    /// after their gains are equalized (see `estimate_gains`). Bitplanes are splatted individually if
+    /// `bitplane_exact` is set, except for the baseline which only has mappings for granular frames.
+    /// The blending mask is applied throughout, and grayscale panoramas are tonemapped if enabled.
+    pub fn render(
+        &self,
+        mappings: &PanoMappings,
+        baseline: bool,
+        verbose: bool,
+    ) -> Result<PanoRender> {
+        let config = &self.config;
+        let name = if baseline {
+            "Baseline Pano"
+        } else {
+            "Panorama"
+        };
+        let message = if config.upscale != 1.0 {
+            format!("Drizzling {name}...")
+        } else {
+            format!("Making {name}...")
+        };
+        let message = verbose.then_some(message.as_str());
+        let blend_mask = self.blend_mask.as_ref().map(std::slice::from_ref);
+
+        let baseline_maps: Vec<Mapping>;
+        let interpolated = if baseline {
+            baseline_maps = self.baseline(mappings)?;
+            &baseline_maps
+        } else {
+            &mappings.interpolated
+        };
+
+        if config.color {
+            let canvas = self.color_canvas(interpolated, blend_mask, message)?;
+            return Ok(PanoRender {
+                canvas: PanoCanvas::Color(canvas),
+                layers: None,
+                gains: None,
+            });
+        }
+
+        let (canvas, layers, gains) = if config.bitplane_exact && !baseline {
+            let counts = self.splat(
+                &mappings.pairwise,
+                verbose.then_some("Splatting Bitplanes..."),
+            )?;
+            (
+                counts_to_image(&counts, config.invert_response, config.dark_count),
+                None,
+                None,
+            )
+        } else if config.invert_response {
+            let valid = self.blend_mask.as_ref().map(|m| m.mapv(|v| v > 0.0));
+            let counts = self.photon_counts(interpolated, valid.as_ref(), message)?;
+            (
+                counts_to_image(&counts, true, config.dark_count),
+                None,
+                None,
+            )
+        } else {
+            // Gains are always estimated using the final mappings
+            let gains = config
+                .gain_compensation
+                .then(|| self.estimate_gains(&mappings.interpolated, verbose))
+                .transpose()?;
+            let (canvas, layers) = self.merge_frames(
+                interpolated,
+                gains.as_ref(),
+                blend_mask,
+                config.layers && !baseline,
+                message,
+            )?;
+            (canvas, layers, gains)
+        };
+
+        let canvas = if config.tonemap2srgb {
+            tonemap_srgb(canvas)
+        } else {
+            canvas
+        };
+        Ok(PanoRender {
+            canvas: PanoCanvas::Gray(canvas),
+            layers,
+            gains,
+        })
+    }
+
+    /// Merge granular frames, either by drizzling them or by blending them with optional layers.
+    /// Frames are multiplied by their `gains` if provided, and are either streamed from the source,
+    /// or loaded all at once if drizzling. The time layers are converted to the index of the frames'
+    /// first bitplane.
+    fn merge_frames(
+        &self,
+        mappings: &[Mapping],
+        gains: Option<&Array1<f32>>,
+        weights: Option<&[Array2<f32>]>,
+        with_layers: bool,
+        message: Option<&str>,
+    ) -> Result<(GrayImage, Option<MergeLayers>)> {
+        if self.config.upscale != 1.0 {
+            // Drizzling needs all frames at once
+            let mut frames = self.load_frames(0..self.num_frames())?;
+            if let Some(gains) = gains {
+                frames.iter_mut().zip(gains).for_each(|(frame, &gain)| {
+                    frame
+                        .pixels_mut()
+                        .for_each(|p| p.0[0] = <u8 as Clamp<f32>>::clamp(p.0[0] as f32 * gain))
+                });
+            }
+            let canvas = drizzle_images(
+                mappings,
+                &frames,
+                weights,
+                None,
+                self.config.upscale,
+                self.config.pixfrac,
+                None,
+                message,
+            )?;
+            return Ok((canvas, None));
+        }
+
+        let (canvas, layers) = self.canvas(mappings, gains, weights, with_layers, message)?;
+
+        // Convert granular frame indices to the index of their first bitplane
+        let start = self.config.start.unwrap_or(0) as f32;
+        let granularity = self.config.granularity as f32;
+        let layers = layers.map(|mut layers| {
+            azip!((
+                mean in &mut layers.time_mean,
+                min in &mut layers.time_min,
+                max in &mut layers.time_max,
+                &n in &layers.count
+            ) {
+                if n > 0.0 {
+                    *mean = *mean * granularity + start;
+                    *min = *min * granularity + start;
+                    *max = *max * granularity + start;
+                }
+            });
+            layers
+        });
+        Ok((canvas, layers))
+    }
+
+    /// Estimate the gain of every granular frame that equalizes their brightness using the given
+    /// mappings (one per frame), see `gain_compensation`. Only frames that are at most one virtual
+    /// exposure apart (i.e: `num_frames_per_chunk` granular frames) are compared.
+    ///
+    /// Frames are streamed from the source, and gains are estimated on thumbnails whose longest
+    /// side is `GAIN_THUMBNAIL_SIZE` pixels, as only mean intensities over overlaps are needed.
+    pub fn estimate_gains(&self, mappings: &[Mapping], verbose: bool) -> Result<Array1<f32>> {
+        let (w, h) = self.frame_size;
+        let scale = (w.max(h) as f32 / GAIN_THUMBNAIL_SIZE as f32).max(1.0);
+        let (thumb_w, thumb_h) = (
+            ((w as f32 / scale).round() as u32).max(1),
+            ((h as f32 / scale).round() as u32).max(1),
+        );
+
+        let thumbnails = self
+            .frames()
+            .map(|frame| {
+                let thumbnail = resize(&frame?, thumb_w, thumb_h, FilterType::Triangle);
+                Ok(image_to_array3(thumbnail).mapv(f32::from))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mappings: Vec<_> = mappings.iter().map(|m| m.rescale(scale)).collect();
+
+        let gains = gain_compensation(
+            &mappings,
+            &thumbnails,
+            false,
+            None,
+            None,
+            Some(self.num_frames_per_chunk()),
+            verbose.then_some("Compensating Gains..."),
+        )?
+        .index_axis_move(Axis(1), 0);
+        if verbose {
+            println!(
+                "Estimated gains range from {:.4} to {:.4}.",
+                gains.fold(f32::INFINITY, |a, b| a.min(*b)),
+                gains.fold(-f32::INFINITY, |a, b| a.max(*b))
+            );
+        }
+        Ok(gains)
+    }
+
+    /// Warp every bitplane of the photoncube individually with the pairwise mappings interpolated to
+    /// every bitplane (see `bitplane_mappings`), and splat them onto a canvas, see `splat_bitplanes`.
+    /// Hot/dead pixels and pixels outside of the blending mask are excluded.
+    pub fn splat(&self, pairwise: &[Mapping], message: Option<&str>) -> Result<PhotonCounts> {
+        let cube = self
+            .cube()
+            .ok_or(anyhow!("Bitplanes can only be splatted from photoncubes."))?;
+        let view = cube.view()?;
+        let slice = self.config.slice(&view);
+
+        // Exclude any hot/dead pixels and pixels outside the blending mask, at full resolution
+        let mut valid = cube.inpaint_mask.as_ref().map(|m| m.mapv(|v| !v));
+        if let Some(path) = &self.config.blend_mask {
+            let mask = image_to_array3(ImageReader::open(path)?.decode()?.into_luma8())
+                .index_axis_move(Axis(2), 0)
+                .mapv(|v| v > 0);
+            if mask.dim() != self.full_size {
+                return Err(anyhow!("Blend mask and frames need to be of same size."));
+            }
+            valid = Some(valid.map_or(mask.clone(), |v| v & &mask));
+        }
+
+        splat_bitplanes(
+            &self.bitplane_mappings(pairwise),
+            &slice,
+            self.config.bitpacked,
+            self.config.downscale,
+            valid.as_ref(),
+            None,
+            message,
+        )
+    }
+
+    /// Save a panorama rendered by `render`. Color panoramas only keep their first three (RGB) channels,
+    /// and are sRGB encoded with 8 bits per channel if `tonemap2srgb` is enabled, otherwise they are saved
+    /// in linear space with 16 bits per channel, which requires a format such as PNG or TIFF.
+    pub fn save_canvas<P: AsRef<Path>>(&self, canvas: &PanoCanvas, path: P) -> Result<()> {
+        match canvas {
+            PanoCanvas::Gray(img) => img.save(path)?,
+            PanoCanvas::Color(canvas) => {
+                let rgb = canvas.slice(s![.., .., 0..3]);
+                if self.config.tonemap2srgb {
+                    let rgb = rgb.mapv(|v| <u8 as Clamp<f32>>::clamp(linear_to_srgb(v) * 255.0));
+                    array3_to_image::<Rgb<u8>>(rgb).save(path)?;
+                } else {
+                    let rgb = rgb.mapv(|v| (v.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16);
+                    array3_to_image::<Rgb<u16>>(rgb).save(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Load a mask with the same size as the bitplanes, and apply the same downscaling
+    /// and transforms as the granular frames. Values are normalized to [0, 1].
+    pub fn load_mask<P: AsRef<Path>>(&self, path: P) -> Result<Array2<f32>> {
+        let mut mask = ImageReader::open(path)?.decode()?.into_luma8();
+
+        if self.config.downscale != 1.0 {
+            mask = resize(
+                &mask,
+                (mask.width() as f32 / self.config.downscale).round() as u32,
+                (mask.height() as f32 / self.config.downscale).round() as u32,
+                FilterType::CatmullRom,
+            );
+        }
+        let mask = apply_transforms(mask, &self.config.transforms[..]);
+
+        if mask.dimensions() != self.frame_size {
+            return Err(anyhow!("Mask and frames need to be of same size."));
+        }
+        Ok(image_to_array3(mask)
+            .mapv(|v| v as f32 / 255.0)
+            .index_axis_move(Axis(2), 0))
+    }
+}
+
+// --------------------------------------------------------------- Python Interface ---------------------------------------------------------------
+#[pymethods]
+impl PanoBuilder {
+    /// Configure the panorama pipeline, defaults match those of the CLI.
+    /// Transforms are given by name (i.e: "flip-ud"), see the CLI's help for options.
+    #[new]
+    #[pyo3(signature = (
+        burst_size=256, granularity=8, step=1, wrt=0.5, start=None, end=None, max_lvls=8, min_size=16,
+        downscale=1.0, iterations=250, early_stop=1e-3, patience=10, transforms=vec![], bitpacked=true,
+        colorspad_fix=false, cfa_path=None, inpaint_paths=vec![], color=false,
+        demosaic=DemosaicMethod::Bilinear, window=512, checkpoint_dir=None, resume=false,
+        start_level=None, init_mappings=None, bundle_adjust=None,
+        loop_closure=false, mosaic=false, blend_mask=None, gain_compensation=false, layers=false,
+        upscale=1.0, pixfrac=1.0, invert_response=false, dark_count=None, bitplane_exact=false,
+        tonemap2srgb=false, pixel_selection=None, pixel_budget=4096,
+        pyramid_filter=PyramidFilter::Box, gradient_operator=GradientOperator::Prewitt,
+        gradient_sigma=1.0, smoothing=None, pyramid_scale=2.0
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_py(
+        burst_size: usize,
+        granularity: usize,
+        step: usize,
+        wrt: f32,
+        start: Option<usize>,
+        end: Option<isize>,
+        max_lvls: u32,
+        min_size: usize,
+        downscale: f32,
+        iterations: u32,
+        early_stop: f32,
+        patience: u32,
+        transforms: Vec<String>,
+        bitpacked: bool,
+        colorspad_fix: bool,
+        cfa_path: Option<PathBuf>,
+        inpaint_paths: Vec<PathBuf>,
+        color: bool,
+        demosaic: DemosaicMethod,
+        window: usize,
+        checkpoint_dir: Option<PathBuf>,
+        resume: bool,
+        start_level: Option<u32>,
+        init_mappings: Option<Vec<Mapping>>,
+        bundle_adjust: Option<usize>,
+        loop_closure: bool,
+        mosaic: bool,
+        blend_mask: Option<PathBuf>,
+        gain_compensation: bool,
+        layers: bool,
+        upscale: f32,
+        pixfrac: f32,
+        invert_response: bool,
+        dark_count: Option<f32>,
+        bitplane_exact: bool,
+        tonemap2srgb: bool,
+        pixel_selection: Option<PixelSelection>,
+        pixel_budget: usize,
+        pyramid_filter: PyramidFilter,
+        gradient_operator: GradientOperator,
+        gradient_sigma: f32,
+        smoothing: Option<f32>,
+        pyramid_scale: f32,
+    ) -> PyResult<Self> {
+        let transforms = transforms
+            .iter()
+            .map(|t| Transform::from_str(t, true).map_err(|e| anyhow!(e)))
+            .collect::<Result<Vec<_>>>()?;
+        let builder = Self::new()
+            .burst_size(burst_size)
+            .granularity(granularity)
+            .step(step)
+            .wrt(wrt)
+            .range(start, end)
+            .levels(max_lvls, min_size)
+            .downscale(downscale)
+            .lk_settings(iterations, early_stop, patience)
+            .pixel_selection(pixel_selection.map(|method| (method, pixel_budget)))
+            .pyramid_filter(pyramid_filter)
+            .pyramid_scale(pyramid_scale)
+            .gradients(GradientSettings {
+                operator: gradient_operator,
+                sigma: gradient_sigma,
+                smoothing,
+            })
+            .transforms(transforms)
+            .bitpacked(bitpacked)
+            .colorspad_fix(colorspad_fix)
+            .cfa_path(cfa_path)
+            .inpaint_paths(inpaint_paths)
+            .color(color)
+            .demosaic(demosaic)
+            .window(window)
+            .checkpoint_dir(checkpoint_dir)
+            .resume(resume)
+            .start_from(start_level, init_mappings)
+            .bundle_adjust(bundle_adjust)
+            .loop_closure(loop_closure)
+            .mosaic(mosaic)
+            .blend_mask(blend_mask)
+            .gain_compensation(gain_compensation)
+            .layers(layers)
+            .drizzle(upscale, pixfrac)
+            .invert_response(invert_response, dark_count)
+            .bitplane_exact(bitplane_exact)
+            .tonemap2srgb(tonemap2srgb);
+        builder.validate()?;
+        Ok(builder)
+    }
+
+    /// Run the whole pipeline on the photoncube (or image sequence/video) at `path`. Returns a
+    /// dictionary with the pairwise mappings of every level ("levels", coarsest first), the final
+    /// pairwise mappings ("pairwise"), the mappings interpolated to every granular frame
+    /// ("interpolated"), and the panorama rendered by `Pano::render` ("canvas") as a (h, w, 1) uint8
+    /// array, or as a (h, w, c) float32 array of linear intensities if color is enabled. If layers
+    /// are enabled, they are also returned as a dictionary of arrays ("layers"), and if gain
+    /// compensation is enabled, the gain of every granular frame is returned too ("gains").
+    #[pyo3(name = "run", signature = (path, verbose=false))]
+    pub fn run_py<'py>(
+        &self,
+        py: Python<'py>,
+        path: PathBuf,
+        verbose: bool,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let _defer = DeferredSignal::new(py, "SIGINT")?;
+
+        let pano = self.build(path)?;
+        let mappings = pano.register(verbose, None)?;
+        let PanoRender {
+            canvas,
+            layers,
+            gains,
+        } = pano.render(&mappings, false, verbose)?;
+
+        let dict = PyDict::new_bound(py);
+        match canvas {
+            PanoCanvas::Gray(img) => {
+                dict.set_item("canvas", image_to_array3(img).to_pyarray_bound(py))?
+            }
+            PanoCanvas::Color(canvas) => dict.set_item("canvas", canvas.to_pyarray_bound(py))?,
+        }
+        if let Some(layers) = layers {
+            dict.set_item("layers", layers.to_pydict(py)?)?;
+        }
+        if let Some(gains) = gains {
+            dict.set_item("gains", gains.to_pyarray_bound(py))?;
+        }
+        dict.set_item("levels", mappings.levels.into_py(py))?;
+        dict.set_item("pairwise", mappings.pairwise.into_py(py))?;
+        dict.set_item("interpolated", mappings.interpolated.into_py(py))?;
+        Ok(dict)
+    }
+}
+
+#[cfg(test)]
+mod test_pano {
+    use approx::assert_relative_eq;
+    use ffmpeg_sidecar::command::FfmpegCommand;
+    use image::{GrayImage, Luma};
+    use ndarray::{array, Array2};
+    use photoncube2video::ffmpeg::ensure_ffmpeg;
+    use tempfile::tempdir;
+
+    use crate::{
+        blend::srgb_to_linear,
+        pano::{arrays_to_mappings, load_gray, mappings_to_arrays, PanoBuilder, PanoSource},
+        warps::{Mapping, TransformationType},
+    };
+
+    #[test]
+    fn test_mapping_arrays_roundtrip() {
+        let maps = vec![
+            Mapping::from_params(vec![1.0, 2.0]),
+            Mapping::from_matrix(
+                array![[1.1, 0.1, 3.0], [-0.2, 0.9, 4.0], [0.01, 0.0, 1.0]],
+                TransformationType::Projective,
+            ),
+        ];
+        let (mats, kinds) = mappings_to_arrays(&maps);
+        assert_eq!(mats.dim(), (2, 3, 3));
+
+        let loaded = arrays_to_mappings(&mats, &kinds).unwrap();
+        for (a, b) in maps.iter().zip(loaded) {
+            assert_eq!(a.kind, b.kind);
+            assert_eq!(a.mat, b.mat);
+        }
+    }
+
+    #[test]
+    fn test_bounds() {
+        assert_eq!(PanoBuilder::new().bounds(10), 0..10);
+        assert_eq!(PanoBuilder::new().range(Some(2), Some(-3)).bounds(10), 2..7);
+        assert_eq!(
+            PanoBuilder::new().range(Some(4), Some(20)).bounds(10),
+            4..10
+        );
+        assert_eq!(PanoBuilder::new().range(Some(8), Some(5)).bounds(10), 8..8);
+    }
+
+    #[test]
+    fn test_load_gray() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("frame.png");
+        GrayImage::from_pixel(4, 4, Luma([128]))
+            .save(&path)
+            .unwrap();
+
+        // Frames are linearized, like photoncube frames
+        assert_relative_eq!(
+            load_gray(&path).unwrap(),
+            Array2::from_elem((4, 4), srgb_to_linear(128.0 / 255.0)),
+            epsilon = 1e-4
+        );
+    }
+
+    #[test]
+    fn test_open_video() {
+        // Encode a few frames with distinct intensities into a lossless video
+        let dir = tempdir().unwrap();
+        let values: Vec<u8> = (0..5).map(|i| 40 * i + 20).collect();
+        for (i, v) in values.iter().enumerate() {
+            GrayImage::from_pixel(32, 16, Luma([*v]))
+                .save(dir.path().join(format!("in{i:02}.png")))
+                .unwrap();
+        }
+        let video = dir.path().join("video.mkv");
+        ensure_ffmpeg(true);
+        let mut child = FfmpegCommand::new()
+            .hide_banner()
+            .args(["-framerate", "10"])
+            .input(dir.path().join("in%02d.png").to_str().unwrap())
+            .args(["-c:v", "ffv1"])
+            .output(video.to_str().unwrap())
+            .spawn()
+            .unwrap();
+        child.iter().unwrap().for_each(drop);
+        assert!(child.wait().unwrap().success());
+
+        // Every frame is extracted, in order
+        let PanoSource::Sequence {
+            paths,
+            _tmp_dir: tmp_dir,
+        } = PanoBuilder::new().open_source(&video).unwrap()
+        else {
+            panic!("Videos should be opened as a sequence of frames.");
+        };
+        assert_eq!(paths.len(), values.len());
+        assert!(tmp_dir.is_some());
+        for (path, v) in paths.iter().zip(values) {
+            let gray = load_gray(path).unwrap();
+            assert_eq!(gray.dim(), (16, 32));
+            assert_relative_eq!(
+                gray[(8, 16)],
+                srgb_to_linear(v as f32 / 255.0),
+                epsilon = 1e-2
+            );
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use ndarray::{array, Array3, ArrayBase, ArrayView3, Axis, CowArray, Ix3, RawData};
+use pyo3::prelude::*;
+use rayon::prelude::*;
+use strum_macros::Display;
+
+use crate::{
+    lk::{gradients, GradientOperator},
+    warps::{Mapping, TransformationType},
+};
+
+/// Number of standard deviations after which the Gaussian prefilter is truncated.
+const GAUSSIAN_TRUNCATE: f32 = 3.0;
+
+#[pyclass]
+#[derive(Copy, Clone, Debug, Display, ValueEnum, PartialEq)]
+pub enum PyramidFilter {
+    Box,      // Area averaging, i.e: a 2x2 box filter when halving the size
+    Gaussian, // Gaussian prefilter with a standard deviation of a third of the scale factor
+}
+
+/// Normalized Gaussian kernel, truncated at `GAUSSIAN_TRUNCATE` standard deviations.
+pub fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (GAUSSIAN_TRUNCATE * sigma).ceil().max(1.0) as isize;
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.iter().map(|v| v / total).collect()
+}
+
+/// Reflect an index into [0, len), such that borders are repeated, i.e: (d c b a | a b c d).
+fn reflect(i: isize, len: usize) -> usize {
+    let period = 2 * len as isize;
+    let i = i.rem_euclid(period);
+    (if i < len as isize { i } else { period - 1 - i }) as usize
+}
+
+/// Resampling taps along an axis, as (input index, weight) pairs for every output index.
+/// Pixel centers are aligned, and weights are normalized, which handles truncated kernels.
+fn axis_taps(len_in: usize, len_out: usize, filter: PyramidFilter) -> Vec<Vec<(usize, f32)>> {
+    let ratio = len_in as f32 / len_out as f32;
+
+    (0..len_out)
+        .map(|o| {
+            // Center of output sample in input pixel coordinates
+            let center = (o as f32 + 0.5) * ratio - 0.5;
+
+            let mut taps: Vec<(usize, f32)> = if ratio <= 1.0 {
+                // Upsampling, linearly interpolate between closest input samples
+                let center = center.clamp(0.0, (len_in - 1) as f32);
+                let left = center.floor();
+                let l = left as usize;
+                vec![
+                    (l, 1.0 - (center - left)),
+                    ((l + 1).min(len_in - 1), center - left),
+                ]
+            } else {
+                match filter {
+                    PyramidFilter::Box => {
+                        let (start, end) = (o as f32 * ratio, (o + 1) as f32 * ratio);
+                        (start.floor() as usize..(end.ceil() as usize).min(len_in))
+                            .map(|i| (i, end.min(i as f32 + 1.0) - start.max(i as f32)))
+                            .filter(|(_, w)| *w > 0.0)
+                            .collect()
+                    }
+                    PyramidFilter::Gaussian => {
+                        let sigma = ratio / 3.0;
+                        let radius = (GAUSSIAN_TRUNCATE * sigma).ceil() as isize;
+                        let closest = center.round() as isize;
+                        ((closest - radius).max(0)..=(closest + radius).min(len_in as isize - 1))
+                            .map(|i| {
+                                let d = i as f32 - center;
+                                (i as usize, (-d * d / (2.0 * sigma * sigma)).exp())
+                            })
+                            .collect()
+                    }
+                }
+            };
+
+            let total: f32 = taps.iter().map(|(_, w)| w).sum();
+            taps.iter_mut().for_each(|(_, w)| *w /= total);
+            taps
+        })
+        .collect()
+}
+
+/// Resample an array along an axis given the taps of every output index, see `axis_taps`.
+fn resample_axis(im: ArrayView3<f32>, axis: Axis, taps: &[Vec<(usize, f32)>]) -> Array3<f32> {
+    let mut shape = im.raw_dim();
+    shape[axis.index()] = taps.len();
+    let mut out = Array3::zeros(shape);
+
+    out.axis_iter_mut(axis)
+        .into_par_iter()
+        .zip(taps)
+        .for_each(|(mut lane, taps)| {
+            for (i, weight) in taps {
+                lane.scaled_add(*weight, &im.index_axis(axis, *i));
+            }
+        });
+    out
+}
+
+/// Resize an HxWxC array to the given (height, width) using a separable filter. Downsampling
+/// uses the filter to avoid aliasing, while upsampling linearly interpolates. Pixel centers are
+/// aligned, so arbitrary sizes, including odd ones, are handled without dropping edge pixels.
+pub fn resize_array3<S>(
+    im: &ArrayBase<S, Ix3>,
+    size: (usize, usize),
+    filter: PyramidFilter,
+) -> Array3<f32>
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    let (h, w) = size;
+    let (h_in, w_in, _) = im.dim();
+    let resized = resample_axis(im.view(), Axis(0), &axis_taps(h_in, h, filter));
+    resample_axis(resized.view(), Axis(1), &axis_taps(w_in, w, filter))
+}
+
+/// Blur an HxWxC array with a separable Gaussian kernel, borders are reflected.
+pub fn gaussian_blur<S>(im: &ArrayBase<S, Ix3>, sigma: f32) -> Array3<f32>
+where
+    S: RawData<Elem = f32> + ndarray::Data,
+{
+    let kernel = gaussian_kernel(sigma);
+    let radius = (kernel.len() / 2) as isize;
+    let taps = |len: usize| -> Vec<Vec<(usize, f32)>> {
+        (0..len as isize)
+            .map(|o| {
+                kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| (reflect(o + k as isize - radius, len), *w))
+                    .collect()
+            })
+            .collect()
+    };
+
+    let (h, w, _) = im.dim();
+    let blurred = resample_axis(im.view(), Axis(0), &taps(h));
+    resample_axis(blurred.view(), Axis(1), &taps(w))
+}
+
+/// Image pyramid, with the largest size first and every level downscaled by a constant factor,
+/// which does not need to be an integer (e.g: √2). Level sizes are rounded from the size of the
+/// image, and every level is resampled from the previous one with the exact ratio of their sizes.
+/// The first level borrows the image.
+///
+/// Laplacian and gradient pyramids can be derived from it, see `laplacian` and `gradients`.
+pub struct Pyramid<'a> {
+    levels: Vec<CowArray<'a, f32, Ix3>>,
+    scale: f32,
+}
+
+impl<'a> Pyramid<'a> {
+    /// Create a pyramid, downscaling by `scale` until either `max_levels` downscaled levels are
+    /// created or the next level would be smaller than the minimum (width, height).
+    pub fn new<S>(
+        im: &'a ArrayBase<S, Ix3>,
+        scale: f32,
+        filter: PyramidFilter,
+        min_dimensions: (usize, usize),
+        max_levels: u32,
+    ) -> Result<Self>
+    where
+        S: RawData<Elem = f32> + ndarray::Data,
+    {
+        if scale.is_nan() || scale <= 1.0 {
+            return Err(anyhow!(
+                "Pyramid scale factor should be larger than one, got {scale}."
+            ));
+        }
+        let (h, w, _) = im.dim();
+        let (min_width, min_height) = min_dimensions;
+        let mut levels = vec![CowArray::from(im.view())];
+
+        for lvl in 1..=max_levels {
+            let downscale = scale.powi(lvl as i32);
+            let (lvl_w, lvl_h) = (w as f32 / downscale, h as f32 / downscale);
+            if lvl_w < min_width as f32 || lvl_h < min_height as f32 {
+                break;
+            }
+            let size = (
+                (lvl_h.round() as usize).max(1),
+                (lvl_w.round() as usize).max(1),
+            );
+            let level = resize_array3(&levels[levels.len() - 1], size, filter);
+            levels.push(CowArray::from(level));
+        }
+        Ok(Self { levels, scale })
+    }
+
+    /// Create a pyramid with a single level, which borrows the image.
+    pub fn single<S>(im: &'a ArrayBase<S, Ix3>) -> Self
+    where
+        S: RawData<Elem = f32> + ndarray::Data,
+    {
+        Self {
+            levels: vec![CowArray::from(im.view())],
+            scale: 1.0,
+        }
+    }
+
+    /// Blur every level with a Gaussian kernel of the given standard deviation, in pixels of
+    /// the level, see `gaussian_blur`. Non-positive values leave the pyramid untouched.
+    pub fn smoothed(self, sigma: f32) -> Self {
+        if sigma <= 0.0 {
+            return self;
+        }
+        let levels = self
+            .levels
+            .iter()
+            .map(|level| CowArray::from(gaussian_blur(level, sigma)))
+            .collect();
+        Self { levels, ..self }
+    }
+
+    /// Levels of the pyramid, largest first.
+    pub fn levels(&self) -> &[CowArray<'a, f32, Ix3>] {
+        &self.levels
+    }
+
+    /// Nominal scale factor between consecutive levels.
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Consume the pyramid and return owned levels.
+    pub fn into_levels(self) -> Vec<Array3<f32>> {
+        self.levels.into_iter().map(|l| l.into_owned()).collect()
+    }
+
+    /// Mapping from the pixel coordinates of a level to those of the previous (larger) level.
+    /// It uses the exact ratio of their sizes, and accounts for the alignment of pixel centers.
+    pub fn upsampling(&self, level: usize) -> Mapping {
+        let (h_fine, w_fine, _) = self.levels[level - 1].dim();
+        let (h, w, _) = self.levels[level].dim();
+        let (sx, sy) = (w_fine as f32 / w as f32, h_fine as f32 / h as f32);
+
+        Mapping::from_matrix(
+            array![
+                [sx, 0.0, (sx - 1.0) / 2.0],
+                [0.0, sy, (sy - 1.0) / 2.0],
+                [0.0, 0.0, 1.0]
+            ],
+            TransformationType::Affine,
+        )
+    }
+
+    /// Laplacian pyramid, where every level is the difference between a level and the upsampled
+    /// next one, except for the last level which is kept as is. See `collapse` for the inverse.
+    pub fn laplacian(&self) -> Vec<Array3<f32>> {
+        self.levels
+            .windows(2)
+            .map(|pair| {
+                let (h, w, _) = pair[0].dim();
+                &pair[0] - &resize_array3(&pair[1], (h, w), PyramidFilter::Box)
+            })
+            .chain(self.levels.last().map(|level| level.to_owned()))
+            .collect()
+    }
+
+    /// Reconstruct the largest level of a pyramid from its Laplacian pyramid, see `laplacian`.
+    pub fn collapse(bands: &[Array3<f32>]) -> Option<Array3<f32>> {
+        let (last, bands) = bands.split_last()?;
+        Some(bands.iter().rev().fold(last.clone(), |acc, band| {
+            let (h, w, _) = band.dim();
+            band + &resize_array3(&acc, (h, w), PyramidFilter::Box)
+        }))
+    }
+
+    /// Gradient pyramid, as the (dx, dy) gradients of every level, see `lk::gradients`.
+    pub fn gradients(
+        &self,
+        operator: GradientOperator,
+        sigma: f32,
+    ) -> Vec<(Array3<f32>, Array3<f32>)> {
+        self.levels
+            .iter()
+            .map(|level| gradients(level, operator, sigma))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test_pyramid {
+    use approx::assert_relative_eq;
+    use ndarray::{s, Array3};
+
+    use crate::pyramid::{gaussian_blur, Pyramid, PyramidFilter};
+
+    #[test]
+    fn test_box_matches_decimation() {
+        let im = Array3::from_shape_fn((32, 48, 2), |(y, x, c)| ((x * 7 + y * 3 + c) % 11) as f32);
+        let pyramid = Pyramid::new(&im, 2.0, PyramidFilter::Box, (4, 4), 8).unwrap();
+        let expected = (im.slice(s![0..;2, 0..;2, ..]).to_owned()
+            + im.slice(s![0..;2, 1..;2, ..])
+            + im.slice(s![1..;2, 0..;2, ..])
+            + im.slice(s![1..;2, 1..;2, ..]))
+            / 4.0;
+
+        assert_eq!(pyramid.levels().len(), 4);
+        assert_relative_eq!(pyramid.levels()[1], expected, epsilon = 1e-5);
+    }
+
+    #[test]
+    fn test_odd_and_fractional_sizes() {
+        let im = Array3::from_elem((30, 66, 1), 3.0f32);
+
+        for filter in [PyramidFilter::Box, PyramidFilter::Gaussian] {
+            let pyramid = Pyramid::new(&im, 2f32.sqrt(), filter, (8, 8), 8).unwrap();
+            let sizes: Vec<_> = pyramid.levels().iter().map(|l| l.dim()).collect();
+            assert_eq!(sizes, [(30, 66, 1), (21, 47, 1), (15, 33, 1), (11, 23, 1)]);
+
+            // Constant images stay constant, including at the edges
+            for level in pyramid.levels() {
+                assert_relative_eq!(*level, Array3::from_elem(level.dim(), 3.0), epsilon = 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_laplacian_roundtrip() {
+        let im = Array3::from_shape_fn((37, 50, 3), |(y, x, c)| ((x * x + y * 5 + c) % 17) as f32);
+        let pyramid = Pyramid::new(&im, 2.0, PyramidFilter::Gaussian, (4, 4), 8).unwrap();
+        let bands = pyramid.laplacian();
+
+        assert_eq!(bands.len(), pyramid.levels().len());
+        assert_relative_eq!(Pyramid::collapse(&bands).unwrap(), im, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn test_gaussian_blur() {
+        // Blurring an impulse away from the borders gives a symmetric kernel that sums to one
+        let mut im = Array3::zeros((15, 15, 1));
+        im[(7, 7, 0)] = 1.0f32;
+        let blurred = gaussian_blur(&im, 1.5);
+        assert_eq!(blurred.dim(), im.dim());
+        assert_relative_eq!(blurred.sum(), 1.0, epsilon = 1e-5);
+        assert_relative_eq!(blurred[(7, 6, 0)], blurred[(6, 7, 0)], epsilon = 1e-6);
+        assert_relative_eq!(blurred[(7, 6, 0)], blurred[(7, 8, 0)], epsilon = 1e-6);
+        assert!(blurred[(7, 7, 0)] > blurred[(7, 8, 0)]);
+
+        // Constants are preserved, including at the borders
+        let constant = Array3::from_elem((5, 7, 2), 2.0f32);
+        assert_relative_eq!(gaussian_blur(&constant, 2.0), constant, epsilon = 1e-5);
+    }
+}
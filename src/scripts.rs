@@ -6,26 +6,14 @@ use image::{
     io::Reader as ImageReader,
     GrayImage, Rgb,
 };
-use indicatif::{ParallelProgressIterator, ProgressStyle};
-use ndarray::{Array1, Axis, Slice};
-use photoncube2video::{
-    cube::PhotonCube,
-    signals::DeferredSignal,
-    transforms::{
-        apply_transforms, array2_to_grayimage, image_to_array3, interpolate_where_mask,
-        process_colorspad, unpack_single,
-    },
-};
+use ndarray_npy::write_npy;
+use photoncube2video::signals::DeferredSignal;
 use pyo3::prelude::*;
-use rayon::{
-    iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator},
-    slice::ParallelSlice,
-};
 
 use crate::{
-    blend::merge_images,
     cli::{Cli, Commands, LKArgs, Parser},
-    lk::{iclk, pairwise_iclk},
+    lk::{iclk, GradientSettings, LKSettings, LevelSettings},
+    pano::{load_pairwise_mappings, PanoBuilder, PanoMappings},
     utils::{animate_warp, stabilized_video},
     warps::Mapping,
 };
@@ -75,6 +63,42 @@ fn match_imgpair(global_args: Cli, lk_args: LKArgs) -> Result<()> {
         FilterType::CatmullRom,
     );
 
+    // Assemble coarse-to-fine schedule if any
+    for (name, len) in [
+        ("--schedule-iterations", lk_args.schedule_iterations.len()),
+        ("--schedule-early-stop", lk_args.schedule_early_stop.len()),
+    ] {
+        if len != 0 && len != lk_args.schedule.len() {
+            return Err(anyhow!(
+                "Expected one value per level of --schedule for {name}, got {len} values for {} levels.",
+                lk_args.schedule.len()
+            ));
+        }
+    }
+    let schedule: Vec<LevelSettings> = lk_args
+        .schedule
+        .iter()
+        .enumerate()
+        .map(|(i, kind)| LevelSettings {
+            kind: *kind,
+            max_iters: lk_args.schedule_iterations.get(i).copied(),
+            stop_early: lk_args.schedule_early_stop.get(i).copied(),
+        })
+        .collect();
+    let settings = LKSettings {
+        pyramid_filter: lk_args.pyramid_filter,
+        scale: lk_args.pyramid_scale,
+        gradients: GradientSettings {
+            operator: lk_args.gradient_operator,
+            sigma: lk_args.gradient_sigma,
+            smoothing: lk_args.smoothing,
+        },
+        pixel_selection: lk_args
+            .pixel_selection
+            .map(|method| (method, lk_args.pixel_budget)),
+        schedule,
+    };
+
     // Conditionally convert images to grayscale, then register images
     let (mapping, params_history) = if lk_args.grayscale {
         let img1 = grayscale(&img1);
@@ -90,6 +114,7 @@ fn match_imgpair(global_args: Cli, lk_args: LKArgs) -> Result<()> {
             Some(lk_args.max_lvls),
             Some(lk_args.early_stop),
             Some(lk_args.patience),
+            Some(&settings),
             true,
         )?
     } else {
@@ -104,6 +129,7 @@ fn match_imgpair(global_args: Cli, lk_args: LKArgs) -> Result<()> {
             Some(lk_args.max_lvls),
             Some(lk_args.early_stop),
             Some(lk_args.patience),
+            Some(&settings),
             true,
         )?
     };
@@ -163,277 +189,84 @@ pub fn cli_entrypoint(py: Python) -> Result<()> {
         Commands::LK(lk_args) => match_imgpair(args.clone(), lk_args.clone()),
         Commands::Pano(pano_args) => {
             // Validate CLI args
-            let [cube_path, ..] = &args.input[..] else {
+            let [input_path, ..] = &args.input[..] else {
                 return Err(anyhow!(
                     "Only one input is required for --input when forming Pano."
                 ));
             };
-            if pano_args.burst_size <= pano_args.granularity {
+            if !pano_args.lk_args.schedule.is_empty() {
                 return Err(anyhow!(
-                    "Argument `granularity` must be smaller than `burst-size`."
+                    "Argument --schedule is not supported when forming Pano, mapping types are upgraded at every level instead."
                 ));
             }
-            if pano_args.burst_size % pano_args.granularity != 0 {
+            if pano_args.gains_out.is_some() && !pano_args.gain_compensation {
                 return Err(anyhow!(
-                    "Argument `granularity` must evenly divide `burst-size`."
+                    "Argument --gains-out requires --gain-compensation."
                 ));
             }
 
-            // Load and pre-process chunks of frames from photoncube
-            // We unpack the bitplanes, average them in groups of `burst_size`,
-            // Apply colorspad corrections, and optionally downscale.
-            // Any transforms (i.e: flip-ud) can be applied here too.
-            let mut cube = PhotonCube::open(cube_path)?;
-            if let Some(cfa_path) = &pano_args.cfa_path {
-                cube.load_cfa(cfa_path.to_path_buf())?;
-            }
-            for inpaint_path in pano_args.inpaint_path.iter() {
-                cube.load_mask(inpaint_path.to_path_buf())?;
-            }
-
-            let view = cube.view()?;
-            let slice = view.slice_axis(
-                Axis(0),
-                Slice::new(
-                    pano_args.start.unwrap_or(0) as isize,
-                    pano_args.end.map(|v| v.min(view.len_of(Axis(0)) as isize)),
-                    1,
-                ),
-            );
-
-            let (_, h, w) = view.dim();
-            let w = w * 8;
-            let (lvls_h, lvls_w) = (
-                f32::log2(
-                    h as f32 / pano_args.lk_args.downscale / pano_args.lk_args.min_size as f32,
-                )
-                .ceil(),
-                f32::log2(
-                    w as f32 / pano_args.lk_args.downscale / pano_args.lk_args.min_size as f32,
-                )
-                .ceil(),
-            );
-            let num_lvls = (lvls_h as u32)
-                .min(lvls_w as u32)
-                .min(pano_args.lk_args.max_lvls);
-
-            let num_ves = (slice.len_of(Axis(0)) / pano_args.burst_size) / pano_args.step;
-            let num_frames_per_chunk = pano_args.burst_size / pano_args.granularity;
-            let mut mappings: Vec<Mapping> = vec![Mapping::from_params(vec![0.0; 2]); num_ves - 1];
-            let mut virtual_exposures: Vec<_>;
-            let mut all_mappings = vec![];
-
-            // Preload all data at given granularity
-            let granular_frames: Vec<GrayImage> = slice.axis_chunks_iter(Axis(0), pano_args.granularity)
-                .into_par_iter()
-                .progress()
-                .with_style(
-                    ProgressStyle::with_template(
-                        "{msg} ETA:{eta}, [{elapsed_precise}] {wide_bar:.cyan/blue} {pos:>6}/{len:6}",
-                    )
-                    .expect("Invalid progress style."),
-                )
-                .with_message("Preloading Data...")
-                .map(|group| {
-                    // Iterate over all bitplanes in group,
-                    // Unpack every frame in group as a f32 array
-                    // Apply any corrections and blend them together
-                    let mut frame = group
-                        .axis_iter(Axis(0))
-                        .map(|bitplane| {
-                            if pano_args.not_bitpacked {
-                                bitplane.mapv(|v| v as f32)
-                            } else {
-                                unpack_single::<f32>(&bitplane, 1).unwrap()
-                            }
-                        })
-                        // Sum frames together (.sum not implemented for this type)
-                        .reduce(|acc, e| acc + e)
-                        .unwrap();
-
-                    // Compute mean values
-                    frame.mapv_inplace(|v| v / (pano_args.granularity as f32));
-
-                    // Apply any frame-level fixes (only for ColorSPAD at the moment)
-                    if pano_args.colorspad_fix {
-                        frame = process_colorspad(frame);
-                    }
-
-                    // Demosaic frame by interpolating white pixels
-                    if let Some(mask) = &cube.cfa_mask {
-                        frame = interpolate_where_mask(&frame, mask, false).unwrap();
-                    }
-
-                    // Inpaint any hot/dead pixels
-                    if let Some(mask) = &cube.inpaint_mask {
-                        frame = interpolate_where_mask(&frame, mask, false).unwrap();
-                    }
-
-                    // Convert to img and apply transforms
-                    let mut img = array2_to_grayimage(
-                        frame.mapv(|v| (v * 255.0) as u8),
-                    );
-
-                    if pano_args.lk_args.downscale != 1.0 {
-                        img = resize(
-                            &img,
-                            (w as f32 / pano_args.lk_args.downscale).round() as u32,
-                            (h as f32 / pano_args.lk_args.downscale).round() as u32,
-                            FilterType::CatmullRom,
-                        );
-                    }
-
-                    apply_transforms(img, &args.transform[..])
-                })
-                .collect();
-            let (w, h) = granular_frames[0].dimensions();
+            // Open photoncube (or image sequence/video), frames are loaded on demand
+            let init_mappings = pano_args
+                .init_mappings
+                .as_ref()
+                .map(load_pairwise_mappings)
+                .transpose()?;
+            let pano = PanoBuilder::from_args(&args, pano_args)
+                .start_from(pano_args.start_level, init_mappings)
+                .build(input_path)?;
+            let num_lvls = pano.num_lvls;
 
             // -------------------- Main hierarchical matching process ----------------------------
-            for lvl in (0..num_lvls).rev() {
-                // Interpolate mappings to all bitplanes
-                mappings = mappings.iter().map(|m| m.rescale(0.5)).collect();
-                let acc_maps = Mapping::accumulate(mappings.clone());
-                let interpd_maps = Mapping::interpolate_array(
-                    Array1::linspace(0.0, (num_ves - 1) as f32, num_ves).to_vec(),
-                    acc_maps,
-                    Array1::linspace(0.0, (num_ves - 1) as f32, num_ves * num_frames_per_chunk)
-                        .to_vec(),
-                );
-                let downscale = 1 << lvl;
-
-                // Compute virtual exposure by merging `num_frames_per_chunk` granular frames, and downscaling result
-                virtual_exposures = (
-                    granular_frames.par_chunks(num_frames_per_chunk).step_by(pano_args.step),
-                    interpd_maps.par_chunks(num_frames_per_chunk),
-                )
-                    .into_par_iter()
-                    .progress()
-                    .with_style(
-                        ProgressStyle::with_template(
-                            "{msg} ETA:{eta}, [{elapsed_precise}] {wide_bar:.cyan/blue} {pos:>6}/{len:6}",
-                        )
-                        .expect("Invalid progress style."),
-                    )
-                    .with_message(format!("({}/{}): Loading Data...", num_lvls - lvl, num_lvls))
-                    .map(|(frames, maps)| {
-                        let img = merge_images(
-                            &Mapping::with_respect_to_idx(maps.to_vec(), 0.5),
-                            frames,
-                            Some((w as usize, h as usize)),
-                            None
-                        ).unwrap();
-
-                        resize(
-                            &img,
-                            (w as f32 / downscale as f32).round() as u32,
-                            (h as f32 / downscale as f32).round() as u32,
-                            FilterType::CatmullRom,
-                        )
-                    })
-                    .collect();
-
-                // Estimate pairwise registration
-                // TODO: Fix this needless copying!
-                print!("({}/{}): Matching... ", num_lvls - lvl, num_lvls);
-                (mappings, _) = pairwise_iclk(
-                    &virtual_exposures
-                        .clone()
-                        .into_iter()
-                        .map(|ve| image_to_array3(ve).mapv(f32::from))
-                        .collect(),
-                    &mappings[..],
-                    false,
-                    None,
-                    None,
-                    Some(pano_args.lk_args.iterations),
-                    Some(pano_args.lk_args.early_stop),
-                    Some(pano_args.lk_args.patience),
-                    true,
-                )?;
-                all_mappings.push(mappings.clone());
-                println!("Done.");
-
-                // Augment mapping type every iteration
-                mappings = mappings.iter().map(|m| m.upgrade()).collect();
-
-                if let Some(viz_path) = args.viz_output.clone() {
-                    let parent = Path::new(&viz_path)
-                        .parent()
-                        .expect("Viz output path should have a parent directory");
-                    let file = format!("lvl-{}.mp4", num_lvls - lvl);
-                    let path = parent.join(file);
-                    stabilized_video(
-                        &Mapping::accumulate_wrt_idx(mappings.clone(), pano_args.wrt),
-                        &virtual_exposures,
-                        None,
-                        Some(args.viz_fps),
-                        Some(args.viz_step),
-                        Some(
-                            path.to_str()
-                                .expect("Cannot join output visualization paths"),
-                        ),
-                        Some(
-                            format!("({}/{}): Creating Preview...", num_lvls - lvl, num_lvls)
-                                .as_str(),
-                        ),
-                    )?;
-                }
+            let mut preview =
+                |lvl: u32, mappings: &[Mapping], virtual_exposures: &[GrayImage]| -> Result<()> {
+                    if let Some(viz_path) = args.viz_output.clone() {
+                        let parent = Path::new(&viz_path)
+                            .parent()
+                            .expect("Viz output path should have a parent directory");
+                        let file = format!("lvl-{}.mp4", lvl);
+                        let path = parent.join(file);
+                        stabilized_video(
+                            &Mapping::accumulate_wrt_idx(mappings.to_vec(), pano_args.wrt),
+                            virtual_exposures,
+                            None,
+                            Some(args.viz_fps),
+                            Some(args.viz_step),
+                            Some(
+                                path.to_str()
+                                    .expect("Cannot join output visualization paths"),
+                            ),
+                            Some(format!("({}/{}): Creating Preview...", lvl, num_lvls).as_str()),
+                        )?;
+                    }
+                    Ok(())
+                };
+            let mappings = if let Some(path) = &pano_args.mappings_in {
+                println!("Loading mappings from {}...", path.display());
+                PanoMappings::load(&pano, path)?
+            } else {
+                pano.register(true, Some(&mut preview))?
+            };
+            if let Some(path) = &pano_args.mappings_out {
+                mappings.save(&pano, path)?;
             }
             // ----------------------------------------------------------------------------------
 
-            // Save final panorama
-            // Interpolate mapping to every granular frame
-            let acc_maps = Mapping::accumulate_wrt_idx(mappings.clone(), pano_args.wrt);
-            let interpd_maps = Mapping::interpolate_array(
-                Array1::linspace(0.0, (num_ves - 1) as f32, num_ves).to_vec(),
-                acc_maps,
-                Array1::linspace(0.0, (num_ves - 1) as f32, granular_frames.len()).to_vec(),
-            );
-            let canvas = merge_images(
-                &interpd_maps,
-                &granular_frames,
-                None,
-                Some("Making Panorama..."),
-            )?;
-            canvas.save(&args.output.unwrap_or("out.png".to_string()))?;
-
-            // ----------------------------------------------------------------------------------
+            // Save final panorama, and any auxiliary layers
+            let output = args.output.unwrap_or("out.png".to_string());
+            let render = pano.render(&mappings, false, true)?;
+            pano.save_canvas(&render.canvas, &output)?;
+            if let (Some(layers_dir), Some(layers)) = (&pano_args.layers_dir, &render.layers) {
+                layers.save(layers_dir)?;
+            }
+            if let (Some(gains_path), Some(gains)) = (&pano_args.gains_out, &render.gains) {
+                write_npy(gains_path, gains)?;
+            }
 
             // Save a baseline pano using the first lvl maps
             if let Some(baseline_path) = &pano_args.baseline_path {
-                // Accumulate wrt center frame
-                let acc_maps = Mapping::accumulate_wrt_idx(all_mappings[0].clone(), pano_args.wrt);
-
-                // Scale back to original size
-                let scaled_mappings: Vec<_> = acc_maps
-                    .iter()
-                    .map(|m| m.rescale(1.0 / ((1 << (num_lvls - 1)) as f32)))
-                    .collect();
-
-                // Interpolate from all virtual exposures to all granular frames (if step != 1 these are not equal)
-                let interpd_maps = Mapping::interpolate_array(
-                    Array1::linspace(0.0, (num_ves - 1) as f32, num_ves).to_vec(),
-                    scaled_mappings,
-                    Array1::linspace(0.0, (num_ves - 1) as f32, granular_frames.len()).to_vec(),
-                );
-
-                // Repeat mapping such that it is constant for the duration of a burst frame
-                let interpd_maps: Vec<_> = interpd_maps
-                    .into_iter()
-                    .step_by(num_frames_per_chunk)
-                    .flat_map(|n| std::iter::repeat(n).take(num_frames_per_chunk))
-                    .collect();
-
-                // Create baseline pano and save
-                let canvas = merge_images(
-                    &interpd_maps,
-                    &granular_frames,
-                    None,
-                    Some("Making Baseline Pano..."),
-                )?;
-                canvas.save(baseline_path)?;
+                let render = pano.render(&mappings, true, true)?;
+                pano.save_canvas(&render.canvas, baseline_path)?;
             }
-
             Ok(())
         }
     }
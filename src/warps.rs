@@ -21,6 +21,7 @@ use rayon::{
     iter::{IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator},
     slice::ParallelSliceMut,
 };
+use serde_json::{json, Value};
 use strum::{EnumCount, VariantArray};
 use strum_macros::Display;
 
@@ -373,6 +374,35 @@ impl Mapping {
                 *valid_slice = true;
             });
     }
+
+    /// Serialize mapping as a JSON object with its type and 3x3 matrix.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind.to_string(),
+            "mat": self.mat.outer_iter().map(|row| row.to_vec()).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Deserialize mapping from a JSON object created with `to_json`.
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let kind = value["kind"]
+            .as_str()
+            .ok_or(anyhow!("Mapping should have a `kind` field."))?;
+        let kind = TransformationType::from_str(kind, true)
+            .map_err(|_| anyhow!("Invalid transformation type {kind}."))?;
+        let mat = value["mat"]
+            .as_array()
+            .ok_or(anyhow!("Mapping should have a `mat` field."))?
+            .iter()
+            .flat_map(|row| row.as_array().cloned().unwrap_or_default())
+            .map(|v| v.as_f64().map(|v| v as f32))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(anyhow!("Mapping matrix should only contain numbers."))?;
+        Ok(Self::from_matrix(
+            Array2::from_shape_vec((3, 3), mat)?,
+            kind,
+        ))
+    }
 }
 
 // Note: Methods in this `impl` block are exposed to python
@@ -867,4 +897,13 @@ mod test_warps {
         map = map.downgrade();
         assert!(map.kind == TransformationType::Identity);
     }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let map = Mapping::from_params(vec![1.0, 0.1, -0.2, 1.1, 5.0, -3.0]);
+        let decoded = Mapping::from_json(&map.to_json()).unwrap();
+
+        assert_eq!(decoded.kind, map.kind);
+        assert_relative_eq!(decoded.mat, map.mat);
+    }
 }
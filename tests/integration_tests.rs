@@ -1,9 +1,16 @@
+use std::collections::HashMap;
+
 use approx::assert_relative_eq;
-use image::{io::Reader as ImageReader, Rgb};
-use ndarray::array;
+use image::{io::Reader as ImageReader, Rgb, RgbImage};
+use ndarray::{array, s, Array3};
 use photoncube2video::transforms::image_to_array3;
 use spano::{
-    lk::iclk,
+    blend::{merge_arrays, merge_arrays_iter},
+    lk::{
+        frame_pyramids, gradients, iclk, register_pairs, register_pyramids, FramePyramid,
+        GradientOperator, GradientSettings, LKSettings, LevelSettings, PixelSelection,
+    },
+    pyramid::PyramidFilter,
     warps::{Mapping, TransformationType},
 };
 
@@ -36,6 +43,58 @@ fn test_warp_img() {
     assert_relative_eq!(arr_dst, arr_warped);
 }
 
+/// Source and warped test images, along with the ground truth mapping between them.
+fn lk_fixture() -> (RgbImage, RgbImage, Mapping) {
+    let map = Mapping::from_matrix(
+        array![
+            [0.4479, -0.0426, 79.3745],
+            [-0.1567, 0.6156, 39.4790],
+            [-0.0006, -0.0001, 0.8669]
+        ],
+        TransformationType::Projective,
+    );
+
+    let img_src = ImageReader::open("tests/source.png")
+        .unwrap()
+        .decode()
+        .unwrap()
+        .into_rgb8();
+    let img_dst = ImageReader::open("tests/warped.png")
+        .unwrap()
+        .decode()
+        .unwrap()
+        .into_rgb8();
+    (img_src, img_dst, map)
+}
+
+/// Register the test images with the given settings, and check that the estimated mapping is within
+/// 5% of the ground truth in corner coordinates. Returns the estimate and its parameter history.
+fn check_lk_fixture(settings: Option<&LKSettings>) -> (Mapping, HashMap<u32, Vec<Vec<f32>>>) {
+    let (img_src, img_dst, map) = lk_fixture();
+    let (estimated_map, params_history) = iclk(
+        &img_src,
+        &img_dst,
+        Mapping::from_params(vec![0.0; 8]),
+        None,
+        true,
+        Some(250),
+        Some(25),
+        Some(5),
+        Some(1e-3),
+        None,
+        settings,
+        false,
+    )
+    .unwrap();
+
+    assert_relative_eq!(
+        estimated_map.corners((480, 640)),
+        map.corners((480, 640)),
+        max_relative = 0.05
+    );
+    (estimated_map, params_history)
+}
+
 #[test]
 fn test_lk() {
     let map = Mapping::from_matrix(
@@ -69,6 +128,7 @@ fn test_lk() {
         Some(5),
         Some(1e-3),
         None,
+        None,
         true,
     )
     .unwrap();
@@ -80,3 +140,291 @@ fn test_lk() {
         max_relative = 0.05
     );
 }
+
+#[test]
+fn test_lk_sparse() {
+    let (_, img_dst, _) = lk_fixture();
+    let frame = image_to_array3(img_dst).mapv(|v| v as f32);
+
+    // Only use a fraction of the pixels of the full resolution levels
+    for method in [PixelSelection::Gradient, PixelSelection::ShiTomasi] {
+        let settings = LKSettings {
+            pixel_selection: Some((method, 16384)),
+            ..Default::default()
+        };
+        check_lk_fixture(Some(&settings));
+
+        // Same pyramid as above, where the first three levels (640x480 to 160x120) exceed the budget
+        let pyramid = FramePyramid::new(&frame, true, 2.0, PyramidFilter::Box, (25, 25), 5)
+            .unwrap()
+            .with_selection(settings.pixel_selection);
+        for level in 0..3 {
+            let template = pyramid
+                .template(level, TransformationType::Projective)
+                .unwrap();
+            assert!(template.num_pixels() <= 16384);
+            assert!(template.num_pixels() > 8192);
+        }
+        let template = pyramid.template(3, TransformationType::Projective).unwrap();
+        assert_eq!(template.num_pixels(), 80 * 60);
+    }
+}
+
+#[test]
+fn test_lk_schedule() {
+    // Translation and similarity at the coarsest levels, projective everywhere else
+    let schedule = [
+        (TransformationType::Translational, Some(50)),
+        (TransformationType::Similarity, Some(100)),
+        (TransformationType::Projective, None),
+    ]
+    .map(|(kind, max_iters)| LevelSettings {
+        kind,
+        max_iters,
+        stop_early: None,
+    });
+    let settings = LKSettings {
+        schedule: schedule.to_vec(),
+        ..Default::default()
+    };
+    let (estimated_map, params_history) = check_lk_fixture(Some(&settings));
+
+    // Coarsest level has 2 parameters and at most 50 updates, the next one is a similarity,
+    // and all remaining levels, including full resolution, are projective
+    let coarsest = *params_history.keys().max().unwrap();
+    assert!(params_history[&coarsest].len() <= 51);
+    assert!(params_history[&coarsest].iter().all(|p| p.len() == 2));
+    assert!(params_history[&(coarsest - 1)].len() <= 101);
+    assert!(params_history[&(coarsest - 1)].iter().all(|p| p.len() == 4));
+    for (_, history) in params_history.iter().filter(|(l, _)| **l < coarsest - 1) {
+        assert!(history.iter().all(|p| p.len() == 8));
+    }
+    assert_eq!(estimated_map.kind, TransformationType::Projective);
+}
+
+#[test]
+fn test_lk_schedule_single_level() {
+    let (img_src, img_dst, _) = lk_fixture();
+    let settings = LKSettings {
+        schedule: [
+            TransformationType::Translational,
+            TransformationType::Projective,
+        ]
+        .map(|kind| LevelSettings {
+            kind,
+            max_iters: None,
+            stop_early: None,
+        })
+        .to_vec(),
+        ..Default::default()
+    };
+
+    // With a single level, the finest level still uses the last entry of the schedule
+    let (estimated_map, params_history) = iclk(
+        &img_src,
+        &img_dst,
+        Mapping::from_params(vec![0.0; 8]),
+        None,
+        false,
+        Some(5),
+        None,
+        None,
+        None,
+        None,
+        Some(&settings),
+        false,
+    )
+    .unwrap();
+    assert_eq!(estimated_map.kind, TransformationType::Projective);
+    assert_eq!(params_history.len(), 1);
+    assert!(params_history[&1].iter().all(|p| p.len() == 8));
+}
+
+#[test]
+fn test_merge_streaming() {
+    let frames: Vec<_> = (0..4)
+        .map(|i| Array3::from_shape_fn((24, 32, 1), |(y, x, _)| (x + y * i) as f32))
+        .collect();
+    let maps: Vec<_> = (0..4)
+        .map(|i| Mapping::from_params(vec![i as f32 * 2.5, i as f32]))
+        .collect();
+
+    let merged = merge_arrays(&maps, &frames, None, None, None, None).unwrap();
+    let streamed =
+        merge_arrays_iter(&maps, frames.clone(), (24, 32, 1), None, None, None, None).unwrap();
+    assert_relative_eq!(merged, streamed);
+}
+
+#[test]
+fn test_template_cache() {
+    let frame = Array3::from_shape_fn((64, 48, 1), |(y, x, _)| ((x * 3 + y * 5) % 13) as f32);
+    let pyramid = FramePyramid::new(&frame, true, 2.0, PyramidFilter::Box, (16, 16), 8).unwrap();
+    assert_eq!(pyramid.levels().len(), 2);
+
+    // Template data is computed once per level and kind of mapping
+    let translational = pyramid
+        .template(0, TransformationType::Translational)
+        .unwrap();
+    let projective = pyramid.template(0, TransformationType::Projective).unwrap();
+    assert!(std::sync::Arc::ptr_eq(
+        &translational,
+        &pyramid
+            .template(0, TransformationType::Translational)
+            .unwrap()
+    ));
+    assert!(!std::sync::Arc::ptr_eq(
+        &projective,
+        &pyramid.template(1, TransformationType::Projective).unwrap()
+    ));
+}
+
+#[test]
+fn test_shared_pyramids() {
+    let scene = Array3::from_shape_fn((64, 96, 1), |(y, x, _)| {
+        let (y, x) = (y as f32, x as f32);
+        ((x / 7.0).sin() + (y / 5.0).cos() + ((x + y) / 11.0).sin()) * 50.0 + 128.0
+    });
+    let frames: Vec<_> = (0..3)
+        .map(|i| scene.slice(s![.., 3 * i..3 * i + 64, ..]).to_owned())
+        .collect();
+    let pairs = [(0, 1), (1, 2), (0, 2)];
+    let init = vec![Mapping::from_params(vec![0.0; 2]); pairs.len()];
+
+    // Single scale matching on multi-level pyramids only uses their finest level
+    let pyramids = frame_pyramids(&frames, 0..frames.len(), true, None, None, None).unwrap();
+    let shared = register_pyramids(
+        &pyramids, &pairs, &init, false, None, None, None, None, false,
+    )
+    .unwrap();
+    let expected = register_pairs(
+        &frames, &pairs, &init, false, None, None, None, None, None, None, false,
+    )
+    .unwrap();
+    for pair in pairs {
+        assert_relative_eq!(shared[&pair].0.mat, expected[&pair].0.mat, epsilon = 1e-6);
+    }
+
+    // Template data of the finest level is then shared with multi-scale matching
+    let template = pyramids[&1]
+        .template(0, TransformationType::Translational)
+        .unwrap();
+    register_pyramids(
+        &pyramids, &pairs, &init, true, None, None, None, None, false,
+    )
+    .unwrap();
+    assert!(std::sync::Arc::ptr_eq(
+        &template,
+        &pyramids[&1]
+            .template(0, TransformationType::Translational)
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_pixel_selection() {
+    let frame = Array3::from_shape_fn((64, 48, 2), |(y, x, c)| ((x * 3 + y * (5 + c)) % 13) as f32);
+
+    for method in [
+        PixelSelection::Gradient,
+        PixelSelection::ShiTomasi,
+        PixelSelection::Random,
+    ] {
+        let pyramid = FramePyramid::new(&frame, true, 2.0, PyramidFilter::Box, (16, 16), 8)
+            .unwrap()
+            .with_selection(Some((method, 1000)));
+        let template = pyramid.template(0, TransformationType::Affine).unwrap();
+        assert!(template.num_pixels() <= 1000);
+        assert!(template.num_pixels() > 500);
+
+        // Smaller levels that fit in the budget use all of their pixels
+        let template = pyramid.template(1, TransformationType::Affine).unwrap();
+        assert_eq!(template.num_pixels(), 32 * 24);
+    }
+}
+
+#[test]
+fn test_gradient_operators() {
+    let ramp = Array3::from_shape_fn((20, 24, 1), |(y, x, _)| (2 * x + y) as f32);
+
+    // All operators have the same gain, such that step sizes don't depend on them
+    let operators = [
+        GradientOperator::Prewitt,
+        GradientOperator::Sobel,
+        GradientOperator::Scharr,
+        GradientOperator::Central,
+        GradientOperator::Gaussian,
+    ];
+    for operator in operators {
+        let (dx, dy) = gradients(&ramp, operator, 1.0);
+        let inner = s![4..16, 4..20, ..];
+        assert_relative_eq!(
+            dx.slice(inner),
+            Array3::from_elem((12, 16, 1), 12.0).view(),
+            epsilon = 1e-3
+        );
+        assert_relative_eq!(
+            dy.slice(inner),
+            Array3::from_elem((12, 16, 1), 6.0).view(),
+            epsilon = 1e-3
+        );
+    }
+
+    // But they smooth differently, which shows in their impulse responses
+    let mut impulse = Array3::<f32>::zeros((21, 21, 1));
+    impulse[(10, 10, 0)] = 1.0;
+    let responses = operators.map(|operator| gradients(&impulse, operator, 1.0).0);
+    for (i, (a, op_a)) in responses.iter().zip(operators).enumerate() {
+        for (b, op_b) in responses.iter().zip(operators).skip(i + 1) {
+            let diff = (a - b).mapv(f32::abs).sum();
+            assert!(diff > 1e-3, "{op_a} and {op_b} have the same response");
+        }
+    }
+
+    // And the support of the derivative of Gaussian grows with its standard deviation
+    let support = |sigma| {
+        gradients(&impulse, GradientOperator::Gaussian, sigma)
+            .0
+            .iter()
+            .filter(|v| v.abs() > 1e-6)
+            .count()
+    };
+    assert!(support(2.0) > support(1.0));
+}
+
+#[test]
+fn test_lk_smoothing() {
+    let settings = LKSettings {
+        gradients: GradientSettings {
+            operator: GradientOperator::Sobel,
+            sigma: 1.0,
+            smoothing: Some(1.0),
+        },
+        ..Default::default()
+    };
+    check_lk_fixture(Some(&settings));
+
+    // Every level of the pyramid is smoothed, which reduces its total variation
+    fn total_variation(pyramid: &FramePyramid) -> Vec<f32> {
+        pyramid
+            .levels()
+            .iter()
+            .map(|l| {
+                (&l.slice(s![1.., .., ..]) - &l.slice(s![..-1, .., ..]))
+                    .mapv(f32::abs)
+                    .sum()
+            })
+            .collect()
+    }
+    let (_, img_dst, _) = lk_fixture();
+    let frame = image_to_array3(img_dst).mapv(|v| v as f32);
+    let plain = FramePyramid::new(&frame, true, 2.0, PyramidFilter::Box, (25, 25), 5).unwrap();
+    let smoothed = FramePyramid::new(&frame, true, 2.0, PyramidFilter::Box, (25, 25), 5)
+        .unwrap()
+        .with_gradients(settings.gradients);
+    for (s, p) in total_variation(&smoothed)
+        .iter()
+        .zip(total_variation(&plain))
+    {
+        assert!(*s < p);
+    }
+}
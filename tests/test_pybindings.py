@@ -46,3 +46,249 @@ def test_transform_types():
         assert var == TransformationType.from_str(var.to_str())
         assert var == TransformationType.from_str(var.to_str().upper())
         assert var == TransformationType.from_str(var.to_str().lower())
+
+
+def test_merge_layers():
+    from spano import Mapping, merge_arrays_with_layers
+
+    frames = [np.ones((32, 32, 1), dtype=np.float32) for _ in range(3)]
+    maps = [Mapping.shift(-8 * i, 0) for i in range(3)]
+    canvas, layers = merge_arrays_with_layers(maps, frames)
+
+    assert not np.isnan(canvas).any()
+    assert canvas.shape[:2] == layers["count"].shape
+    assert layers["count"].max() == 3
+    assert np.all(layers["time_min"] <= layers["time_mean"])
+    assert np.all(layers["time_mean"] <= layers["time_max"])
+    assert np.allclose(canvas[layers["weight"] > 0], 1.0)
+
+
+def test_merge_valid_mask():
+    from spano import Mapping, merge_arrays_with_layers
+
+    frames = [np.full((32, 32, 1), i, dtype=np.float32) for i in range(2)]
+    valid = [np.ones((32, 32), dtype=bool), np.zeros((32, 32), dtype=bool)]
+    maps = [Mapping.identity(), Mapping.identity()]
+    canvas, layers = merge_arrays_with_layers(maps, frames, valid=valid)
+
+    assert layers["count"].max() == 1
+    assert np.allclose(canvas, 0.0)
+
+
+def test_drizzle_constant():
+    from spano import Mapping, drizzle_arrays
+
+    frames = [np.full((32, 32, 1), 5.0, dtype=np.float32) for _ in range(4)]
+    maps = [Mapping.shift(x, y) for x, y in [(0, 0), (0.5, 0), (0, 0.5), (0.5, 0.5)]]
+    canvas = drizzle_arrays(maps, frames, scale=2.0, pixfrac=0.5)
+
+    assert canvas.shape[0] >= 2 * 32 - 2 and canvas.shape[1] >= 2 * 32 - 2
+    assert np.allclose(canvas[canvas > 0], 5.0, atol=1e-4)
+
+
+def test_photon_flux():
+    from spano import Mapping, merge_photon_counts
+
+    rng = np.random.default_rng(0)
+    flux = 0.5
+    frames = [
+        rng.binomial(16, 1 - np.exp(-flux), size=(32, 32, 1)).astype(np.float32)
+        for _ in range(16)
+    ]
+    maps = [Mapping.identity() for _ in frames]
+    estimate, trials = merge_photon_counts(maps, frames, 16)
+
+    assert np.allclose(trials[trials > 0], 16 * 16)
+    assert abs(estimate[trials > 0].mean() - flux) < 0.05
+
+
+def test_distance_transforms():
+    from spano import Mapping, distance_transform, polygon_distance_transform
+
+    size = (64, 48)
+    corners = Mapping.identity().corners(size)
+    assert np.allclose(distance_transform(size), polygon_distance_transform(corners, size))
+
+
+def test_gain_compensation():
+    from spano import Mapping, gain_compensation
+
+    rng = np.random.default_rng(0)
+    base = rng.uniform(50, 200, size=(64, 64, 1)).astype(np.float32)
+    frames = [base, base * 1.5]
+    maps = [Mapping.identity(), Mapping.identity()]
+    gains = gain_compensation(maps, frames, sigma_g=10.0)
+
+    assert gains.shape == (2, 1)
+    assert np.isclose(gains[0, 0] / gains[1, 0], 1.5, rtol=0.02)
+
+
+def test_demosaic_bayer():
+    from spano import DemosaicMethod, demosaic
+
+    # RGGB pattern given as a single period
+    pattern = np.zeros((2, 2, 3), dtype=np.uint8)
+    pattern[0, 0, 0] = pattern[0, 1, 1] = pattern[1, 0, 1] = pattern[1, 1, 2] = 255
+    color = np.array([0.2, 0.5, 0.8], dtype=np.float32)
+    mosaic = np.tile(np.array([[color[0], color[1]], [color[1], color[2]]]), (8, 8)).astype(np.float32)
+
+    for method in (DemosaicMethod.Bilinear, DemosaicMethod.Edge):
+        out = demosaic(mosaic, pattern, method=method)
+        assert out.shape == (16, 16, 3)
+        assert np.allclose(out[2:-2, 2:-2], color, atol=1e-5)
+
+
+def test_mosaic_iclk():
+    from spano import mosaic_iclk, pairwise_iclk
+
+    # Slow pan over a smooth scene, both approaches should agree on the motion
+    y, x = np.mgrid[0:64, 0:160].astype(np.float32)
+    scene = (np.sin(x / 7) + np.cos(y / 5) + np.sin((x + y) / 11))[..., None] * 50 + 128
+    frames = [np.ascontiguousarray(scene[:, 3 * i : 3 * i + 64]) for i in range(6)]
+
+    maps = mosaic_iclk(frames, kind="translational")
+    expected, _ = pairwise_iclk(frames)
+    assert len(maps) == len(frames) - 1
+    for m, e in zip(maps, expected):
+        assert np.allclose(m.mat[:2, 2], e.mat[:2, 2], atol=0.5)
+
+
+def test_mosaic_iclk_init():
+    from spano import Mapping, mosaic_iclk, pairwise_iclk
+
+    # Irregular pan, which is only matched if frames are placed using the initial mappings
+    y, x = np.mgrid[0:64, 0:160].astype(np.float32)
+    scene = (np.sin(x / 7) + np.cos(y / 5) + np.sin((x + y) / 11))[..., None] * 50 + 128
+    offsets = [0, 2, 9, 11, 20, 22]
+    frames = [np.ascontiguousarray(scene[:, o : o + 64]) for o in offsets]
+
+    expected, _ = pairwise_iclk(frames)
+    init = [Mapping.from_params(np.round(e.mat[:2, 2]).tolist()) for e in expected]
+    maps = mosaic_iclk(frames, init_mappings=init)
+    assert len(maps) == len(frames) - 1
+    for m, e in zip(maps, expected):
+        assert np.allclose(m.mat[:2, 2], e.mat[:2, 2], atol=0.5)
+
+
+def test_register_pairs():
+    from spano import pairwise_iclk, register_pairs
+
+    y, x = np.mgrid[0:64, 0:160].astype(np.float32)
+    scene = (np.sin(x / 7) + np.cos(y / 5) + np.sin((x + y) / 11))[..., None] * 50 + 128
+    frames = [np.ascontiguousarray(scene[:, 4 * i : 4 * i + 64]) for i in range(4)]
+
+    pairs = [(0, 1), (1, 2), (2, 3), (0, 2)]
+    results = register_pairs(frames, pairs)
+    assert set(results.keys()) == set(pairs)
+
+    # Consecutive pairs match pairwise registration, and skipping a frame doubles the motion
+    expected, _ = pairwise_iclk(frames)
+    for i, e in enumerate(expected):
+        assert np.allclose(results[(i, i + 1)][0].mat, e.mat, atol=1e-3)
+    assert np.allclose(results[(0, 2)][0].mat[:2, 2], 2 * expected[0].mat[:2, 2], atol=0.5)
+
+    # Results are keyed by pair, so duplicates are rejected
+    try:
+        register_pairs(frames, [(0, 1), (0, 1)])
+    except Exception:
+        pass
+    else:
+        raise AssertionError("Duplicate pairs should be rejected.")
+
+
+def test_iclk_schedule():
+    from spano import iclk
+
+    y, x = np.mgrid[0:96, 0:160].astype(np.float32)
+    scene = (np.sin(x / 7) + np.cos(y / 5) + np.sin((x + y) / 11))[..., None] * 50 + 128
+    im1, im2 = np.ascontiguousarray(scene[:, :128]), np.ascontiguousarray(scene[:, 5:133])
+
+    schedule = [("translational", 50, None), ("projective", None, None)]
+    mapping, history = iclk(im1, im2, schedule=schedule)
+    assert mapping.kind == "Projective"
+    assert all(len(p) == 2 for p in history[max(history)])
+    assert np.allclose(np.abs(mapping.mat[:2, 2]), [5, 0], atol=0.5)
+
+
+def test_pairwise_schedule():
+    from spano import mosaic_iclk, pairwise_iclk, register_pairs
+
+    y, x = np.mgrid[0:64, 0:160].astype(np.float32)
+    scene = (np.sin(x / 7) + np.cos(y / 5) + np.sin((x + y) / 11))[..., None] * 50 + 128
+    frames = [np.ascontiguousarray(scene[:, 4 * i : 4 * i + 64]) for i in range(3)]
+
+    # The schedule is used for every pair, the finest level being translational here
+    schedule = [("translational", 50, None)]
+    maps, history = pairwise_iclk(frames, schedule=schedule)
+    assert all(m.kind == "Translational" for m in maps)
+    assert all(len(p) == 2 for h in history for p in h[max(h)])
+
+    results = register_pairs(frames, [(0, 1), (0, 2)], schedule=schedule)
+    assert all(m.kind == "Translational" for m, _ in results.values())
+    assert np.allclose(results[(0, 1)][0].mat, maps[0].mat, atol=1e-3)
+
+    maps = mosaic_iclk(frames, kind="translational", schedule=schedule)
+    assert all(m.kind == "Translational" for m in maps)
+
+    # Invalid kinds are rejected
+    try:
+        pairwise_iclk(frames, schedule=[("nonexistent", None, None)])
+    except Exception:
+        pass
+    else:
+        raise AssertionError("Invalid schedule kinds should be rejected.")
+
+
+def test_pyramid_scale():
+    from spano import iclk, pairwise_iclk
+
+    y, x = np.mgrid[0:64, 0:160].astype(np.float32)
+    scene = (np.sin(x / 7) + np.cos(y / 5) + np.sin((x + y) / 11))[..., None] * 50 + 128
+    im1, im2 = np.ascontiguousarray(scene[:, :64]), np.ascontiguousarray(scene[:, 4:68])
+
+    # Smaller scale factors yield more pyramid levels, but should converge to the same mapping
+    mapping, history = iclk(im1, im2)
+    fine_mapping, fine_history = iclk(im1, im2, pyramid_scale=1.5)
+    assert len(fine_history) > len(history)
+    assert np.allclose(fine_mapping.mat, mapping.mat, atol=1e-2)
+
+    maps, _ = pairwise_iclk([im1, im2], pyramid_scale=1.5)
+    assert np.allclose(maps[0].mat, fine_mapping.mat, atol=1e-3)
+
+
+def test_img_pyramid():
+    from spano import GradientOperator, PyramidFilter, img_pyramid
+
+    y, x = np.mgrid[0:46, 0:68].astype(np.float32)
+    im = np.sin(x / 5)[..., None] + np.cos(y / 3)[..., None]
+
+    levels = img_pyramid(im, min_dimension=8, scale=np.sqrt(2), filter=PyramidFilter.Gaussian, kind="image")
+    assert levels[0].shape == im.shape
+    assert [l.shape[:2] for l in levels[1:3]] == [(33, 48), (23, 34)]
+
+    # Laplacian bands and gradients have one entry per level
+    bands = img_pyramid(im, min_dimension=8, kind="laplacian")
+    assert len(bands) == len(img_pyramid(im, min_dimension=8))
+    gradients = img_pyramid(im, min_dimension=8, kind="gradient")
+    assert all(dx.shape == dy.shape for dx, dy in gradients)
+
+    # Gradient settings are forwarded, and differ from the default Prewitt operator
+    gaussian = img_pyramid(
+        im, min_dimension=8, kind="gradient", gradient_operator=GradientOperator.Gaussian, gradient_sigma=2.0
+    )
+    assert len(gaussian) == len(gradients)
+    assert not np.allclose(gaussian[0][0], gradients[0][0])
+
+
+def test_iclk_gradient_operator():
+    from spano import GradientOperator, Mapping, iclk
+
+    y, x = np.mgrid[0:96, 0:160].astype(np.float32)
+    scene = (np.sin(x / 7) + np.cos(y / 5) + np.sin((x + y) / 11))[..., None] * 50 + 128
+    noisy = scene + np.random.default_rng(0).normal(0, 5, scene.shape).astype(np.float32)
+    im1, im2 = np.ascontiguousarray(noisy[:, :128]), np.ascontiguousarray(noisy[:, 5:133])
+
+    for operator in (GradientOperator.Scharr, GradientOperator.Gaussian):
+        init = Mapping.from_params([0.0, 0.0])
+        mapping, _ = iclk(im1, im2, init, gradient_operator=operator, smoothing=1.0)
+        assert np.allclose(np.abs(mapping.mat[:2, 2]), [5, 0], atol=0.5)